@@ -6,10 +6,13 @@ use env_logger;
 
 use comphdl::bit::RepInputIterator;
 use comphdl::component::Component;
+use comphdl::event::{self, Delays, Timing};
 use comphdl::simulation::run_simulation;
-use comphdl::{emit_json, parser};
-use std::io::{BufReader, Read, Write};
-use std::fs::File;
+use comphdl::{emit_json, fmt, project, suggest};
+use std::io::{BufReader, Write};
+use std::path::Path;
+use std::fs::{self, File};
+use std::process;
 use std::rc::Rc;
 use std::cell::RefCell;
 
@@ -20,22 +23,46 @@ fn yosys_netlist(c: &dyn Component) {
     println!("{}", s);
 }
 
-pub fn parse_file(filename: &str, top: &str) {
-    let file = File::open(filename).expect("Unable to open file");
-    let mut buf_reader = BufReader::new(file);
-    let mut bs = String::new();
-    buf_reader.read_to_string(&mut bs).unwrap();
-
-    let mut cf = parser::parse_str(&bs).unwrap();
+pub fn parse_file(filename: &str, top: &str, timing: Timing, flat: bool, compiled: bool) {
+    // Imported files are read relative to this file
+    let mut cf = match project::parse_file(Path::new(filename)) {
+        Ok(cf) => cf,
+        Err(e) => {
+            // The diagnostics already include the file name
+            for d in &e.diagnostics {
+                eprintln!("{}", d);
+            }
+            process::exit(1);
+        }
+    };
+    for d in cf.warnings() {
+        eprintln!("{}", d);
+    }
     // If file stdin.txt exists, read input from there instead of stdin
     if let Ok(stdin_bufread) = File::open("stdin.txt") {
         info!("Reading input from stdin.txt");
         cf.set_stdin_bufread(Rc::new(RefCell::new(BufReader::new(stdin_bufread))));
     }
-    let mux = cf.create_named(top).unwrap();
+    let mux = match cf.create_named(top) {
+        Some(c) => c,
+        None => {
+            eprintln!("error: top component `{}` not found", top);
+            if let Some(similar) = cf.suggest_component(top) {
+                eprintln!("    help: {}", suggest::help(&similar));
+            }
+            process::exit(1);
+        }
+    };
+    let mux: Box<dyn Component> = if compiled {
+        Box::new(mux.clone_as_structural().unwrap().compile())
+    } else if flat {
+        Box::new(mux.clone_as_structural().unwrap().flatten())
+    } else {
+        mux
+    };
     println!("{:#?}", mux);
 
-    let mut gate = mux;
+    let mut gate = event::with_timing(mux, timing);
 
     // Run simulation
     let mut buf = Vec::with_capacity(20_000_000);
@@ -50,15 +77,124 @@ pub fn parse_file(filename: &str, top: &str) {
     yosys_netlist(&*gate);
 }
 
+// Format the files in place. With --check, only report the files which
+// are not formatted, and fail if there is any
+pub fn fmt_files(args: Vec<String>) {
+    let check = args.iter().any(|x| x == "--check");
+    let mut failed = false;
+    for filename in args.iter().filter(|x| *x != "--check") {
+        let source = match fs::read_to_string(filename) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("{}: {}", filename, e);
+                failed = true;
+                continue;
+            }
+        };
+        let formatted = match fmt::format_str(&source) {
+            Ok(x) => x,
+            Err(e) => {
+                for d in &e.diagnostics {
+                    eprintln!("{}: {}", filename, d);
+                }
+                failed = true;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            eprintln!("{}: not formatted", filename);
+            failed = true;
+        } else if let Err(e) = fs::write(filename, formatted) {
+            eprintln!("{}: {}", filename, e);
+            failed = true;
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+// Run the test blocks of each file, fail if any test fails
+pub fn test_files(args: Vec<String>) {
+    let (mut passed, mut failed) = (0, 0);
+    let mut errors = false;
+    for filename in &args {
+        let cf = match project::parse_file(Path::new(filename)) {
+            Ok(cf) => cf,
+            Err(e) => {
+                for d in &e.diagnostics {
+                    eprintln!("{}", d);
+                }
+                errors = true;
+                continue;
+            }
+        };
+        for result in cf.tests().run(&cf) {
+            if result.passed() {
+                println!("test {} ... ok", result.name);
+                passed += 1;
+            } else {
+                println!("test {} ... FAILED", result.name);
+                for d in &result.failures {
+                    eprintln!("{}", d);
+                }
+                failed += 1;
+            }
+        }
+    }
+    println!("\ntest result: {} passed; {} failed", passed, failed);
+
+    if errors || failed > 0 {
+        process::exit(1);
+    }
+}
+
 fn main(){
     env_logger::init();
     // Usage: cargo run (for default arguments)
     //        cargo run -- test.txt Buf123 (filename, component name)
+    //        cargo run -- fmt [--check] test.txt (format files)
+    //        cargo run -- test test.txt (run the test blocks)
+    //        cargo run -- --event test.txt Buf123 (event driven simulation)
+    //        cargo run -- --flat test.txt Buf123 (flatten the hierarchy first)
+    //        cargo run -- --compiled test.txt Buf123 (compiled simulation)
     use std::env;
-    let mut args = env::args();
+    let mut args = env::args().peekable();
     let _program_name = args.next().unwrap();
-    let filename = args.next().unwrap_or(format!("test.txt"));
+    let mut timing = Timing::UnitDelay;
+    let mut flat = false;
+    let mut compiled = false;
+    while let Some(option) = args.peek().filter(|x| x.starts_with("--")).cloned() {
+        args.next();
+        match option.as_str() {
+            "--event" => timing = Timing::EventDriven { delays: Delays::default(), tick: 1 },
+            "--flat" => flat = true,
+            "--compiled" => compiled = true,
+            _ => {
+                eprintln!("error: unknown option `{}`", option);
+                process::exit(1);
+            }
+        }
+    }
+    if compiled && !matches!(timing, Timing::UnitDelay) {
+        eprintln!("error: `--compiled` can't be used with `--event`");
+        process::exit(1);
+    }
+    let first = args.next();
+    if first.as_deref() == Some("fmt") {
+        fmt_files(args.collect());
+        return;
+    }
+    if first.as_deref() == Some("test") {
+        test_files(args.collect());
+        return;
+    }
+    let filename = first.unwrap_or(format!("test.txt"));
     let top = args.next().unwrap_or(format!("Demux_1_4"));
-    parse_file(&filename, &top);
+    parse_file(&filename, &top, timing, flat, compiled);
 }
 
@@ -6,6 +6,7 @@ extern crate comphdl;
 use self::test::Bencher;
 use comphdl::parser;
 use comphdl::bit::{Bit, RepInputIterator};
+use comphdl::component::Component;
 
 static OR2: &str = r#"
 component Or2(a, b) -> x {
@@ -141,6 +142,38 @@ fn simulate_ram16k(b: &mut Bencher) {
     });
 }
 
+#[bench]
+fn simulate_ram16k_compiled(b: &mut Bencher) {
+    let cf = parser::parse_str(RAM16K).unwrap();
+    let c = cf.create_named("Ram16384x8").unwrap();
+    let mut c = c.clone_as_structural().unwrap().compile();
+    let ticks = 50;
+    let mut inputs = RepInputIterator::new(14, ticks);
+    // Write at one address per iter
+    b.iter(|| {
+        let mut outputs = vec![];
+        for _ in 0..ticks {
+            let mut inputs_write = vec![Bit::H];
+            let current_addr = inputs.next().unwrap();
+            inputs_write.extend(&current_addr);
+            inputs_write.extend(&[Bit::L; 8]);
+            outputs = c.update(&inputs_write);
+        }
+        outputs
+    });
+}
+
+#[bench]
+fn truth_table_ram16x8(b: &mut Bencher) {
+    let cf = parser::parse_str(RAM16K).unwrap();
+    let c = cf.create_named("Ram16x8").unwrap();
+    let c = c.clone_as_structural().unwrap();
+    // 2^13 input vectors, 64 at a time
+    b.iter(|| {
+        c.truth_table()
+    });
+}
+
 #[bench]
 fn simulate_cat(b: &mut Bencher) {
     let input = format!("Hello, world! Hmmmmmm... 0123456789 ");
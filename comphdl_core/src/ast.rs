@@ -0,0 +1,807 @@
+// Syntax tree of a source file, before expanding arrays and parameters.
+// It keeps the comments and the span of every node, so tools like the
+// formatter can work with the source code faithfully
+use crate::bit::Bit;
+use crate::diagnostic::{DiagnosticKind, Span};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+// Values of the parameters of a component: N = 8
+pub type Env = HashMap<String, i64>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+// Integer expression, used in array ranges and component parameters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntExpr {
+    Number(u64),
+    Var(String),
+    BinOp(Box<IntExpr>, BinOp, Box<IntExpr>),
+}
+
+impl IntExpr {
+    pub fn bin_op(a: IntExpr, op: BinOp, b: IntExpr) -> Self {
+        IntExpr::BinOp(Box::new(a), op, Box::new(b))
+    }
+    pub fn eval(&self, env: &Env) -> Result<i64, DiagnosticKind> {
+        match self {
+            IntExpr::Number(n) => {
+                if *n > i64::MAX as u64 {
+                    Err(DiagnosticKind::InvalidExpression {
+                        message: format!("number `{}` is too large", n)
+                    })
+                } else {
+                    Ok(*n as i64)
+                }
+            }
+            IntExpr::Var(name) => {
+                env.get(name).cloned().ok_or_else(|| {
+                    DiagnosticKind::UndefinedParameter { name: name.clone() }
+                })
+            }
+            IntExpr::BinOp(a, op, b) => {
+                let a = a.eval(env)?;
+                let b = b.eval(env)?;
+                let x = match op {
+                    BinOp::Add => a.checked_add(b),
+                    BinOp::Sub => a.checked_sub(b),
+                    BinOp::Mul => a.checked_mul(b),
+                    BinOp::Div => a.checked_div(b),
+                };
+                x.ok_or_else(|| DiagnosticKind::InvalidExpression {
+                    message: format!("`{}` overflows or divides by zero", self)
+                })
+            }
+        }
+    }
+    // Evaluate an array index, which cannot be negative
+    pub fn eval_index(&self, env: &Env) -> Result<u64, DiagnosticKind> {
+        let x = self.eval(env)?;
+        if x < 0 {
+            return Err(DiagnosticKind::NegativeIndex { expr: self.to_string(), value: x });
+        }
+
+        Ok(x as u64)
+    }
+}
+
+impl fmt::Display for IntExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntExpr::Number(n) => write!(f, "{}", n),
+            IntExpr::Var(name) => write!(f, "{}", name),
+            IntExpr::BinOp(a, op, b) => {
+                let op = match op {
+                    BinOp::Add => "+",
+                    BinOp::Sub => "-",
+                    BinOp::Mul => "*",
+                    BinOp::Div => "/",
+                };
+                // Parenthesize everything, the output is only used in
+                // error messages
+                let paren = |x: &IntExpr| match x {
+                    IntExpr::BinOp(..) => format!("({})", x),
+                    _ => x.to_string(),
+                };
+                write!(f, "{}{}{}", paren(a), op, paren(b))
+            }
+        }
+    }
+}
+
+// Index of one dimension of an array
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Range {
+    // a[3]
+    Bit(IntExpr),
+    // a[3:0] or a[0:3]
+    Slice(IntExpr, IntExpr),
+}
+
+impl Range {
+    // [3] is equivalent to [3:3]
+    pub fn bounds(&self) -> (&IntExpr, &IntExpr) {
+        match self {
+            Range::Bit(a) => (a, a),
+            Range::Slice(a, b) => (a, b),
+        }
+    }
+}
+
+// a, a[3:0] or mem[15:0][7:0]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitArray {
+    pub name: String,
+    // One range for each dimension
+    pub ranges: Vec<Range>,
+    pub span: Span,
+}
+
+impl BitArray {
+    pub fn eval(&self, env: &Env) -> Result<Vec<(u64, u64)>, DiagnosticKind> {
+        let mut v = vec![];
+        for r in &self.ranges {
+            let (a, b) = r.bounds();
+            v.push((a.eval_index(env)?, b.eval_index(env)?));
+        }
+
+        Ok(v)
+    }
+}
+
+impl fmt::Display for BitArray {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for r in &self.ranges {
+            match r {
+                Range::Bit(a) => write!(f, "[{}]", a)?,
+                Range::Slice(a, b) => write!(f, "[{}:{}]", a, b)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Return the name of each bit: a[3:0] becomes [a$3, a$2, a$1, a$0], and
+// m[1:0][1:0] becomes [m$1$1, m$1$0, m$0$1, m$0$0]
+pub fn expand_bits(name: &str, ranges: &[(u64, u64)]) -> Vec<String> {
+    let mut v = vec![name.to_string()];
+    for r in ranges {
+        let mut indices = vec![];
+        let mut i = r.0;
+        let isign = if r.0 < r.1 { 1 } else { -1 };
+        while i != r.1 {
+            indices.push(i);
+            i = i.wrapping_add(isign as u64);
+        }
+        indices.push(i);
+
+        v = v.iter().flat_map(|prefix| {
+            indices.iter().map(move |i| format!("{}${}", prefix, i))
+        }).collect();
+    }
+
+    v
+}
+
+// Widest sized literal, so a typo like 99999999999'b1 is an error instead of
+// a huge allocation
+pub const MAX_CONSTANT_WIDTH: usize = 4096;
+
+// Constant value: 0, 1, 'x, 4'b10x1 or 8'hFF
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Constant {
+    // As written in the source code
+    pub text: String,
+    // Most significant bit first, like the bits of an array a[7:0]
+    pub bits: Vec<Bit>,
+    pub span: Span,
+}
+
+impl Constant {
+    pub fn bit(text: &str, bit: Bit, span: Span) -> Self {
+        Constant { text: text.to_string(), bits: vec![bit], span }
+    }
+    // Verilog style sized literal: 8'hFF, 4'b10x1, 3'o7, 4'd9, with
+    // optional _ separators. Missing digits are filled with zeros
+    pub fn sized(text: &str, span: Span) -> Result<Self, String> {
+        let i = text.find('\'').unwrap();
+        let width: usize = text[..i].parse().ok()
+            .filter(|&w| w <= MAX_CONSTANT_WIDTH)
+            .ok_or_else(|| format!("width of `{}` is larger than {} bits", text, MAX_CONSTANT_WIDTH))?;
+        if width == 0 {
+            return Err(format!("`{}` has zero width", text));
+        }
+        let base = text[i + 1..].chars().next().unwrap().to_ascii_lowercase();
+        let digits = &text[i + 2..];
+        let mut bits = vec![];
+        if base == 'd' {
+            let value: u128 = digits.replace('_', "").parse()
+                .map_err(|_| format!("invalid decimal digits in `{}`", text))?;
+            if width < 128 && value >> width != 0 {
+                return Err(format!("`{}` does not fit in {} bits", text, width));
+            }
+            bits.extend((0..width).rev().map(|b| {
+                if b < 128 && (value >> b) & 1 == 1 { Bit::H } else { Bit::L }
+            }));
+            return Ok(Constant { text: text.to_string(), bits, span });
+        }
+
+        let bits_per_digit = match base {
+            'b' => 1,
+            'o' => 3,
+            _ => 4,
+        };
+        for c in digits.chars().filter(|&c| c != '_') {
+            if c == 'x' || c == 'X' {
+                bits.extend((0..bits_per_digit).map(|_| Bit::X));
+                continue;
+            }
+            let d = c.to_digit(1 << bits_per_digit)
+                .ok_or_else(|| format!("invalid digit `{}` in `{}`", c, text))?;
+            bits.extend((0..bits_per_digit).rev().map(|b| {
+                if (d >> b) & 1 == 1 { Bit::H } else { Bit::L }
+            }));
+        }
+        // Remove leading zeros, 4'hF is 1111 and not 00001111
+        while bits.len() > width && bits[0] == Bit::L {
+            bits.remove(0);
+        }
+        if bits.len() > width {
+            return Err(format!("`{}` does not fit in {} bits", text, width));
+        }
+        let mut v = vec![Bit::L; width - bits.len()];
+        v.extend(bits);
+
+        Ok(Constant { text: text.to_string(), bits: v, span })
+    }
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+// Internal signal driven by the constant driver of each component. The
+// names start with ' so they cannot clash with user signals
+pub fn constant_signal(bit: Bit) -> &'static str {
+    match bit {
+        Bit::L => "'0",
+        Bit::H => "'1",
+        Bit::X => "'x",
+    }
+}
+
+// Argument of a component call: a signal or a constant
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Signal {
+    Bits(BitArray),
+    Constant(Constant),
+}
+
+impl Signal {
+    pub fn span(&self) -> Span {
+        match self {
+            Signal::Bits(x) => x.span,
+            Signal::Constant(x) => x.span,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// Condition of an if statement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cond {
+    Cmp(IntExpr, CmpOp, IntExpr),
+    And(Box<Cond>, Box<Cond>),
+    Or(Box<Cond>, Box<Cond>),
+}
+
+impl Cond {
+    pub fn eval(&self, env: &Env) -> Result<bool, DiagnosticKind> {
+        Ok(match self {
+            Cond::Cmp(a, op, b) => {
+                let a = a.eval(env)?;
+                let b = b.eval(env)?;
+                match op {
+                    CmpOp::Eq => a == b,
+                    CmpOp::Ne => a != b,
+                    CmpOp::Lt => a < b,
+                    CmpOp::Le => a <= b,
+                    CmpOp::Gt => a > b,
+                    CmpOp::Ge => a >= b,
+                }
+            }
+            Cond::And(a, b) => a.eval(env)? && b.eval(env)?,
+            Cond::Or(a, b) => a.eval(env)? || b.eval(env)?,
+        })
+    }
+}
+
+// Statement inside a component body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Statement {
+    Call(CompCall),
+    Assignment(Assignment),
+    // x = a & !b;
+    Expression(ExprAssignment),
+    Comment(Comment),
+    // for i in 0..8 { ... }, the end is not included
+    For {
+        var: String,
+        start: IntExpr,
+        end: IntExpr,
+        body: Vec<Statement>,
+        span: Span,
+    },
+    // if i == 0 { ... } else { ... }
+    If {
+        cond: Cond,
+        then: Vec<Statement>,
+        otherwise: Vec<Statement>,
+        span: Span,
+    },
+}
+
+// Component call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompCall {
+    // Instance name: u_alu: Add8(a, b) -> s;
+    pub label: Option<String>,
+    pub name: String,
+    // Reg<8>(...)
+    pub params: Vec<IntExpr>,
+    pub inputs: Vec<Signal>,
+    pub outputs: Vec<Signal>,
+    // Mux(s: sel, a: x), the port connected to each input, None if the
+    // inputs are positional
+    pub input_ports: Option<Vec<String>>,
+    pub output_ports: Option<Vec<String>>,
+    // The comments written between the inputs and between the outputs
+    pub arg_comments: (Vec<Comment>, Vec<Comment>),
+    pub span: Span,
+}
+
+// x[3:0] = a[3:0]; or (x, y) = (a, 1);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    pub left: Vec<Signal>,
+    pub right: Vec<Signal>,
+    pub span: Span,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogicOp {
+    And,
+    Or,
+    Xor,
+}
+
+impl LogicOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            LogicOp::And => "&",
+            LogicOp::Or => "|",
+            LogicOp::Xor => "^",
+        }
+    }
+}
+
+// Bitwise boolean expression, lowered to Nand gates. The operands of a
+// binary operator must have the same width
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Signal(Signal),
+    // (a & b, c[1:0]), the bits of all the expressions
+    Concat(Vec<Expr>),
+    Not(Box<Expr>),
+    Binary(Box<Expr>, LogicOp, Box<Expr>),
+}
+
+impl Expr {
+    pub fn binary(a: Expr, op: LogicOp, b: Expr) -> Self {
+        Expr::Binary(Box::new(a), op, Box::new(b))
+    }
+    // Signals used by the expression, from left to right
+    pub fn signals(&self) -> Vec<&Signal> {
+        match self {
+            Expr::Signal(s) => vec![s],
+            Expr::Concat(v) => v.iter().flat_map(|e| e.signals()).collect(),
+            Expr::Not(a) => a.signals(),
+            Expr::Binary(a, _, b) => a.signals().into_iter().chain(b.signals()).collect(),
+        }
+    }
+}
+
+// Assignment with operators on the right side: x[3:0] = a[3:0] ^ b[3:0];
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprAssignment {
+    pub left: Vec<Signal>,
+    pub right: Expr,
+    pub span: Span,
+}
+
+// Right side of an assignment: plain signals are an Assignment, so they
+// only connect signals, anything else needs gates
+pub fn assignment(left: Vec<Signal>, right: Expr, span: Span) -> Statement {
+    let plain = match &right {
+        Expr::Signal(s) => Some(vec![s.clone()]),
+        Expr::Concat(v) => v.iter().map(|e| match e {
+            Expr::Signal(s) => Some(s.clone()),
+            _ => None,
+        }).collect(),
+        _ => None,
+    };
+    match plain {
+        Some(right) => Statement::Assignment(Assignment { left, right, span }),
+        None => Statement::Expression(ExprAssignment { left, right, span }),
+    }
+}
+
+// Any comment style: // comment, /* comment */ or # comment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comment {
+    // Including the delimiters
+    pub text: String,
+    pub span: Span,
+}
+
+impl Comment {
+    // Doc comments start with three slashes: /// Adds two bits
+    pub fn is_doc(&self) -> bool {
+        self.text.starts_with("///") && !self.text.starts_with("////")
+    }
+}
+
+// The text of consecutive doc comments, one line for each comment
+pub fn doc_text(comments: &[&Comment]) -> Option<String> {
+    if comments.is_empty() {
+        return None;
+    }
+    let lines: Vec<&str> = comments.iter().map(|c| {
+        let text = &c.text[3..];
+        text.strip_prefix(' ').unwrap_or(text).trim_end()
+    }).collect();
+
+    Some(lines.join("\n"))
+}
+
+// Split the arguments of a call into the signals and the port names. All
+// the arguments must be named, or none of them
+pub fn named_args(args: Args) -> Result<(Vec<Signal>, Option<Vec<String>>), String> {
+    let num_named = args.iter().filter(|(port, _)| port.is_some()).count();
+    if num_named == 0 {
+        return Ok((args.into_iter().map(|(_, s)| s).collect(), None));
+    }
+    if num_named != args.len() {
+        return Err("cannot mix named and positional ports".to_string());
+    }
+    let (ports, signals) = args.into_iter().map(|(port, s)| (port.unwrap(), s)).unzip();
+
+    Ok((signals, Some(ports)))
+}
+
+// Arguments of a call, with the port name if they are named
+pub type Args = Vec<(Option<String>, Signal)>;
+
+// Element of the argument list of a call
+pub enum ArgItem {
+    Arg(Option<String>, Signal),
+    Comma(Span),
+    Comment(Comment),
+}
+
+// The arguments of a call and the comments written between them
+pub fn arg_list(items: Vec<ArgItem>) -> Result<(Args, Vec<Comment>), (String, Span)> {
+    let mut args = vec![];
+    let mut comments = vec![];
+    let mut expect_arg = true;
+    for item in items {
+        match item {
+            ArgItem::Arg(port, s) => {
+                if !expect_arg {
+                    return Err(("expected `,` before this argument".to_string(), s.span()));
+                }
+                args.push((port, s));
+                expect_arg = false;
+            }
+            ArgItem::Comma(span) => {
+                if expect_arg {
+                    return Err(("expected an argument before `,`".to_string(), span));
+                }
+                expect_arg = true;
+            }
+            ArgItem::Comment(c) => comments.push(c),
+        }
+    }
+
+    Ok((args, comments))
+}
+
+// Element of the port list of a definition
+pub enum PortItem {
+    Port(BitArray),
+    Comma(Span),
+    Comment(Comment),
+}
+
+// Ports of a definition, with the comments written between them
+#[derive(Debug, Clone, Default)]
+pub struct PortList {
+    pub ports: Vec<BitArray>,
+    pub comments: Vec<Comment>,
+    // The doc comments before a port describe that port
+    pub docs: Vec<(String, String)>,
+}
+
+impl PortList {
+    pub fn single(port: BitArray) -> Self {
+        PortList { ports: vec![port], ..Default::default() }
+    }
+}
+
+// The ports must be separated by commas, a trailing comma is allowed
+pub fn port_list(items: Vec<PortItem>) -> Result<PortList, (String, Span)> {
+    let mut list = PortList::default();
+    let mut docs: Vec<Comment> = vec![];
+    let mut expect_port = true;
+    for item in items {
+        match item {
+            PortItem::Port(p) => {
+                if !expect_port {
+                    return Err((format!("expected `,` before port `{}`", p.name), p.span));
+                }
+                if let Some(text) = doc_text(&docs.iter().collect::<Vec<_>>()) {
+                    list.docs.push((p.name.clone(), text));
+                }
+                docs.clear();
+                list.ports.push(p);
+                expect_port = false;
+            }
+            PortItem::Comma(span) => {
+                if expect_port {
+                    return Err(("expected a port before `,`".to_string(), span));
+                }
+                expect_port = true;
+            }
+            PortItem::Comment(c) => {
+                if c.is_doc() {
+                    docs.push(c.clone());
+                } else {
+                    docs.clear();
+                }
+                list.comments.push(c);
+            }
+        }
+    }
+
+    Ok(list)
+}
+
+// Component definition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompDef {
+    pub name: String,
+    // component Reg<N>(...)
+    pub params: Vec<String>,
+    pub inputs: Vec<BitArray>,
+    pub outputs: Vec<BitArray>,
+    pub body: Vec<Statement>,
+    // Rows of a truth table definition, the body only has its comments
+    pub table: Option<Table>,
+    // Span of the header: name and ports
+    pub span: Span,
+    // Text of the doc comments written before the definition
+    pub doc: Option<String>,
+    // Doc comments of the ports: (port, text)
+    pub port_docs: Vec<(String, String)>,
+    // All the comments between the ports of the inputs and the outputs
+    pub port_comments: (Vec<Comment>, Vec<Comment>),
+    // Values of the constants of the file, set when the project is loaded.
+    // The parameters shadow them
+    pub consts: Env,
+}
+
+impl CompDef {
+    pub fn new(name: String, params: Vec<String>, inputs: PortList, outputs: PortList,
+               body: Vec<Statement>, table: Option<Table>, span: Span) -> Self {
+        let mut port_docs = inputs.docs;
+        port_docs.extend(outputs.docs);
+        CompDef {
+            name,
+            params,
+            inputs: inputs.ports,
+            outputs: outputs.ports,
+            body,
+            table,
+            span,
+            doc: None,
+            port_docs,
+            port_comments: (inputs.comments, outputs.comments),
+            consts: Env::new(),
+        }
+    }
+    pub fn is_parametric(&self) -> bool {
+        !self.params.is_empty()
+    }
+}
+
+// table Decoder(a[1:0]) -> y[3:0] { 00 -> 0001; 1x -> 1x00; }
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table {
+    pub rows: Vec<TableRow>,
+}
+
+// An X input matches both values, an X output is unknown. Each bit is one
+// port bit, in the order of the ports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableRow {
+    pub inputs: Vec<Bit>,
+    pub outputs: Vec<Bit>,
+    pub span: Span,
+}
+
+// Bits of a table row: 0, 1, and x or - for don't care. Underscores can be
+// used as separators
+pub fn table_bits(text: &str) -> Result<Vec<Bit>, String> {
+    text.chars().filter(|&c| c != '_').map(|c| match c {
+        '0' => Ok(Bit::L),
+        '1' => Ok(Bit::H),
+        'x' | 'X' | '-' => Ok(Bit::X),
+        _ => Err(format!("invalid truth table value `{}`, expected 0, 1, x or -", c)),
+    }).collect()
+}
+
+// import "lib/alu.txt"; or import "lib/alu-v2.txt" as alu;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    // Relative to the importing file
+    pub path: String,
+    pub alias: Option<String>,
+    pub span: Span,
+}
+
+impl Import {
+    // The alias, or the name of the file without the extension
+    pub fn namespace(&self) -> String {
+        match &self.alias {
+            Some(alias) => alias.clone(),
+            None => Path::new(&self.path).file_stem().map(|x| x.to_string_lossy().to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+// use alu::Add8;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Use {
+    pub namespace: String,
+    pub name: String,
+    pub span: Span,
+}
+
+// const WIDTH = 8;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstDef {
+    pub name: String,
+    // Can use the constants defined before it: const MSB = WIDTH - 1;
+    pub value: IntExpr,
+    pub span: Span,
+}
+
+// test "select a" for Mux_4_1 { set s1 = 0, s0 = 0, a = 1; step; expect y = 1; }
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestDef {
+    pub name: String,
+    // Component under test
+    pub component: String,
+    pub body: Vec<TestStatement>,
+    // Span of the header: name and component
+    pub span: Span,
+    // Constants of the file, like CompDef::consts
+    pub consts: Env,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TestStatement {
+    // Change the value of some inputs
+    Set(Vec<PortValue>, Span),
+    // Update the component until it settles
+    Step(Span),
+    // Check the value of some outputs
+    Expect(Vec<PortValue>, Span),
+    Comment(Comment),
+}
+
+// a[3:0] = 4'b0101
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortValue {
+    pub port: BitArray,
+    pub value: Constant,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct File {
+    pub imports: Vec<Import>,
+    pub uses: Vec<Use>,
+    pub consts: Vec<ConstDef>,
+    pub components: Vec<CompDef>,
+    pub tests: Vec<TestDef>,
+    // Comments outside of any definition
+    pub comments: Vec<Comment>,
+}
+
+// Top level statement, only used by the parser
+pub enum Item {
+    Import(Import),
+    Use(Use),
+    Const(ConstDef),
+    Component(CompDef),
+    Test(TestDef),
+    Comment(Comment),
+}
+
+impl File {
+    // The doc comments written just before a definition are attached to it
+    pub fn from_items(items: Vec<Item>) -> Self {
+        let mut f = File::default();
+        let mut docs: Vec<Comment> = vec![];
+        for item in items {
+            match item {
+                Item::Comment(c) => {
+                    if c.is_doc() {
+                        docs.push(c.clone());
+                    } else {
+                        docs.clear();
+                    }
+                    f.comments.push(c);
+                    continue;
+                }
+                Item::Import(x) => f.imports.push(x),
+                Item::Use(x) => f.uses.push(x),
+                Item::Const(x) => f.consts.push(x),
+                Item::Component(mut x) => {
+                    x.doc = doc_text(&docs.iter().collect::<Vec<_>>());
+                    f.components.push(x);
+                }
+                Item::Test(x) => f.tests.push(x),
+            }
+            docs.clear();
+        }
+
+        f
+    }
+}
+
+// Call `f` for every component call inside `body`, including the ones in
+// generate statements
+pub fn for_each_call<'a, F: FnMut(&'a CompCall)>(body: &'a [Statement], f: &mut F) {
+    for st in body {
+        match st {
+            Statement::Call(c) => f(c),
+            Statement::Assignment(_) | Statement::Expression(_) | Statement::Comment(_) => {}
+            Statement::For { body, .. } => for_each_call(body, f),
+            Statement::If { then, otherwise, .. } => {
+                for_each_call(then, f);
+                for_each_call(otherwise, f);
+            }
+        }
+    }
+}
+
+pub fn for_each_call_mut<F: FnMut(&mut CompCall)>(body: &mut [Statement], f: &mut F) {
+    for st in body {
+        match st {
+            Statement::Call(c) => f(c),
+            Statement::Assignment(_) | Statement::Expression(_) | Statement::Comment(_) => {}
+            Statement::For { body, .. } => for_each_call_mut(body, f),
+            Statement::If { then, otherwise, .. } => {
+                for_each_call_mut(then, f);
+                for_each_call_mut(otherwise, f);
+            }
+        }
+    }
+}
+
+// Name of the component created from a parametric definition: Reg<8>
+pub fn instance_name(name: &str, values: &[i64]) -> String {
+    let values: Vec<_> = values.iter().map(|x| x.to_string()).collect();
+    format!("{}<{}>", name, values.join(", "))
+}
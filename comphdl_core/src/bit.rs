@@ -1,6 +1,6 @@
 use vcd::Value;
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Bit {
     L, // Low, false, 0
     H, // High, true, 1
@@ -0,0 +1,383 @@
+// Compiled simulation of a flat netlist. The hierarchy is flattened, every
+// signal becomes an index into one array of nets, and the cells are sorted
+// so that each one is evaluated after the cells which drive its inputs.
+// One update runs this program once: combinational logic settles in a single
+// update, no matter how deep it is.
+//
+// Feedback loops (latches, oscillators) can not be sorted: some cell reads a
+// net before the cell which drives it is evaluated. These feedback nets keep
+// the value from the previous pass, and when one of them changes the
+// component needs another update, like the sweep of Structural::update.
+//
+// Nand gates are evaluated inline, any other primitive is called through the
+// Component trait.
+use crate::bit::Bit;
+use crate::component::{Component, CompIo, InstanceIndex, PortNames, Structural, VcdSignalHandle, write_vcd_signals};
+use std::io;
+use vcd;
+
+// Nets 1..=num_inputs are the inputs of the top component, the net 0 is
+// never driven and stays X
+const UNDRIVEN: usize = 0;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Op {
+    // Output net and range of `args` with the input nets
+    Nand { out: usize, args: (usize, usize) },
+    // First output net of the cell
+    Call { cell: usize, outs: usize, args: (usize, usize) },
+}
+
+#[derive(Debug, Clone)]
+pub struct Compiled {
+    // The flat netlist, which owns the primitives other than Nand. The
+    // ports of its CompIo are not updated, see clone_as_structural
+    pub(crate) flat: Structural,
+    values: Vec<Bit>,
+    pub(crate) program: Vec<Op>,
+    // Input nets of every cell, the range of each cell is in `cell_args`
+    pub(crate) args: Vec<usize>,
+    cell_args: Vec<(usize, usize)>,
+    // First output net of every cell
+    cell_outs: Vec<usize>,
+    // Net of each output of the top component
+    pub(crate) outputs: Vec<usize>,
+    pub(crate) feedback: Vec<bool>,
+    // Run the program until no feedback net changes, at most `passes` times
+    pub(crate) passes: usize,
+    unstable: bool,
+    buf: Vec<Bit>,
+}
+
+impl Compiled {
+    pub fn new(top: &Structural) -> Self {
+        let flat = crate::flatten::netlist(top);
+        let n_cells = flat.components.len();
+
+        // Number the nets: the undriven net, the inputs, and then the
+        // outputs of each cell
+        let mut cell_outs = vec![1; n_cells];
+        let mut n_nets = 1 + flat.num_inputs();
+        for (outs, c) in cell_outs.iter_mut().zip(&flat.components).skip(1) {
+            *outs = n_nets;
+            n_nets += c.comp.num_outputs();
+        }
+        // The net read by every input
+        let mut drivers: Vec<Vec<usize>> = flat.components.iter()
+            .map(|c| vec![UNDRIVEN; c.input.len()])
+            .collect();
+        // Cells reading the outputs of each cell
+        let mut succ = vec![vec![]; n_cells];
+        for c in 0..n_cells {
+            for (o, to) in flat.connections[c].iter().enumerate() {
+                for i in to {
+                    drivers[i.comp_id][i.input_id] = cell_outs[c] + o;
+                    if c != 0 && i.comp_id != 0 {
+                        succ[c].push(i.comp_id);
+                    }
+                }
+            }
+        }
+
+        let mut args = vec![];
+        let mut cell_args = vec![(0, 0); n_cells];
+        for c in 1..n_cells {
+            let start = args.len();
+            args.extend_from_slice(&drivers[c]);
+            cell_args[c] = (start, args.len());
+        }
+
+        let order = eval_order(&succ);
+        let mut position = vec![0; n_cells];
+        for (k, &c) in order.iter().enumerate() {
+            position[c] = k;
+        }
+        let mut feedback = vec![false; n_nets];
+        for &c in &order {
+            for o in 0..flat.components[c].comp.num_outputs() {
+                let read_before = flat.connections[c][o].iter()
+                    .any(|i| i.comp_id != 0 && position[i.comp_id] <= position[c]);
+                feedback[cell_outs[c] + o] = read_before;
+            }
+        }
+        let program = order.iter().map(|&c| {
+            let comp = &flat.components[c].comp;
+            if comp.name() == "Nand" {
+                Op::Nand { out: cell_outs[c], args: cell_args[c] }
+            } else {
+                Op::Call { cell: c, outs: cell_outs[c], args: cell_args[c] }
+            }
+        }).collect();
+
+        let outputs = drivers[0].clone();
+
+        Self {
+            flat,
+            values: vec![Bit::X; n_nets],
+            program,
+            args,
+            cell_args,
+            cell_outs,
+            outputs,
+            feedback,
+            passes: 1,
+            unstable: true,
+            buf: vec![],
+        }
+    }
+    // Settle feedback loops inside one update, running the program up to
+    // `passes` times. An oscillator will still need more updates
+    pub fn with_passes(mut self, passes: usize) -> Self {
+        self.passes = passes.max(1);
+        self
+    }
+    // Run the program once, returns true if a feedback net changed
+    fn run(&mut self) -> bool {
+        let mut changed = false;
+        for k in 0..self.program.len() {
+            match self.program[k] {
+                Op::Nand { out, args: (start, end) } => {
+                    // Same as Nand::update
+                    let mut x = Bit::L;
+                    for &a in &self.args[start..end] {
+                        match self.values[a] {
+                            Bit::L => { x = Bit::H; break; }
+                            Bit::X => x = Bit::X,
+                            Bit::H => {}
+                        }
+                    }
+                    changed |= self.set(out, x);
+                }
+                Op::Call { cell, outs, args: (start, end) } => {
+                    self.buf.clear();
+                    for &a in &self.args[start..end] {
+                        self.buf.push(self.values[a]);
+                    }
+                    let comp = &mut self.flat.components[cell].comp;
+                    let output = comp.update(&self.buf);
+                    // Stdin and friends change without a change of the inputs
+                    changed |= comp.needs_update();
+                    for (o, x) in output.into_iter().enumerate() {
+                        changed |= self.set(outs + o, x);
+                    }
+                }
+            }
+        }
+
+        changed
+    }
+    fn set(&mut self, net: usize, x: Bit) -> bool {
+        if self.values[net] == x {
+            return false;
+        }
+        self.values[net] = x;
+
+        self.feedback[net]
+    }
+    fn inputs(&self) -> &[Bit] {
+        &self.values[1..=self.flat.num_inputs()]
+    }
+    fn cell_io(&self, c: usize) -> (Vec<Bit>, Vec<Bit>) {
+        let (start, end) = self.cell_args[c];
+        let inputs = self.args[start..end].iter().map(|&a| self.values[a]).collect();
+        let outs = self.cell_outs[c];
+        let n = self.flat.components[c].comp.num_outputs();
+
+        (inputs, self.values[outs..outs + n].to_vec())
+    }
+}
+
+impl Structural {
+    pub fn compile(&self) -> Compiled {
+        Compiled::new(self)
+    }
+}
+
+impl Component for Compiled {
+    fn update(&mut self, input: &[Bit]) -> Vec<Bit> {
+        assert_eq!(input.len(), self.num_inputs());
+        if self.unstable || self.inputs() != input {
+            self.values[1..=input.len()].copy_from_slice(input);
+            let mut passes = 0;
+            self.unstable = true;
+            while self.unstable && passes < self.passes {
+                self.unstable = self.run();
+                passes += 1;
+            }
+        }
+
+        self.outputs.iter().map(|&net| self.values[net]).collect()
+    }
+    fn needs_update(&self) -> bool {
+        self.unstable
+    }
+    fn num_inputs(&self) -> usize {
+        self.flat.num_inputs()
+    }
+    fn num_outputs(&self) -> usize {
+        self.flat.num_outputs()
+    }
+    fn name(&self) -> &str {
+        self.flat.name()
+    }
+    fn write_internal_components(&self, w: &mut vcd::Writer<'_>, j: &mut u64) -> io::Result<VcdSignalHandle> {
+        self.flat.write_internal_components(w, j)
+    }
+    // Same signals as Structural::write_internal_signals, read from the nets
+    fn write_internal_signals(&self, w: &mut vcd::Writer<'_>, j: &mut u64, vh: &VcdSignalHandle) -> io::Result<()> {
+        if *j == 0 {
+            let outputs: Vec<_> = self.outputs.iter().map(|&net| self.values[net]).collect();
+            write_vcd_signals(w, InstanceIndex::new(0, 0), vh, self.inputs(), &outputs)?;
+            *j += 1;
+        }
+        for c in 1..self.flat.components.len() {
+            let (inputs, outputs) = self.cell_io(c);
+            write_vcd_signals(w, InstanceIndex::new(*j as usize, 0), vh, &inputs, &outputs)?;
+            *j += 1;
+        }
+
+        Ok(())
+    }
+    fn port_names(&self) -> PortNames {
+        self.flat.port_names()
+    }
+    fn internal_inputs(&self) -> Option<Vec<Vec<Bit>>> {
+        let mut v = vec![self.outputs.iter().map(|&net| self.values[net]).collect()];
+        v.extend((1..self.flat.components.len()).map(|c| self.cell_io(c).0));
+
+        Some(v)
+    }
+    // The flat netlist, with the current value of every port
+    fn clone_as_structural(&self) -> Option<Structural> {
+        let mut s = self.flat.clone();
+        s.components[0].input = self.outputs.iter().map(|&net| self.values[net]).collect();
+        s.components[0].output = self.inputs().to_vec();
+        for c in 1..s.components.len() {
+            let (inputs, outputs) = self.cell_io(c);
+            let CompIo { input, output, .. } = &mut s.components[c];
+            *input = inputs;
+            *output = outputs;
+        }
+
+        Some(s)
+    }
+    fn box_clone(&self) -> Box<dyn Component> {
+        Box::new(self.clone())
+    }
+}
+
+// Cells sorted so that every edge which is not part of a cycle goes forward:
+// the reverse postorder of a depth first search. The cell 0 is not included
+fn eval_order(succ: &[Vec<usize>]) -> Vec<usize> {
+    let mut visited = vec![false; succ.len()];
+    let mut post = Vec::with_capacity(succ.len());
+    // Big netlists would overflow the call stack with a recursive search
+    let mut stack = vec![];
+    for root in 1..succ.len() {
+        if visited[root] {
+            continue;
+        }
+        visited[root] = true;
+        stack.push((root, 0));
+        while let Some(&(c, i)) = stack.last() {
+            match succ[c].get(i) {
+                Some(&next) => {
+                    stack.last_mut().unwrap().1 += 1;
+                    if !visited[next] {
+                        visited[next] = true;
+                        stack.push((next, 0));
+                    }
+                }
+                None => {
+                    post.push(c);
+                    stack.pop();
+                }
+            }
+        }
+    }
+    post.reverse();
+
+    post
+}
+
+#[test]
+fn compiled_matches_structural() {
+    use crate::bit::Bit::*;
+    use crate::parser::parse_str;
+    let d = r#"
+component Not(a) -> y {
+    Nand(a) -> y;
+}
+table Xor(a, b) -> y { 00 -> 0; 01 -> 1; 10 -> 1; 11 -> 0; }
+component Full(a, b, c) -> (s, co) {
+    u_x1: Xor(a, b) -> t;
+    u_x2: Xor(t, c) -> s;
+    co = (a & b) | (t & c);
+}
+component Add2(a[1:0], b[1:0]) -> (s[1:0], co) {
+    Full(a[0], b[0], 0) -> (s[0], c0);
+    Full(a[1], b[1], c0) -> (s[1], co);
+}
+component SrLatch(n_s, n_r) -> (q, n_q) {
+    Nand(n_s, n_q) -> q;
+    Nand(n_r, q) -> n_q;
+}
+component Clk2(a) -> x {
+    Nand(a, x) -> x;
+}
+"#;
+    let cf = parse_str(d).unwrap();
+    let settle = |c: &mut Box<dyn Component>, input: &[Bit]| {
+        let mut out = c.update(input);
+        for _ in 0..100 {
+            if !c.needs_update() {
+                break;
+            }
+            out = c.update(input);
+        }
+        out
+    };
+    let compiled = |name| -> Box<dyn Component> {
+        Box::new(cf.create_named(name).unwrap().clone_as_structural().unwrap().compile())
+    };
+
+    // Combinational logic settles in one update
+    let mut s = cf.create_named("Add2").unwrap();
+    let mut c = compiled("Add2");
+    for x in 0..16 {
+        let input: Vec<_> = (0..4).map(|j| Bit::from_bool((x >> j) & 1 == 1)).collect();
+        let out = c.update(&input);
+        assert!(!c.needs_update());
+        assert_eq!(out, settle(&mut s, &input));
+        assert!(!out.contains(&X));
+    }
+
+    // A latch needs more than one pass
+    let mut s = cf.create_named("SrLatch").unwrap();
+    let mut c = compiled("SrLatch");
+    for input in &[[L, H], [H, H], [H, L], [H, H], [L, H]] {
+        assert_eq!(settle(&mut c, input), settle(&mut s, input));
+    }
+    let mut c = cf.create_named("SrLatch").unwrap().clone_as_structural().unwrap().compile().with_passes(4);
+    assert_eq!(c.update(&[H, L]), vec![L, H]);
+    assert!(!c.needs_update());
+    assert_eq!(c.update(&[H, H]), vec![L, H]);
+
+    // An oscillator toggles once per update and never settles
+    let mut c = compiled("Clk2");
+    let out: Vec<_> = [L, H, H, H, H].iter().map(|&a| c.update(&[a])[0]).collect();
+    assert_eq!(out, vec![H, L, H, L, H]);
+    assert!(c.needs_update());
+
+    // The ports can be inspected in the flat netlist
+    let mut c = compiled("Add2");
+    c.update(&[H, L, H, L]);
+    let flat = c.clone_as_structural().unwrap();
+    let x1 = flat.components.iter().find(|c| c.label.as_deref() == Some("Full-2.u_x1")).unwrap();
+    assert_eq!(x1.input, vec![H, H]);
+    assert_eq!(x1.output, vec![L]);
+    let mut vcd = vec![];
+    let inputs = vec![vec![L, H, L, H]];
+    crate::simulation::run_simulation(&mut vcd, &mut *c, &mut inputs.into_iter(), 1).unwrap();
+    assert!(String::from_utf8(vcd).unwrap().contains("$scope module Full-2.u_x2 $end"));
+}
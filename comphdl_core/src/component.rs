@@ -36,9 +36,16 @@ pub trait Component: std::fmt::Debug {
     fn as_structural(&self) -> Option<&Structural> {
         None
     }
+    fn as_structural_mut(&mut self) -> Option<&mut Structural> {
+        None
+    }
     fn clone_as_structural(&self) -> Option<Structural> {
         Some(Structural::new_wrap(self.box_clone()))
     }
+    // Nand gate implementation of a native component, used in netlists
+    fn lower(&self) -> Option<Structural> {
+        None
+    }
     fn box_clone(&self) -> Box<dyn Component>;
 }
 
@@ -431,6 +438,40 @@ impl Structural {
 
         Structural::new_legacy(components, num_inputs, num_outputs, &name, port_names)
     }
+    // Hierarchical path of a nested instance, given the index of the local
+    // component at each level: u_cpu.u_alu.Nand-3
+    pub fn instance_path(&self, ids: &[usize]) -> Option<String> {
+        let mut names = vec![];
+        let mut s = self;
+        for (i, &c_id) in ids.iter().enumerate() {
+            let c = s.components.get(c_id).filter(|_| c_id != 0)?;
+            names.push(c.instance_name(c_id));
+            if i + 1 < ids.len() {
+                s = c.comp.as_structural()?;
+            }
+        }
+        Some(names.join("."))
+    }
+    // The inverse of instance_path. The cells of a flat netlist have dotted
+    // labels, u_h.u_xor, so the longest name which matches wins
+    pub fn find_instance(&self, path: &str) -> Option<Vec<usize>> {
+        let mut ids = vec![];
+        let mut s = self;
+        let mut rest = path;
+        loop {
+            let (c_id, len) = (1..s.components.len()).rev().filter_map(|c_id| {
+                let name = s.components[c_id].instance_name(c_id);
+                let found = rest == name || (rest.starts_with(&name) && rest[name.len()..].starts_with('.'));
+                Some((c_id, name.len())).filter(|_| found)
+            }).max_by_key(|&(_, len)| len)?;
+            ids.push(c_id);
+            if len == rest.len() {
+                return Some(ids);
+            }
+            rest = &rest[len + 1..];
+            s = s.components[c_id].comp.as_structural()?;
+        }
+    }
     fn propagate(&mut self, c_id: usize) {
         let connections = &self.connections[c_id];
         for (out_id, to) in connections.iter().enumerate() {
@@ -532,7 +573,7 @@ impl Component for Structural {
 
         for c in self.components.iter().skip(1).filter(|&c| VCD_SHOW_NAND || (c.comp.name() != "NAND")) {
             let mut vi = InstanceIndex::new(*j as usize, 0);
-            let instance_name = format!("{}-{}", c.comp.name(), j);
+            let instance_name = c.instance_name(*j as usize);
             writer.add_module(&instance_name)?;
             let port_names = c.comp.port_names();
             for i in 0..c.comp.num_inputs() {
@@ -597,6 +638,9 @@ impl Component for Structural {
     fn as_structural(&self) -> Option<&Structural> {
         Some(self)
     }
+    fn as_structural_mut(&mut self) -> Option<&mut Structural> {
+        Some(self)
+    }
     fn clone_as_structural(&self) -> Option<Structural> {
         Some(self.clone())
     }
@@ -608,10 +652,12 @@ impl Component for Structural {
 #[derive(Debug, Clone)]
 pub struct CompIo {
     pub comp: Box<dyn Component>,
-    input: Vec<Bit>,
-    output: Vec<Bit>,
+    pub(crate) input: Vec<Bit>,
+    pub(crate) output: Vec<Bit>,
     pub connections: Vec<Vec<Index>>,
     output_changed: bool,
+    // Instance name given in the source: u_alu: Add8(a, b) -> s;
+    pub label: Option<String>,
 }
 
 impl CompIo {
@@ -625,6 +671,7 @@ impl CompIo {
             output,
             connections,
             output_changed: true,
+            label: None,
         }
     }
     pub fn c_zero(num_inputs: usize, num_outputs: usize) -> CompIo {
@@ -638,6 +685,19 @@ impl CompIo {
             output,
             connections,
             output_changed: true,
+            label: None,
+        }
+    }
+    pub fn with_label(mut self, label: Option<String>) -> CompIo {
+        self.label = label;
+        self
+    }
+    // The instance name, or an automatic one based on the component name
+    // and the index `j`: Nand-17
+    pub fn instance_name(&self, j: usize) -> String {
+        match &self.label {
+            Some(label) => label.clone(),
+            None => format!("{}-{}", self.comp.name(), j),
         }
     }
     pub fn add_connection(&mut self, output_id: usize, to: Index) {
@@ -0,0 +1,497 @@
+use std::error::Error;
+use std::fmt;
+
+// Byte offsets into the source code, as reported by the parser
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    // The parser could not make sense of the input
+    Syntax { message: String },
+    // Input and output names of a component must be unique
+    DuplicatePort { port: String },
+    // a[3:0] = b[1:0];
+    UnbalancedAssignment { left: usize, right: usize },
+    // A signal appears twice in the list of assignments
+    DuplicateAssignment { signal: String },
+    Redefinition { name: String },
+    RecursiveDefinition,
+    ComponentNotFound { name: String },
+    WrongNumberOfInputs { callee: String, expected: usize, found: usize },
+    WrongNumberOfOutputs { callee: String, expected: usize, found: usize },
+    // Builtin gates accept a variable number of ports, but not any number
+    BuiltinArity { name: String, inputs: usize, outputs: usize },
+    // The same array is used with different number of dimensions
+    ArrayDimensionMismatch { signal: String, expected: usize, found: usize },
+    ArrayUsedAsBit { signal: String },
+    MultipleDrivers { signal: String, drivers: usize },
+    InvalidExpression { message: String },
+    UndefinedParameter { name: String },
+    NegativeIndex { expr: String, value: i64 },
+    WrongNumberOfParameters { callee: String, expected: usize, found: usize },
+    // The file could not be read
+    ImportError { path: String, message: String },
+    // a.txt imports b.txt which imports a.txt
+    ImportCycle { chain: Vec<String> },
+    // Two imported files with the same name: a/alu.txt and b/alu.txt
+    NamespaceClash { namespace: String },
+    // import "my-lib.txt"; the name of the file is not a valid namespace
+    InvalidNamespace { namespace: String },
+    // use alu::Add8; without import "alu.txt";
+    UnknownNamespace { namespace: String },
+    // Constant on the left side of an assignment or as an output: 1 = a;
+    DrivenConstant { constant: String },
+    // Mux(foo: a) when Mux has no port foo
+    UnknownPort { callee: String, port: String },
+    // Mux(s: a, s: b)
+    DuplicatePortConnection { port: String },
+    // A named call must connect all the ports
+    MissingPort { callee: String, port: String },
+    PortWidthMismatch { callee: String, port: String, expected: usize, found: usize },
+    // A uses B which uses A
+    RecursiveChain { chain: Vec<String> },
+    // A truth table row with the wrong number of bits, side is "input" or
+    // "output"
+    TableRowWidth { side: String, expected: usize, found: usize },
+    TableTooLarge { inputs: usize, max: usize },
+    // a[3:0] & b
+    OperandWidthMismatch { op: String, left: usize, right: usize },
+    // Failures of test blocks, see testbench.rs
+    TestFailed { port: String, expected: String, found: String },
+    // set y = 1; when y is an output
+    WrongPortDirection { callee: String, port: String, direction: String },
+    NotSettled { updates: usize },
+    // Two calls with the same instance name: u: Not(a); u: Not(b);
+    DuplicateInstance { name: String },
+    // const N = 1; const N = 2;
+    DuplicateConstant { name: String },
+    // Tree<N> using Tree<N+1>
+    ElaborationTooDeep { max: usize },
+    // Warnings of the lint pass, see lint.rs
+    UndrivenSignal { signal: String },
+    UnusedSignal { signal: String },
+    UnconnectedOutput { port: String },
+    // Foo(a) -> _; Bar(_) -> x;
+    DiscardedRead,
+    // comphdl: allow(foo)
+    UnknownLint { name: String },
+}
+
+impl DiagnosticKind {
+    // Machine readable error code, stable across releases
+    pub fn code(&self) -> &'static str {
+        use self::DiagnosticKind::*;
+        match self {
+            Syntax { .. } => "E0001",
+            DuplicatePort { .. } => "E0002",
+            UnbalancedAssignment { .. } => "E0003",
+            DuplicateAssignment { .. } => "E0004",
+            Redefinition { .. } => "E0005",
+            RecursiveDefinition => "E0006",
+            ComponentNotFound { .. } => "E0007",
+            WrongNumberOfInputs { .. } => "E0008",
+            WrongNumberOfOutputs { .. } => "E0009",
+            BuiltinArity { .. } => "E0010",
+            // E0011 was used by arrays of arrays before they were supported,
+            // it is not reused
+            ArrayDimensionMismatch { .. } => "E0037",
+            ArrayUsedAsBit { .. } => "E0012",
+            MultipleDrivers { .. } => "E0013",
+            InvalidExpression { .. } => "E0014",
+            UndefinedParameter { .. } => "E0015",
+            NegativeIndex { .. } => "E0016",
+            WrongNumberOfParameters { .. } => "E0017",
+            ImportError { .. } => "E0018",
+            ImportCycle { .. } => "E0019",
+            NamespaceClash { .. } => "E0020",
+            UnknownNamespace { .. } => "E0021",
+            DrivenConstant { .. } => "E0022",
+            UnknownPort { .. } => "E0023",
+            DuplicatePortConnection { .. } => "E0024",
+            MissingPort { .. } => "E0025",
+            PortWidthMismatch { .. } => "E0026",
+            RecursiveChain { .. } => "E0027",
+            TableRowWidth { .. } => "E0028",
+            TableTooLarge { .. } => "E0029",
+            OperandWidthMismatch { .. } => "E0030",
+            TestFailed { .. } => "E0031",
+            WrongPortDirection { .. } => "E0032",
+            NotSettled { .. } => "E0033",
+            DuplicateInstance { .. } => "E0034",
+            DuplicateConstant { .. } => "E0035",
+            ElaborationTooDeep { .. } => "E0036",
+            InvalidNamespace { .. } => "E0038",
+            UndrivenSignal { .. } => "W0001",
+            UnusedSignal { .. } => "W0002",
+            UnconnectedOutput { .. } => "W0003",
+            DiscardedRead => "W0004",
+            UnknownLint { .. } => "W0005",
+        }
+    }
+}
+
+impl fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::DiagnosticKind::*;
+        match self {
+            Syntax { message } => write!(f, "{}", message),
+            DuplicatePort { port } => write!(f, "port names must be unique, `{}` isn't", port),
+            UnbalancedAssignment { left, right } => write!(f,
+                "unbalanced assignment: {} signals on the left, and {} on the right",
+                left, right),
+            DuplicateAssignment { signal } => write!(f, "duplicate assignment of signal `{}`", signal),
+            Redefinition { name } => write!(f, "redefinition of component `{}`", name),
+            RecursiveDefinition => write!(f, "recursive definition"),
+            ComponentNotFound { name } => write!(f, "component `{}` not found", name),
+            WrongNumberOfInputs { callee, expected, found } => write!(f,
+                "component `{}` has {} inputs but {} were supplied",
+                callee, expected, found),
+            WrongNumberOfOutputs { callee, expected, found } => write!(f,
+                "component `{}` has {} outputs but {} were supplied",
+                callee, expected, found),
+            BuiltinArity { name, inputs, outputs } => write!(f,
+                "builtin gate `{}` cannot have {} inputs and {} outputs",
+                name, inputs, outputs),
+            ArrayDimensionMismatch { signal, expected, found } => write!(f,
+                "array `{}` has {} dimensions, but it is used with {}",
+                signal, expected, found),
+            ArrayUsedAsBit { signal } => write!(f,
+                "signal `{}` is used as an array, but also as a bit", signal),
+            MultipleDrivers { signal, drivers } => write!(f,
+                "signal `{}` is connected to {} outputs", signal, drivers),
+            InvalidExpression { message } => write!(f, "invalid expression: {}", message),
+            UndefinedParameter { name } => write!(f, "undefined constant or parameter `{}`", name),
+            NegativeIndex { expr, value } => write!(f,
+                "array index `{}` evaluates to {}, which is negative", expr, value),
+            WrongNumberOfParameters { callee, expected, found } => write!(f,
+                "component `{}` has {} parameters but {} were supplied",
+                callee, expected, found),
+            ImportError { path, message } => write!(f, "cannot import `{}`: {}", path, message),
+            ImportCycle { chain } => write!(f, "import cycle: {}", chain.join(" -> ")),
+            NamespaceClash { namespace } => write!(f,
+                "two different files define the namespace `{}`", namespace),
+            InvalidNamespace { namespace } => write!(f,
+                "`{}` cannot be used as a namespace", namespace),
+            UnknownNamespace { namespace } => write!(f,
+                "namespace `{}` is not imported", namespace),
+            DrivenConstant { constant } => write!(f,
+                "constant `{}` cannot be used as an output", constant),
+            UnknownPort { callee, port } => write!(f,
+                "component `{}` has no port named `{}`", callee, port),
+            DuplicatePortConnection { port } => write!(f,
+                "port `{}` is connected more than once", port),
+            MissingPort { callee, port } => write!(f,
+                "port `{}` of component `{}` is not connected", port, callee),
+            PortWidthMismatch { callee, port, expected, found } => write!(f,
+                "port `{}` of component `{}` has {} bits but {} were connected",
+                port, callee, expected, found),
+            RecursiveChain { chain } => write!(f, "recursive definition: {}", chain.join(" -> ")),
+            TableRowWidth { side, expected, found } => write!(f,
+                "truth table row has {} {} bits, but the component has {}", found, side, expected),
+            TableTooLarge { inputs, max } => write!(f,
+                "truth table has {} inputs, but at most {} are supported", inputs, max),
+            OperandWidthMismatch { op, left, right } => write!(f,
+                "operands of `{}` have different widths: {} and {} bits", op, left, right),
+            TestFailed { port, expected, found } => write!(f,
+                "`{}` is {}, expected {}", port, found, expected),
+            WrongPortDirection { callee, port, direction } => write!(f,
+                "`{}` is not an {} of component `{}`", port, direction, callee),
+            NotSettled { updates } => write!(f,
+                "the component did not settle after {} updates", updates),
+            DuplicateInstance { name } => write!(f,
+                "instance names must be unique, `{}` isn't", name),
+            DuplicateConstant { name } => write!(f,
+                "constant `{}` is defined more than once", name),
+            ElaborationTooDeep { max } => write!(f,
+                "parametric components are nested more than {} levels deep", max),
+            UndrivenSignal { signal } => write!(f,
+                "signal `{}` is read but nothing drives it", signal),
+            UnusedSignal { signal } => write!(f,
+                "signal `{}` is driven but never read", signal),
+            UnconnectedOutput { port } => write!(f, "output `{}` is not connected", port),
+            DiscardedRead => write!(f,
+                "`_` is read here, but it is always unconnected, even if an output is routed to it"),
+            UnknownLint { name } => write!(f, "unknown lint `{}`", name),
+        }
+    }
+}
+
+// Another place of the source code related to a diagnostic, for example
+// the previous definition of a redefined component
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Related {
+    pub message: String,
+    pub file: Option<String>,
+    pub span: Option<Span>,
+    pub location: Option<Location>,
+}
+
+impl fmt::Display for Related {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "note: {}", self.message)?;
+        match (&self.file, &self.location) {
+            (Some(file), Some(l)) => write!(f, " ({}, {})", file, l),
+            (Some(file), None) => write!(f, " ({})", file),
+            (None, Some(l)) => write!(f, " ({})", l),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub kind: DiagnosticKind,
+    // Name of the component being defined when the error was found
+    pub component: Option<String>,
+    // Source file, when compiling more than one file
+    pub file: Option<String>,
+    pub span: Option<Span>,
+    // Filled by `locate`, once the source code is available
+    pub location: Option<Location>,
+    pub related: Vec<Related>,
+    // Hint about how to fix the error, like a similar name
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(kind: DiagnosticKind) -> Self {
+        Self {
+            severity: Severity::Error,
+            kind,
+            component: None,
+            file: None,
+            span: None,
+            location: None,
+            related: vec![],
+            help: None,
+        }
+    }
+    pub fn warning(kind: DiagnosticKind) -> Self {
+        Self { severity: Severity::Warning, ..Self::error(kind) }
+    }
+    pub fn in_component(mut self, name: &str) -> Self {
+        self.component = Some(name.to_string());
+        self
+    }
+    pub fn with_span(mut self, span: Option<Span>) -> Self {
+        self.span = span;
+        self
+    }
+    pub fn in_file(mut self, file: &str) -> Self {
+        self.file = Some(file.to_string());
+        self
+    }
+    pub fn with_related(mut self, message: &str, file: Option<&str>, span: Span) -> Self {
+        self.related.push(Related {
+            message: message.to_string(),
+            file: file.map(|x| x.to_string()),
+            span: Some(span),
+            location: None,
+        });
+        self
+    }
+    pub fn with_help(mut self, help: Option<String>) -> Self {
+        self.help = help;
+        self
+    }
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+    // Convert the byte span into line and column
+    pub fn locate(&mut self, lines: &Lines) {
+        if let Some(span) = self.span {
+            self.location = lines.location(span.start);
+        }
+        for r in self.related.iter_mut() {
+            if r.file == self.file {
+                if let Some(span) = r.span {
+                    r.location = lines.location(span.start);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]: {}", self.severity, self.code(), self.kind)?;
+        let mut place = vec![];
+        if let Some(c) = &self.component {
+            place.push(format!("in component {}", c));
+        }
+        if let Some(file) = &self.file {
+            place.push(file.clone());
+        }
+        if let Some(l) = &self.location {
+            place.push(l.to_string());
+        }
+        if !place.is_empty() {
+            write!(f, " ({})", place.join(", "))?;
+        }
+        for r in &self.related {
+            write!(f, "\n    {}", r)?;
+        }
+        if let Some(help) = &self.help {
+            write!(f, "\n    help: {}", help)?;
+        }
+        Ok(())
+    }
+}
+
+// All the diagnostics found while compiling a source file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl CompileError {
+    pub fn new(diagnostics: Vec<Diagnostic>) -> Self {
+        Self { diagnostics }
+    }
+    pub fn errors(&self) -> impl Iterator<Item=&Diagnostic> {
+        self.diagnostics.iter().filter(|d| d.is_error())
+    }
+    pub fn locate(&mut self, lines: &Lines) {
+        for d in self.diagnostics.iter_mut() {
+            d.locate(lines);
+        }
+    }
+}
+
+impl From<Diagnostic> for CompileError {
+    fn from(d: Diagnostic) -> Self {
+        Self::new(vec![d])
+    }
+}
+
+impl From<Vec<Diagnostic>> for CompileError {
+    fn from(v: Vec<Diagnostic>) -> Self {
+        Self::new(v)
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, d) in self.diagnostics.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", d)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for CompileError {}
+
+// Line/column code taken from
+// https://github.com/gluon-lang/gluon/blob/f8326d21a14b5f21d203e9c43fa5bb7f0688a74c/base/src/source.rs
+#[derive(Debug, Clone)]
+pub struct Lines {
+    starting_bytes: Vec<usize>,
+    end: usize,
+}
+
+impl Lines {
+    /// Creates a mapping for `src`
+    pub fn new<I>(src: I) -> Lines
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        use std::iter;
+
+        let mut len = 0;
+        let starting_bytes = {
+            let input_indices = src.into_iter()
+                .inspect(|_| len += 1)
+                .enumerate()
+                .filter(|&(_, b)| b == b'\n')
+                .map(|(i, _)| i + 1); // index of first char in the line
+
+            iter::once(0).chain(input_indices).collect()
+        };
+        Lines {
+            starting_bytes,
+            end: len,
+        }
+    }
+    /// Returns the byte offset of the start of `line_number`
+    pub fn line(&self, line_number: Line) -> Option<usize> {
+        let line_number = line_number.0 - 1;
+        self.starting_bytes.get(line_number).cloned()
+    }
+    /// Returns the line and column location of `byte`
+    pub fn location(&self, byte: usize) -> Option<Location> {
+        if byte <= self.end {
+            let line_index = self.line_number_at_byte(byte);
+
+            self.line(line_index).map(|line_byte| {
+                Location {
+                    line: line_index,
+                    column: Column(byte - line_byte + 1),
+                    absolute: byte,
+                }
+            })
+        } else {
+            None
+        }
+    }
+    /// Returns which line `byte` points to
+    pub fn line_number_at_byte(&self, byte: usize) -> Line {
+        let num_lines = self.starting_bytes.len();
+
+        Line(
+            (0..num_lines)
+                .filter(|&i| self.starting_bytes[i] > byte)
+                .map(|i| i - 1)
+                .next()
+                .unwrap_or(num_lines - 1) + 1
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Line(pub usize);
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Column(pub usize);
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub line: Line,
+    pub column: Column,
+    pub absolute: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, col {}", self.line.0, self.column.0)
+    }
+}
@@ -13,6 +13,15 @@ impl YosysJson {
         let mut modules = HashMap::new();
         let m = Module::from_structural(c);
         modules.insert(c.name().to_string(), m);
+        // Native components like truth tables are cells of the top module,
+        // and their Nand implementation is a module with the same name
+        for x in c.components.iter().skip(1) {
+            if !modules.contains_key(x.comp.name()) {
+                if let Some(s) = x.comp.lower() {
+                    modules.insert(x.comp.name().to_string(), Module::from_structural(&s));
+                }
+            }
+        }
 
         Self {
             creator: "comphdl 0.3".to_string(),
@@ -23,6 +32,9 @@ impl YosysJson {
 
 #[derive(Serialize, Deserialize)]
 struct Module {
+    // The doc comment of the component
+    #[serde(default, skip_serializing_if = "HashMap::is_empty", serialize_with = "ordered_map")]
+    attributes: HashMap<String, String>,
     #[serde(serialize_with = "ordered_map")]
     ports: HashMap<String, Port>,
     #[serde(serialize_with = "ordered_map")]
@@ -46,14 +58,31 @@ impl Module {
             let connections = &c.components[c_id].connections;
             let port_names = c.components[c_id].comp.port_names();
             let cell = Cell::new(c_id, name, n_in, n_out, connections, &pin_addr_to_yosys_addr, &port_names);
-            let name = c.components[c_id].comp.name();
-            cells.insert(format!("${}$input.v:1${}", name, c_id), cell);
+            // Instances without a name get one in the style of yosys
+            let cell_name = match &c.components[c_id].label {
+                Some(label) => label.clone(),
+                None => format!("${}$input.v:1${}", c.components[c_id].comp.name(), c_id),
+            };
+            cells.insert(cell_name, cell);
+        }
+
+        let mut attributes = HashMap::new();
+        if let Some(doc) = &c.info.doc {
+            attributes.insert("doc".to_string(), doc.clone());
+        }
+        // Only the ports with doc comments have a netname
+        let mut netnames = HashMap::new();
+        for (name, port) in ports.iter() {
+            if let Some(doc) = c.info.port_doc(name) {
+                netnames.insert(name.clone(), Netname::with_doc(port.bits.clone(), doc));
+            }
         }
 
         Self {
+            attributes,
             ports,
             cells,
-            netnames: HashMap::new(), // netnames aren't important
+            netnames,
         }
     }
 }
@@ -156,15 +185,26 @@ impl Cell {
 struct Netname {
     hide_name: u8,
     bits: Vec<usize>,
-    //attributes
+    #[serde(default, skip_serializing_if = "HashMap::is_empty", serialize_with = "ordered_map")]
+    attributes: HashMap<String, String>,
 }
 
 impl Netname {
+    fn with_doc(bits: Vec<usize>, doc: &str) -> Netname {
+        let mut attributes = HashMap::new();
+        attributes.insert("doc".to_string(), doc.to_string());
+        Netname {
+            hide_name: 0,
+            bits,
+            attributes,
+        }
+    }
     #[allow(dead_code)]
     fn new(n: usize) -> Netname {
         Netname {
             hide_name: 1,
             bits: vec![n],
+            attributes: HashMap::new(),
         }
     }
 }
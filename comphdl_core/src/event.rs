@@ -0,0 +1,360 @@
+// Event driven simulation. Every primitive component (anything which is not
+// a Structural: Nand gates, truth tables, Stdin...) has a propagation delay,
+// and a change of its inputs at time t changes its outputs at time t + delay,
+// no matter how deep it is in the hierarchy.
+//
+// Time is measured in units, and one update of the simulated component
+// advances the time by one tick, which is `tick` units long.
+//
+// The Structural::update sweep is still available as Timing::UnitDelay: each
+// update propagates the signals one component further at each level of the
+// hierarchy, which is what existing designs like Clk2 were written for.
+use crate::bit::Bit;
+use crate::component::{Component, CompIo, PortNames, Structural, VcdSignalHandle};
+use crate::flatten::{find_prims, net_slots, structural, Slot};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use vcd;
+
+// Propagation delay of each primitive, by component name
+#[derive(Debug, Clone)]
+pub struct Delays {
+    default: u64,
+    by_name: HashMap<String, u64>,
+}
+
+impl Delays {
+    // A delay of 0 would let an oscillator run forever without advancing
+    // the time, so delays are at least 1
+    pub fn new(default: u64) -> Self {
+        Self { default: default.max(1), by_name: HashMap::new() }
+    }
+    pub fn with_delay(mut self, name: &str, delay: u64) -> Self {
+        self.by_name.insert(name.to_string(), delay.max(1));
+        self
+    }
+    pub fn delay(&self, name: &str) -> u64 {
+        self.by_name.get(name).cloned().unwrap_or(self.default)
+    }
+}
+
+impl Default for Delays {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Timing {
+    // The sweep of Structural::update
+    UnitDelay,
+    EventDriven { delays: Delays, tick: u64 },
+}
+
+// Wrap a component so it is simulated with the given timing
+pub fn with_timing(c: Box<dyn Component>, timing: Timing) -> Box<dyn Component> {
+    match timing {
+        Timing::UnitDelay => c,
+        Timing::EventDriven { delays, tick } => {
+            let s = c.clone_as_structural().expect("any component can be wrapped in a Structural");
+            Box::new(EventSim::new(s, &delays, tick))
+        }
+    }
+}
+
+// All the ports which carry the same signal, across the hierarchy. The
+// first slot is the driver: a primitive output or an input of the top
+// component
+#[derive(Debug, Clone)]
+struct Net {
+    slots: Vec<Slot>,
+    // Primitives reading this net
+    loads: Vec<usize>,
+    value: Bit,
+    // Value after all the scheduled changes
+    projected: Bit,
+}
+
+#[derive(Debug, Clone)]
+struct Prim {
+    path: Vec<usize>,
+    delay: u64,
+    // Net driven by each output
+    outputs: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Action {
+    Drive(usize, Bit),
+    Eval(usize),
+}
+
+// Events with the same time are run in the order they were scheduled
+#[derive(Debug, Clone)]
+struct Event {
+    time: u64,
+    seq: u64,
+    action: Action,
+}
+
+impl PartialEq for Event {
+    fn eq(&self, other: &Self) -> bool {
+        (self.time, self.seq) == (other.time, other.seq)
+    }
+}
+
+impl Eq for Event {}
+
+impl Ord for Event {
+    // BinaryHeap is a max heap, the earliest event must be the largest
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.time, other.seq).cmp(&(self.time, self.seq))
+    }
+}
+
+impl PartialOrd for Event {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EventSim {
+    // The values of the nets are also written to the ports of every CompIo,
+    // so the VCD output and the netlists work as with the sweep
+    top: Structural,
+    prims: Vec<Prim>,
+    nets: Vec<Net>,
+    // Net driven by each input of the top component
+    inputs: Vec<usize>,
+    queue: BinaryHeap<Event>,
+    seq: u64,
+    time: u64,
+    tick: u64,
+}
+
+impl EventSim {
+    pub fn new(top: Structural, delays: &Delays, tick: u64) -> Self {
+        let mut prim_paths = vec![];
+        find_prims(&top, &mut vec![], &mut prim_paths);
+        let index: HashMap<Vec<usize>, usize> = prim_paths.iter().cloned().enumerate()
+            .map(|(i, p)| (p, i))
+            .collect();
+
+        let mut nets = vec![];
+        let inputs = (0..top.num_inputs()).map(|i| {
+            nets.push(net(&top, &index, &[], 0, i));
+            nets.len() - 1
+        }).collect();
+        let mut prims = vec![];
+        for path in prim_paths {
+            let (&c_id, parent) = path.split_last().unwrap();
+            let c = &structural(&top, parent).components[c_id].comp;
+            let outputs = (0..c.num_outputs()).map(|o| {
+                nets.push(net(&top, &index, parent, c_id, o));
+                nets.len() - 1
+            }).collect();
+            prims.push(Prim { delay: delays.delay(c.name()), path, outputs });
+        }
+
+        let mut sim = Self { top, prims, nets, inputs, queue: BinaryHeap::new(), seq: 0, time: 0, tick: tick.max(1) };
+        // Every primitive is evaluated once, like the first sweep does
+        for p in 0..sim.prims.len() {
+            sim.schedule(0, Action::Eval(p));
+        }
+
+        sim
+    }
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+    fn schedule(&mut self, time: u64, action: Action) {
+        self.seq += 1;
+        self.queue.push(Event { time, seq: self.seq, action });
+    }
+    // Run the events before `end`
+    fn run_until(&mut self, end: u64) {
+        while let Some(time) = self.queue.peek().map(|e| e.time).filter(|&t| t < end) {
+            let mut dirty = vec![];
+            while self.queue.peek().map(|e| e.time) == Some(time) {
+                match self.queue.pop().unwrap().action {
+                    Action::Drive(net, value) => self.set_net(net, value, &mut dirty),
+                    Action::Eval(p) => dirty.push(p),
+                }
+            }
+            // A primitive with many changed inputs is evaluated only once
+            dirty.sort_unstable();
+            dirty.dedup();
+            for p in dirty {
+                self.eval(p, time);
+            }
+        }
+    }
+    fn set_net(&mut self, net: usize, value: Bit, dirty: &mut Vec<usize>) {
+        if self.nets[net].value == value {
+            return;
+        }
+        self.nets[net].value = value;
+        for slot in &self.nets[net].slots {
+            let io = comp_io(&mut self.top, &slot.path, slot.c_id);
+            if slot.output {
+                io.output[slot.port] = value;
+            } else {
+                io.input[slot.port] = value;
+            }
+        }
+        dirty.extend_from_slice(&self.nets[net].loads);
+    }
+    fn eval(&mut self, p: usize, time: u64) {
+        let (&c_id, parent) = self.prims[p].path.split_last().unwrap();
+        let io = comp_io(&mut self.top, parent, c_id);
+        let output = io.comp.update(&io.input);
+        let again = io.comp.needs_update();
+        let delay = self.prims[p].delay;
+        for (o, value) in output.into_iter().enumerate() {
+            let net = self.prims[p].outputs[o];
+            if self.nets[net].projected != value {
+                self.nets[net].projected = value;
+                self.schedule(time + delay, Action::Drive(net, value));
+            }
+        }
+        if again {
+            self.schedule(time + 1, Action::Eval(p));
+        }
+    }
+}
+
+impl Component for EventSim {
+    fn update(&mut self, input: &[Bit]) -> Vec<Bit> {
+        assert_eq!(input.len(), self.num_inputs());
+        for (i, &value) in input.iter().enumerate() {
+            let net = self.inputs[i];
+            if self.nets[net].projected != value {
+                self.nets[net].projected = value;
+                self.schedule(self.time, Action::Drive(net, value));
+            }
+        }
+        self.time += self.tick;
+        self.run_until(self.time);
+
+        self.top.output()
+    }
+    fn needs_update(&self) -> bool {
+        !self.queue.is_empty()
+    }
+    fn num_inputs(&self) -> usize {
+        self.top.num_inputs()
+    }
+    fn num_outputs(&self) -> usize {
+        self.top.num_outputs()
+    }
+    fn name(&self) -> &str {
+        self.top.name()
+    }
+    fn write_internal_components(&self, w: &mut vcd::Writer<'_>, j: &mut u64) -> io::Result<VcdSignalHandle> {
+        self.top.write_internal_components(w, j)
+    }
+    fn write_internal_signals(&self, w: &mut vcd::Writer<'_>, j: &mut u64, vh: &VcdSignalHandle) -> io::Result<()> {
+        self.top.write_internal_signals(w, j, vh)
+    }
+    fn port_names(&self) -> PortNames {
+        self.top.port_names()
+    }
+    fn internal_inputs(&self) -> Option<Vec<Vec<Bit>>> {
+        self.top.internal_inputs()
+    }
+    fn as_structural(&self) -> Option<&Structural> {
+        Some(&self.top)
+    }
+    fn clone_as_structural(&self) -> Option<Structural> {
+        Some(self.top.clone())
+    }
+    fn box_clone(&self) -> Box<dyn Component> {
+        Box::new(self.clone())
+    }
+}
+
+fn comp_io<'a>(top: &'a mut Structural, path: &[usize], c_id: usize) -> &'a mut CompIo {
+    let mut s = top;
+    for &i in path {
+        s = s.components[i].comp.as_structural_mut().unwrap();
+    }
+    &mut s.components[c_id]
+}
+
+// The net driven by output `port` of component `c_id` of the Structural at
+// `path`. For the component 0 this is an input of the Structural
+fn net(top: &Structural, prims: &HashMap<Vec<usize>, usize>, path: &[usize], c_id: usize, port: usize) -> Net {
+    let slots = net_slots(top, path, c_id, port);
+    let loads = slots.iter()
+        .filter(|s| !s.output)
+        .filter_map(|s| prims.get(&s.component()).cloned())
+        .collect();
+    Net { slots, loads, value: Bit::X, projected: Bit::X }
+}
+
+#[test]
+fn gate_delays() {
+    use crate::parser::parse_str;
+    use crate::bit::Bit::*;
+    let d = r#"
+component Not(a) -> y {
+    Nand(a) -> y;
+}
+component Not2(a) -> y {
+    Not(a) -> b;
+    Not(b) -> y;
+}
+component Flat(a) -> y {
+    Nand(a) -> b;
+    Nand(b) -> c;
+    Nand(c) -> y;
+}
+component Nested(a) -> y {
+    Not2(a) -> b;
+    Not(b) -> y;
+}
+component Clk2(a) -> x {
+    Nand(a, x) -> x;
+}
+"#;
+    let cf = parse_str(d).unwrap();
+    let event = |name: &str, delays: Delays, tick| {
+        with_timing(cf.create_named(name).unwrap(), Timing::EventDriven { delays, tick })
+    };
+    // The number of ticks until the output changes
+    let latency = |mut c: Box<dyn Component>| {
+        (1..).find(|_| c.update(&[L]) == vec![H]).unwrap()
+    };
+
+    // The hierarchy does not change the timing, only the gates do
+    assert_eq!(latency(event("Flat", Delays::default(), 1)), 4);
+    assert_eq!(latency(event("Nested", Delays::default(), 1)), 4);
+    assert_eq!(latency(event("Nested", Delays::new(2), 1)), 7);
+    assert_eq!(latency(event("Nested", Delays::new(1).with_delay("Nand", 3), 1)), 10);
+    // A tick of 3 units: the change at time 9 is seen after the 4th update
+    assert_eq!(latency(event("Nested", Delays::new(3), 3)), 4);
+    // The sweep does not use time units, one update is one pass
+    let legacy = |name| latency(with_timing(cf.create_named(name).unwrap(), Timing::UnitDelay));
+    assert_eq!(legacy("Flat"), 3);
+    assert_eq!(legacy("Nested"), 3);
+
+    // The same instances are simulated, so their ports can be inspected
+    let mut c = event("Nested", Delays::default(), 1);
+    for _ in 0..4 {
+        c.update(&[L]);
+    }
+    assert!(!c.needs_update());
+    let s = c.as_structural().unwrap();
+    assert_eq!(s.components[1].output, vec![L]);
+    let not2 = s.components[1].comp.as_structural().unwrap();
+    assert_eq!(not2.components[1].output, vec![H]);
+
+    // An oscillator never settles, and toggles every 2 units
+    let mut c = event("Clk2", Delays::new(2), 1);
+    let input = [L, L, L, H, H, H, H, H, H, H];
+    let out: Vec<_> = input.iter().map(|&a| c.update(&[a])[0]).collect();
+    assert_eq!(out, vec![X, X, H, H, H, L, L, H, H, L]);
+    assert!(c.needs_update());
+}
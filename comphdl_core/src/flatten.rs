@@ -0,0 +1,427 @@
+// Flatten a hierarchy of Structurals into one Structural whose components
+// are all primitives: Nand gates, truth tables, Stdin... Every nested
+// Structural copies its inputs and outputs through its c_zero, which costs
+// one propagation step for each level, and the flat netlist has none of
+// that.
+//
+// The cells of the flat netlist are labelled with their hierarchical path,
+// u_cpu.u_alu.Nand-3, so the VCD output keeps the original names, and
+// FlatNet maps each flat signal back to the ports it crosses. Flat is also a
+// Component, which adds these names to the VCD output.
+use crate::bit::Bit;
+use crate::component::{Component, ComponentIndex, CompIo, Index, InstanceIndex, PortNames, Structural, VcdSignalHandle};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use vcd;
+
+// One port of a CompIo: the component at `c_id` inside the Structural at
+// `path`, which is the list of local component ids starting from the top
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Slot {
+    pub path: Vec<usize>,
+    pub c_id: usize,
+    pub output: bool,
+    pub port: usize,
+}
+
+impl Slot {
+    // Path of the component which owns this port
+    pub fn component(&self) -> Vec<usize> {
+        let mut v = self.path.clone();
+        v.push(self.c_id);
+        v
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Flat {
+    pub structural: Structural,
+    // Path of each cell in the original hierarchy, the first one is the
+    // c_zero and its path is empty
+    pub cells: Vec<Vec<usize>>,
+    pub nets: Vec<FlatNet>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FlatNet {
+    // An input of the top component (c_id 0) or an output of a cell
+    pub driver: ComponentIndex,
+    // Hierarchical names of the signal: the driver first, then every port
+    // of a Structural which it crosses: u_alu.sum
+    pub names: Vec<String>,
+}
+
+impl Flat {
+    pub fn net(&self, driver: &ComponentIndex) -> Option<&FlatNet> {
+        self.nets.iter().find(|n| n.driver == *driver)
+    }
+    // The current value of a signal, by any of its hierarchical names
+    pub fn signal(&self, name: &str) -> Option<Bit> {
+        let net = self.nets.iter().find(|n| n.names.iter().any(|x| x == name))?;
+        let c = &self.structural.components[net.driver.c_id];
+        // The inputs of the top component are the outputs of its c_zero
+        Some(c.output[net.driver.port_id])
+    }
+    // Index of the VCD wire of the driver of a net, see
+    // Structural::write_internal_components
+    fn vcd_index(&self, driver: &ComponentIndex) -> InstanceIndex {
+        let inputs = match driver.c_id {
+            0 => 0,
+            c_id => self.structural.components[c_id].comp.num_inputs(),
+        };
+        InstanceIndex::new(driver.c_id, inputs + driver.port_id)
+    }
+}
+
+// The original hierarchy in the VCD output: one module for each instance
+// and one wire for each port it had
+#[derive(Default)]
+struct VcdScope<'a> {
+    wires: Vec<(&'a str, vcd::IdCode)>,
+    children: BTreeMap<&'a str, VcdScope<'a>>,
+}
+
+impl<'a> VcdScope<'a> {
+    fn write(&self, w: &mut vcd::Writer<'_>) -> io::Result<()> {
+        for (name, id) in &self.wires {
+            w.var_def(vcd::VarType::Wire, 1, *id, name)?;
+        }
+        for (name, child) in &self.children {
+            w.add_module(name)?;
+            child.write(w)?;
+            w.upscope()?;
+        }
+        Ok(())
+    }
+}
+
+impl Component for Flat {
+    fn update(&mut self, input: &[Bit]) -> Vec<Bit> {
+        self.structural.update(input)
+    }
+    fn needs_update(&self) -> bool {
+        self.structural.needs_update()
+    }
+    fn num_inputs(&self) -> usize {
+        self.structural.num_inputs()
+    }
+    fn num_outputs(&self) -> usize {
+        self.structural.num_outputs()
+    }
+    fn name(&self) -> &str {
+        self.structural.name()
+    }
+    // The wires of the flat cells, and the hierarchical names of the nets,
+    // which share the wire of their driver
+    fn write_internal_components(&self, w: &mut vcd::Writer<'_>, j: &mut u64) -> io::Result<VcdSignalHandle> {
+        let vh = self.structural.write_internal_components(w, j)?;
+        let mut root = VcdScope::default();
+        for net in &self.nets {
+            let id = vh.id[&self.vcd_index(&net.driver)];
+            for name in &net.names {
+                let mut path: Vec<_> = name.split('.').collect();
+                let wire = path.pop().unwrap();
+                let scope = path.into_iter().fold(&mut root, |s, x| s.children.entry(x).or_default());
+                scope.wires.push((wire, id));
+            }
+        }
+        w.add_module(self.name())?;
+        root.write(w)?;
+        w.upscope()?;
+
+        Ok(vh)
+    }
+    fn write_internal_signals(&self, w: &mut vcd::Writer<'_>, j: &mut u64, vh: &VcdSignalHandle) -> io::Result<()> {
+        self.structural.write_internal_signals(w, j, vh)
+    }
+    fn port_names(&self) -> PortNames {
+        self.structural.port_names()
+    }
+    fn internal_inputs(&self) -> Option<Vec<Vec<Bit>>> {
+        self.structural.internal_inputs()
+    }
+    fn as_structural(&self) -> Option<&Structural> {
+        Some(&self.structural)
+    }
+    fn as_structural_mut(&mut self) -> Option<&mut Structural> {
+        Some(&mut self.structural)
+    }
+    fn clone_as_structural(&self) -> Option<Structural> {
+        Some(self.structural.clone())
+    }
+    fn box_clone(&self) -> Box<dyn Component> {
+        Box::new(self.clone())
+    }
+}
+
+pub fn flatten(top: &Structural) -> Flat {
+    build(top, true)
+}
+
+// The flat netlist without the names of the nets, there are millions of them
+// in a big design
+pub(crate) fn netlist(top: &Structural) -> Structural {
+    build(top, false).structural
+}
+
+fn build(top: &Structural, with_names: bool) -> Flat {
+    let h = Hierarchy::new(top);
+    let mut cells = vec![vec![]];
+    cells.extend(h.prims.iter().cloned());
+
+    let mut components = vec![CompIo::c_zero(top.num_inputs(), top.num_outputs())];
+    for (path, label) in h.prims.iter().zip(&h.labels) {
+        let (&c_id, parent) = path.split_last().unwrap();
+        let comp = structural(top, parent).components[c_id].comp.clone();
+        components.push(CompIo::new(comp).with_label(Some(label.clone())));
+    }
+
+    let mut nets = vec![];
+    let drivers = (0..top.num_inputs()).map(|i| (vec![], 0, i))
+        .chain(h.prims.iter().flat_map(|path| {
+            let (&c_id, parent) = path.split_last().unwrap();
+            let n = structural(top, parent).components[c_id].comp.num_outputs();
+            (0..n).map(move |o| (parent.to_vec(), c_id, o))
+        }));
+    for (parent, c_id, port) in drivers {
+        let slots = net_slots(top, &parent, c_id, port);
+        let from = h.levels[&parent].flat_ids[c_id];
+        for s in slots.iter().filter(|s| !s.output) {
+            if s.path.is_empty() && s.c_id == 0 {
+                components[from].add_connection(port, Index::new(0, s.port));
+            } else if s.c_id != 0 {
+                match h.levels[&s.path].flat_ids[s.c_id] {
+                    // A nested Structural, its c_zero is in the slots too
+                    0 => {}
+                    to => components[from].add_connection(port, Index::new(to, s.port)),
+                }
+            }
+        }
+        if with_names {
+            let mut names = vec![h.slot_name(&slots[0])];
+            names.extend(slots.iter().skip(1).filter(|s| s.c_id == 0).map(|s| h.slot_name(s)));
+            names.dedup();
+            nets.push(FlatNet { driver: ComponentIndex::output(from, port), names });
+        }
+    }
+
+    let structural = Structural::new(components, top.info.clone());
+
+    Flat { structural, cells, nets }
+}
+
+impl Structural {
+    pub fn flatten(&self) -> Flat {
+        flatten(self)
+    }
+}
+
+// One Structural of the hierarchy: its instance path, and the flat id of
+// each of its components, 0 for the c_zero and the nested Structurals
+struct Level {
+    name: String,
+    flat_ids: Vec<usize>,
+}
+
+// The hierarchy, visited once: the names are not rebuilt with instance_path
+// for each port
+struct Hierarchy<'a> {
+    top: &'a Structural,
+    prims: Vec<Vec<usize>>,
+    // Instance path of each primitive
+    labels: Vec<String>,
+    levels: HashMap<Vec<usize>, Level>,
+}
+
+impl<'a> Hierarchy<'a> {
+    fn new(top: &'a Structural) -> Self {
+        let mut h = Self { top, prims: vec![], labels: vec![], levels: HashMap::new() };
+        h.visit(top, &mut vec![], String::new());
+        h
+    }
+    fn visit(&mut self, s: &Structural, path: &mut Vec<usize>, name: String) {
+        let mut flat_ids = vec![0; s.components.len()];
+        for (c_id, c) in s.components.iter().enumerate().skip(1) {
+            let label = join(&name, c.instance_name(c_id));
+            path.push(c_id);
+            match c.comp.as_structural() {
+                Some(child) => self.visit(child, path, label),
+                None => {
+                    self.prims.push(path.clone());
+                    self.labels.push(label);
+                    flat_ids[c_id] = self.prims.len();
+                }
+            }
+            path.pop();
+        }
+        self.levels.insert(path.clone(), Level { name, flat_ids });
+    }
+    // u_alu.a, or a for the ports of the top component
+    fn slot_name(&self, s: &Slot) -> String {
+        let s_at = structural(self.top, &s.path);
+        let level = &self.levels[&s.path];
+        if s.c_id == 0 {
+            // The c_zero has the ports of its Structural, reversed
+            let info = &s_at.info;
+            let port = if s.output { &info.inputs[s.port] } else { &info.outputs[s.port] };
+            join(&level.name, port.clone())
+        } else {
+            let c = &s_at.components[s.c_id];
+            let names = c.comp.port_names();
+            let port = if s.output { &names.output[s.port] } else { &names.input[s.port] };
+            join(&join(&level.name, c.instance_name(s.c_id)), port.clone())
+        }
+    }
+}
+
+fn join(prefix: &str, name: String) -> String {
+    if prefix.is_empty() {
+        name
+    } else {
+        format!("{}.{}", prefix, name)
+    }
+}
+
+pub(crate) fn structural<'a>(top: &'a Structural, path: &[usize]) -> &'a Structural {
+    let mut s = top;
+    for &c_id in path {
+        s = s.components[c_id].comp.as_structural().unwrap();
+    }
+    s
+}
+
+// Paths of all the components which are not a Structural
+pub(crate) fn find_prims(s: &Structural, path: &mut Vec<usize>, prims: &mut Vec<Vec<usize>>) {
+    for c_id in 1..s.components.len() {
+        path.push(c_id);
+        match s.components[c_id].comp.as_structural() {
+            Some(child) => find_prims(child, path, prims),
+            None => prims.push(path.clone()),
+        }
+        path.pop();
+    }
+}
+
+// All the ports connected to output `port` of component `c_id` of the
+// Structural at `path`, starting with that output. For the component 0 this
+// is an input of the Structural
+pub(crate) fn net_slots(top: &Structural, path: &[usize], c_id: usize, port: usize) -> Vec<Slot> {
+    let mut slots = vec![Slot { path: path.to_vec(), c_id, output: true, port }];
+    fanout(top, path, c_id, port, &mut slots);
+    slots
+}
+
+fn fanout(top: &Structural, path: &[usize], c_id: usize, port: usize, slots: &mut Vec<Slot>) {
+    let s = structural(top, path);
+    for to in &s.connections[c_id][port] {
+        let slot = |path: &[usize], c_id, output, port| Slot { path: path.to_vec(), c_id, output, port };
+        slots.push(slot(path, to.comp_id, false, to.input_id));
+        if to.comp_id == 0 {
+            // An output of this Structural, seen from its parent
+            if let Some((&inst, parent)) = path.split_last() {
+                slots.push(slot(parent, inst, true, to.input_id));
+                fanout(top, parent, inst, to.input_id, slots);
+            }
+            continue;
+        }
+        if s.components[to.comp_id].comp.as_structural().is_some() {
+            let mut child = path.to_vec();
+            child.push(to.comp_id);
+            slots.push(slot(&child, 0, true, to.input_id));
+            fanout(top, &child, 0, to.input_id, slots);
+        }
+    }
+}
+
+#[test]
+fn flatten_hierarchy() {
+    use crate::bit::Bit::{self, *};
+    use crate::parser::parse_str;
+    let d = r#"
+component Not(a) -> y {
+    Nand(a) -> y;
+}
+component And(a, b) -> y {
+    Nand(a, b) -> n;
+    u_not: Not(n) -> y;
+}
+table Xor(a, b) -> y { 00 -> 0; 01 -> 1; 10 -> 1; 11 -> 0; }
+component Half(a, b) -> (s, c) {
+    u_xor: Xor(a, b) -> s;
+    u_and: And(a, b) -> c;
+}
+component Top(a, b, c) -> (s, co, a2) {
+    u_h: Half(a, b) -> (s1, c1);
+    Half(s1, c) -> (s, c2);
+    co = c1 | c2;
+    a2 = a;
+}
+"#;
+    let cf = parse_str(d).unwrap();
+    let c = cf.create_named("Top").unwrap();
+    let s = c.as_structural().unwrap();
+    let flat = s.flatten();
+    let f = &flat.structural;
+    // Nothing but primitives
+    assert!(f.components.iter().skip(1).all(|c| c.comp.as_structural().is_none()));
+    assert_eq!(flat.cells.len(), f.components.len());
+    let labels: Vec<_> = f.components.iter().skip(1).map(|c| c.label.clone().unwrap()).collect();
+    assert!(labels.contains(&"u_h.u_xor".to_string()), "{:?}", labels);
+    assert!(labels.contains(&"u_h.u_and.u_not.Nand-1".to_string()), "{:?}", labels);
+    let i = labels.iter().position(|x| x == "u_h.u_xor").unwrap() + 1;
+    assert_eq!(s.instance_path(&flat.cells[i]).unwrap(), "u_h.u_xor");
+    // The labels are found whole
+    assert_eq!(f.find_instance("u_h.u_xor"), Some(vec![i]));
+    assert_eq!(f.instance_path(&[i]).unwrap(), "u_h.u_xor");
+    assert_eq!(s.find_instance("u_h.u_xor"), Some(flat.cells[i].clone()));
+
+    // The names of the signals
+    // The driver comes first, the order of the others is not specified
+    let names = |driver| {
+        let mut names = flat.net(&driver).unwrap().names.clone();
+        names[1..].sort();
+        names
+    };
+    assert_eq!(names(ComponentIndex::output(0, 0)), vec!["a", "a2", "u_h.a", "u_h.u_and.a"]);
+    assert_eq!(names(ComponentIndex::output(i, 0)), vec!["u_h.u_xor.y", "Half-2.a", "Half-2.u_and.a", "u_h.s"]);
+
+    // Same behavior once the signals settle
+    let mut h = c.clone();
+    let mut fl: Box<dyn Component> = Box::new(flat.clone());
+    let settle = |c: &mut Box<dyn Component>, input: &[Bit]| {
+        let mut out = c.update(input);
+        while c.needs_update() {
+            out = c.update(input);
+        }
+        out
+    };
+    for x in 0..8 {
+        let input: Vec<_> = (0..3).map(|j| Bit::from_bool((x >> j) & 1 == 1)).collect();
+        let expected = settle(&mut h, &input);
+        assert_eq!(settle(&mut fl, &input), expected);
+        assert!(!expected.contains(&X));
+    }
+    let mut flat = flat;
+    settle(&mut (Box::new(flat.structural.clone()) as Box<dyn Component>), &[H, L, L]);
+    for _ in 0..10 {
+        flat.update(&[H, L, L]);
+    }
+    // a XOR b of the first half adder
+    assert_eq!(flat.signal("u_h.s"), Some(H));
+    assert_eq!(flat.signal("Half-2.a"), Some(H));
+    assert_eq!(flat.signal("b"), Some(L));
+    assert_eq!(flat.signal("u_h.nothing"), None);
+
+    // The VCD uses the hierarchical names
+    let mut vcd = vec![];
+    let inputs = vec![vec![L, H, L]];
+    crate::simulation::run_simulation(&mut vcd, &mut *fl, &mut inputs.into_iter(), 1).unwrap();
+    let vcd = String::from_utf8(vcd).unwrap();
+    assert!(vcd.contains("$scope module u_h.u_and.u_not.Nand-1 $end"), "{}", vcd);
+    // The original hierarchy, with the wire of the driver
+    let y = vcd.lines().find(|l| l.ends_with(" u_h.u_xor-y $end")).unwrap().split(' ').nth(3).unwrap();
+    let u_h = &vcd[vcd.find("$scope module u_h $end").unwrap()..];
+    assert!(vcd.contains("$scope module Top $end"), "{}", vcd);
+    assert!(u_h.contains(&format!("$var wire 1 {} s $end", y)), "{}", vcd);
+    assert!(u_h.contains(&format!("$scope module u_xor $end\n$var wire 1 {} y $end", y)), "{}", vcd);
+}
@@ -0,0 +1,698 @@
+// Source code formatter: prints a parsed file in the canonical layout.
+// Comments are kept, and blank lines inside a component body are kept
+// (at most one), everything else is decided by the formatter
+use crate::ast::{BinOp, BitArray, CmpOp, Comment, CompCall, CompDef, Cond, ConstDef, Expr, File, Import, IntExpr, LogicOp, PortValue, Range, Signal, Statement, Table, TableRow, TestDef, TestStatement, Use};
+use crate::bit::Bit;
+use crate::diagnostic::CompileError;
+use crate::parser::parse_ast;
+
+const INDENT: &str = "    ";
+
+pub fn format_str(source: &str) -> Result<String, CompileError> {
+    let file = parse_ast(source)?;
+
+    Ok(format_file(&file, source))
+}
+
+// Returns true if the source code is already formatted
+pub fn check(source: &str) -> Result<bool, CompileError> {
+    Ok(format_str(source)? == source)
+}
+
+// The source code is only used to find blank lines and comments written
+// after a statement
+pub fn format_file(file: &File, source: &str) -> String {
+    let mut p = Printer { out: String::new(), source };
+    // Everything is kept in the source order
+    let mut items: Vec<(usize, Item)> = file.imports.iter().map(|x| (x.span.start, Item::Import(x)))
+        .chain(file.uses.iter().map(|x| (x.span.start, Item::Use(x))))
+        .chain(file.consts.iter().map(|x| (x.span.start, Item::Const(x))))
+        .chain(file.components.iter().map(|x| (p.def_start(x), Item::Def(x))))
+        .chain(file.tests.iter().map(|x| (x.span.start, Item::Test(x))))
+        .chain(file.comments.iter().map(|x| (x.span.start, Item::Comment(x))))
+        .collect();
+    items.sort_by_key(|(start, _)| *start);
+    let mut prev: Option<&Item> = None;
+    for (start, item) in &items {
+        // Imports, uses and constants are grouped, definitions are separated by a
+        // blank line. Comments keep the blank lines of the source, but
+        // they are never attached to the end of a definition
+        if let Some(prev) = prev {
+            match (prev, item) {
+                (_, Item::Comment(c)) => {
+                    if p.separate(*start, Some(c)) {
+                        continue;
+                    }
+                    if p.newlines_before(*start) == 1 && matches!(prev, Item::Def(_) | Item::Test(_)) {
+                        p.out.push('\n');
+                    }
+                }
+                (Item::Comment(_), _) => {
+                    if p.newlines_before(*start) > 1 {
+                        p.out.push('\n');
+                    }
+                }
+                (Item::Import(_), Item::Import(_)) | (Item::Use(_), Item::Use(_))
+                    | (Item::Const(_), Item::Const(_)) => {}
+                _ => p.out.push('\n'),
+            }
+        }
+        match item {
+            Item::Import(x) => match &x.alias {
+                Some(alias) => p.line(0, &format!("import \"{}\" as {};", x.path, alias)),
+                None => p.line(0, &format!("import \"{}\";", x.path)),
+            },
+            Item::Use(x) => p.line(0, &format!("use {}::{};", x.namespace, x.name)),
+            Item::Const(x) => p.line(0, &format!("const {} = {};", x.name, int_expr(&x.value, 0))),
+            Item::Def(def) => match &def.table {
+                Some(table) => p.table(def, table),
+                None => p.component(def),
+            },
+            Item::Test(test) => p.test(test),
+            Item::Comment(c) => p.line(0, &c.text),
+        }
+        prev = Some(item);
+    }
+
+    p.out
+}
+
+enum Item<'a> {
+    Import(&'a Import),
+    Use(&'a Use),
+    Const(&'a ConstDef),
+    Def(&'a CompDef),
+    Test(&'a TestDef),
+    Comment(&'a Comment),
+}
+
+struct Printer<'a> {
+    out: String,
+    source: &'a str,
+}
+
+impl<'a> Printer<'a> {
+    fn line(&mut self, indent: usize, s: &str) {
+        for _ in 0..indent {
+            self.out.push_str(INDENT);
+        }
+        self.out.push_str(s);
+        self.out.push('\n');
+    }
+    // Whitespace before a position of the source code
+    fn newlines_before(&self, pos: usize) -> usize {
+        self.source[..pos].chars().rev()
+            .take_while(|c| c.is_whitespace())
+            .filter(|&c| c == '\n')
+            .count()
+    }
+    // The span of a definition starts after the keyword
+    fn def_start(&self, def: &CompDef) -> usize {
+        let before = self.source[..def.span.start].trim_end();
+        let keyword = if def.table.is_some() { "table" } else { "component" };
+        before.strip_suffix(keyword).map(|x| x.len()).unwrap_or(def.span.start)
+    }
+    // A list of ports with comments is printed with one port in each line.
+    // Returns the last line, which is not printed
+    fn header(&mut self, def: &CompDef) -> String {
+        let (input_comments, output_comments) = &def.port_comments;
+        if input_comments.is_empty() && output_comments.is_empty() {
+            return header(def);
+        }
+        let mut last = if input_comments.is_empty() {
+            format!("{}{}", def_name(def), input_ports(def))
+        } else {
+            self.port_lines(&format!("{}(", def_name(def)), &def.inputs, input_comments);
+            ")".to_string()
+        };
+        if output_comments.is_empty() {
+            last.push_str(&output_ports(def));
+        } else {
+            self.port_lines(&format!("{} -> (", last), &def.outputs, output_comments);
+            last = ")".to_string();
+        }
+
+        last
+    }
+    fn port_lines(&mut self, first: &str, ports: &[BitArray], comments: &[Comment]) {
+        let items = ports.iter().map(|p| (p.span.start, signal(&Signal::Bits(p.clone())))).collect();
+        self.item_lines(first, 0, items, comments);
+    }
+    // One item in each line, with the comments in source order. The items
+    // are (start in the source code, text)
+    fn item_lines(&mut self, first: &str, indent: usize, items: Vec<(usize, String)>, comments: &[Comment]) {
+        self.line(indent, first);
+        let mut lines: Vec<(usize, Result<String, &Comment>)> = items.into_iter()
+            .map(|(start, s)| (start, Ok(s)))
+            .chain(comments.iter().map(|c| (c.span.start, Err(c))))
+            .collect();
+        lines.sort_by_key(|(start, _)| *start);
+        for (i, (start, line)) in lines.into_iter().enumerate() {
+            if i > 0 && self.separate(start, line.as_ref().err().copied()) {
+                continue;
+            }
+            match line {
+                Ok(s) => self.line(indent + 1, &format!("{},", s)),
+                Err(c) => self.line(indent + 1, &c.text),
+            }
+        }
+    }
+    // Same as header, for the arguments of a call
+    fn call(&mut self, c: &CompCall, indent: usize) {
+        let (input_comments, output_comments) = &c.arg_comments;
+        if input_comments.is_empty() && output_comments.is_empty() {
+            self.line(indent, &format!("{};", call(c)));
+            return;
+        }
+        let mut last = if input_comments.is_empty() {
+            format!("{}({})", call_name(c), args(&c.inputs, &c.input_ports))
+        } else {
+            self.item_lines(&format!("{}(", call_name(c)), indent, arg_items(&c.inputs, &c.input_ports), input_comments);
+            ")".to_string()
+        };
+        if output_comments.is_empty() {
+            last.push_str(&call_outputs(c));
+        } else {
+            self.item_lines(&format!("{} -> (", last), indent, arg_items(&c.outputs, &c.output_ports), output_comments);
+            last = ")".to_string();
+        }
+        self.line(indent, &format!("{};", last));
+    }
+    fn component(&mut self, def: &CompDef) {
+        let header = self.header(def);
+        if def.body.is_empty() {
+            self.line(0, &format!("{} {{}}", header));
+            return;
+        }
+        self.line(0, &format!("{} {{", header));
+        self.body(&def.body, 1);
+        self.line(0, "}");
+    }
+    // Keep one blank line before a statement if there was any. A comment in
+    // the same line as the previous statement stays there, then it returns
+    // true because the comment is already printed
+    fn separate(&mut self, start: usize, comment: Option<&Comment>) -> bool {
+        let newlines = self.newlines_before(start);
+        if let (Some(c), 0) = (comment, newlines) {
+            self.out.pop();
+            self.out.push(' ');
+            self.out.push_str(&c.text);
+            self.out.push('\n');
+            return true;
+        }
+        if newlines > 1 {
+            self.out.push('\n');
+        }
+        false
+    }
+    fn body(&mut self, body: &[Statement], indent: usize) {
+        for (i, st) in body.iter().enumerate() {
+            let comment = match st {
+                Statement::Comment(c) => Some(c),
+                _ => None,
+            };
+            if i > 0 && self.separate(statement_start(st), comment) {
+                continue;
+            }
+            self.statement(st, indent);
+        }
+    }
+    // The rows and the comments are printed in source order
+    fn table(&mut self, def: &CompDef, table: &Table) {
+        let header = self.header(def);
+        if table.rows.is_empty() && def.body.is_empty() {
+            self.line(0, &format!("{} {{}}", header));
+            return;
+        }
+        self.line(0, &format!("{} {{", header));
+        let mut lines: Vec<(usize, Result<&TableRow, &Comment>)> = table.rows.iter()
+            .map(|r| (r.span.start, Ok(r)))
+            .chain(def.body.iter().filter_map(|st| match st {
+                Statement::Comment(c) => Some((c.span.start, Err(c))),
+                _ => None,
+            }))
+            .collect();
+        lines.sort_by_key(|(start, _)| *start);
+        for (i, (start, line)) in lines.into_iter().enumerate() {
+            if i > 0 && self.separate(start, line.err()) {
+                continue;
+            }
+            match line {
+                Ok(row) => self.line(1, &format!("{} -> {};", bits(&row.inputs), bits(&row.outputs))),
+                Err(c) => self.line(1, &c.text),
+            }
+        }
+        self.line(0, "}");
+    }
+    fn test(&mut self, test: &TestDef) {
+        let header = format!("test \"{}\" for {}", test.name, test.component);
+        if test.body.is_empty() {
+            self.line(0, &format!("{} {{}}", header));
+            return;
+        }
+        self.line(0, &format!("{} {{", header));
+        for (i, st) in test.body.iter().enumerate() {
+            let (start, comment) = match st {
+                TestStatement::Set(_, span) | TestStatement::Step(span) | TestStatement::Expect(_, span) => (span.start, None),
+                TestStatement::Comment(c) => (c.span.start, Some(c)),
+            };
+            if i > 0 && self.separate(start, comment) {
+                continue;
+            }
+            let values = |v: &[PortValue]| {
+                let v: Vec<_> = v.iter().map(|x| format!("{} = {}", signal(&Signal::Bits(x.port.clone())), x.value)).collect();
+                v.join(", ")
+            };
+            match st {
+                TestStatement::Set(v, _) => self.line(1, &format!("set {};", values(v))),
+                TestStatement::Step(_) => self.line(1, "step;"),
+                TestStatement::Expect(v, _) => self.line(1, &format!("expect {};", values(v))),
+                TestStatement::Comment(c) => self.line(1, &c.text),
+            }
+        }
+        self.line(0, "}");
+    }
+    fn statement(&mut self, st: &Statement, indent: usize) {
+        match st {
+            Statement::Call(c) => self.call(c, indent),
+            Statement::Assignment(a) => {
+                self.line(indent, &format!("{} = {};", side(&a.left), side(&a.right)));
+            }
+            Statement::Expression(a) => {
+                self.line(indent, &format!("{} = {};", side(&a.left), expr(&a.right, 0)));
+            }
+            Statement::Comment(c) => self.line(indent, &c.text),
+            Statement::For { var, start, end, body, .. } => {
+                self.line(indent, &format!("for {} in {}..{} {{", var, int_expr(start, 0), int_expr(end, 0)));
+                self.body(body, indent + 1);
+                self.line(indent, "}");
+            }
+            Statement::If { .. } => {
+                let mut st = st;
+                let mut first = true;
+                // else if chains are printed flat
+                while let Statement::If { cond: c, then, otherwise, .. } = st {
+                    if first {
+                        self.line(indent, &format!("if {} {{", cond(c)));
+                    } else {
+                        self.out.pop();
+                        self.out.push_str(&format!(" else if {} {{\n", cond(c)));
+                    }
+                    self.body(then, indent + 1);
+                    self.line(indent, "}");
+                    match otherwise.as_slice() {
+                        [] => break,
+                        [x @ Statement::If { .. }] => {
+                            st = x;
+                            first = false;
+                        }
+                        _ => {
+                            self.out.pop();
+                            self.out.push_str(" else {\n");
+                            self.body(otherwise, indent + 1);
+                            self.line(indent, "}");
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// First line of a definition, without the body:
+// component Add<N>(a[N-1:0], b[N-1:0]) -> (s[N-1:0], c)
+pub fn header(def: &CompDef) -> String {
+    format!("{}{}{}", def_name(def), input_ports(def), output_ports(def))
+}
+
+fn def_name(def: &CompDef) -> String {
+    if def.table.is_some() {
+        return format!("table {}", def.name);
+    }
+    let mut s = format!("component {}", def.name);
+    if !def.params.is_empty() {
+        s.push_str(&format!("<{}>", def.params.join(", ")));
+    }
+
+    s
+}
+
+fn ports(v: &[BitArray]) -> Vec<Signal> {
+    compact(v.iter().cloned().map(Signal::Bits).collect())
+}
+
+fn input_ports(def: &CompDef) -> String {
+    format!("({})", list(&ports(&def.inputs)))
+}
+
+// The outputs of a table are never empty
+fn output_ports(def: &CompDef) -> String {
+    let outputs = ports(&def.outputs);
+    match (&def.table, outputs.as_slice()) {
+        (Some(_), [x]) => format!(" -> {}", signal(x)),
+        (Some(_), v) => format!(" -> ({})", list(v)),
+        (None, v) => self::outputs(v),
+    }
+}
+
+fn statement_start(st: &Statement) -> usize {
+    match st {
+        Statement::Call(c) => c.span.start,
+        Statement::Assignment(a) => a.span.start,
+        Statement::Expression(a) => a.span.start,
+        Statement::Comment(c) => c.span.start,
+        Statement::For { span, .. } | Statement::If { span, .. } => span.start,
+    }
+}
+
+fn call(c: &CompCall) -> String {
+    format!("{}({}){}", call_name(c), args(&c.inputs, &c.input_ports), call_outputs(c))
+}
+
+// u_reg: Reg<8>
+fn call_name(c: &CompCall) -> String {
+    let mut s = match &c.label {
+        Some(label) => format!("{}: {}", label, c.name),
+        None => c.name.clone(),
+    };
+    if !c.params.is_empty() {
+        let params: Vec<_> = c.params.iter().map(|p| int_expr(p, 0)).collect();
+        s.push_str(&format!("<{}>", params.join(", ")));
+    }
+    s
+}
+
+fn call_outputs(c: &CompCall) -> String {
+    match &c.output_ports {
+        None => outputs(&compact(c.outputs.clone())),
+        Some(_) => format!(" -> ({})", args(&c.outputs, &c.output_ports)),
+    }
+}
+
+// Positional arguments are compacted, named arguments are not
+fn args(v: &[Signal], ports: &Option<Vec<String>>) -> String {
+    match ports {
+        None => list(&compact(v.to_vec())),
+        Some(ports) => {
+            let v: Vec<_> = ports.iter().zip(v).map(|(p, s)| format!("{}: {}", p, signal(s))).collect();
+            v.join(", ")
+        }
+    }
+}
+
+// The arguments of a call with comments, which are not compacted
+fn arg_items(v: &[Signal], ports: &Option<Vec<String>>) -> Vec<(usize, String)> {
+    v.iter().enumerate().map(|(i, s)| match ports {
+        None => (s.span().start, signal(s)),
+        Some(ports) => (s.span().start, format!("{}: {}", ports[i], signal(s))),
+    }).collect()
+}
+
+// One side of an assignment
+fn side(v: &[Signal]) -> String {
+    let v = compact(v.to_vec());
+    if v.len() == 1 { list(&v) } else { format!("({})", list(&v)) }
+}
+
+// Like int_expr, but with spaces around the binary operators
+fn expr(e: &Expr, parent: u8) -> String {
+    match e {
+        Expr::Signal(s) => signal(s),
+        Expr::Concat(v) => {
+            let v: Vec<_> = v.iter().map(|e| expr(e, 0)).collect();
+            format!("({})", v.join(", "))
+        }
+        Expr::Not(a) => format!("!{}", expr(a, 4)),
+        Expr::Binary(a, op, b) => {
+            let p = match op {
+                LogicOp::Or => 1,
+                LogicOp::Xor => 2,
+                LogicOp::And => 3,
+            };
+            let s = format!("{} {} {}", expr(a, p), op.symbol(), expr(b, p + 1));
+            if p < parent { format!("({})", s) } else { s }
+        }
+    }
+}
+
+// Row of a truth table, don't care is always written as x
+fn bits(v: &[Bit]) -> String {
+    v.iter().map(|b| match b {
+        Bit::L => '0',
+        Bit::H => '1',
+        Bit::X => 'x',
+    }).collect()
+}
+
+fn outputs(v: &[Signal]) -> String {
+    match v.len() {
+        0 => String::new(),
+        1 => format!(" -> {}", list(v)),
+        _ => format!(" -> ({})", list(v)),
+    }
+}
+
+fn list(v: &[Signal]) -> String {
+    let v: Vec<_> = v.iter().map(signal).collect();
+    v.join(", ")
+}
+
+fn signal(s: &Signal) -> String {
+    match s {
+        Signal::Constant(c) => c.text.clone(),
+        Signal::Bits(b) => {
+            let mut s = b.name.clone();
+            for r in &b.ranges {
+                match r {
+                    Range::Bit(a) => s.push_str(&format!("[{}]", int_expr(a, 0))),
+                    Range::Slice(a, b) if a == b => s.push_str(&format!("[{}]", int_expr(a, 0))),
+                    Range::Slice(a, b) => s.push_str(&format!("[{}:{}]", int_expr(a, 0), int_expr(b, 0))),
+                }
+            }
+            s
+        }
+    }
+}
+
+// Merge consecutive bits of the same array: (x[7], x[6], x[5]) becomes
+// x[7:5]. Only the last index is merged, and only if it is a number
+fn compact(v: Vec<Signal>) -> Vec<Signal> {
+    // Name, other ranges, first and last index of the last dimension
+    fn bit(s: &Signal) -> Option<(&str, &[Range], u64, u64)> {
+        let b = match s {
+            Signal::Bits(b) => b,
+            _ => return None,
+        };
+        let (last, prefix) = b.ranges.split_last()?;
+        match last.bounds() {
+            (IntExpr::Number(x), IntExpr::Number(y)) => Some((&b.name, prefix, *x, *y)),
+            _ => None,
+        }
+    }
+
+    let mut out: Vec<Signal> = vec![];
+    for s in v {
+        let merged = match (out.last().and_then(bit), bit(&s)) {
+            (Some((name, prefix, a, b)), Some((name2, prefix2, c, d)))
+                if name == name2 && prefix == prefix2 && c == d => {
+                // A single bit can start a run in any direction
+                let descending = a > b || (a == b && b.checked_sub(1) == Some(c));
+                let next = if descending { b.checked_sub(1) } else { b.checked_add(1) };
+                if next == Some(c) {
+                    Some((a, c))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        match (merged, out.last_mut()) {
+            (Some((a, c)), Some(Signal::Bits(prev))) => {
+                *prev.ranges.last_mut().unwrap() = Range::Slice(IntExpr::Number(a), IntExpr::Number(c));
+                prev.span.end = s.span().end;
+            }
+            _ => out.push(s),
+        }
+    }
+
+    out
+}
+
+// Precedence of the operators, used to avoid redundant parentheses
+fn precedence(op: BinOp) -> u8 {
+    match op {
+        BinOp::Add | BinOp::Sub => 1,
+        BinOp::Mul | BinOp::Div => 2,
+    }
+}
+
+fn int_expr(e: &IntExpr, parent: u8) -> String {
+    match e {
+        IntExpr::Number(n) => n.to_string(),
+        IntExpr::Var(v) => v.clone(),
+        IntExpr::BinOp(a, op, b) => {
+            let p = precedence(*op);
+            let op_str = match op {
+                BinOp::Add => "+",
+                BinOp::Sub => "-",
+                BinOp::Mul => "*",
+                BinOp::Div => "/",
+            };
+            // The operators are left associative: a-(b-c) needs the
+            // parentheses, (a-b)-c does not
+            let s = format!("{}{}{}", int_expr(a, p), op_str, int_expr(b, p + 1));
+            if p < parent { format!("({})", s) } else { s }
+        }
+    }
+}
+
+fn cond(c: &Cond) -> String {
+    match c {
+        Cond::Cmp(a, op, b) => {
+            let op = match op {
+                CmpOp::Eq => "==",
+                CmpOp::Ne => "!=",
+                CmpOp::Lt => "<",
+                CmpOp::Le => "<=",
+                CmpOp::Gt => ">",
+                CmpOp::Ge => ">=",
+            };
+            format!("{} {} {}", int_expr(a, 0), op, int_expr(b, 0))
+        }
+        Cond::And(a, b) => format!("{} && {}", cond(a), cond(b)),
+        Cond::Or(a, b) => format!("{} || {}", cond(a), cond(b)),
+    }
+}
+
+#[test]
+fn format_layout() {
+    let d = r#"// Header comment
+
+import "lib/alu.txt";
+import  "lib/mux-v2.txt"as mux;
+use alu::Add8;
+const  N=4;
+const MSB = (N-1)*1 ;
+
+/// Selects one of the inputs
+/// with s1 and s0
+component   Mux_4_1 (s1,s0, a,b ,c, d)->(y) {
+    Buf  (s1) -> d_s1;   // keep me
+    # python comment
+
+
+    Nand (n_s0, n_s1, d_a) -> sel00;
+  (x[3],x[2] , x[1], x[0]) = (a[3:3], 1, 4'b10, b[2*(N-1)-(1-2)]);
+    Reg<N/2+1>(a) -> (q[0], q[1], q[2]);
+    u_mux  :Mux(s: sel, a: x[1], b: x[0]) -> (y: out);
+    z=(a&!b)|c^(d|e) ; w = !(a|b)&( c , d&e);
+  for i in 0..N { if i==0{ Buf(a[i])->x[i]; } else { if i<N-1 && N > 2 || N == 1 { Nand()->(_); } else { } } }
+}
+component Empty() -> (x, y) { } // nothing
+/// Two bit decoder
+table Dec (/// the input
+a[1],a[0] // low bit
+) -> (y[3:0]) { 0 0 -> 0001; // zero
+  01 -> 0_010;
+
+  1- -> 1x00; }
+test "decoder" for Dec { set a[1:0]=2'b01 , a[0] = 'x; # partial
+step ; expect y= 4'h2;}
+"#;
+    let expected = r#"// Header comment
+
+import "lib/alu.txt";
+import "lib/mux-v2.txt" as mux;
+
+use alu::Add8;
+
+const N = 4;
+const MSB = (N-1)*1;
+
+/// Selects one of the inputs
+/// with s1 and s0
+component Mux_4_1(s1, s0, a, b, c, d) -> y {
+    Buf(s1) -> d_s1; // keep me
+    # python comment
+
+    Nand(n_s0, n_s1, d_a) -> sel00;
+    x[3:0] = (a[3], 1, 4'b10, b[2*(N-1)-(1-2)]);
+    Reg<N/2+1>(a) -> q[0:2];
+    u_mux: Mux(s: sel, a: x[1], b: x[0]) -> (y: out);
+    z = a & !b | c ^ (d | e);
+    w = !(a | b) & (c, d & e);
+    for i in 0..N {
+        if i == 0 {
+            Buf(a[i]) -> x[i];
+        } else if i < N-1 && N > 2 || N == 1 {
+            Nand() -> _;
+        }
+    }
+}
+
+component Empty() -> (x, y) {} // nothing
+
+/// Two bit decoder
+table Dec(
+    /// the input
+    a[1],
+    a[0], // low bit
+) -> y[3:0] {
+    00 -> 0001; // zero
+    01 -> 0010;
+
+    1x -> 1x00;
+}
+
+test "decoder" for Dec {
+    set a[1:0] = 2'b01, a[0] = 'x; # partial
+    step;
+    expect y = 4'h2;
+}
+"#;
+    let f = format_str(d).unwrap();
+    assert_eq!(f, expected);
+    // Formatting is idempotent
+    assert_eq!(format_str(&f).unwrap(), f);
+    assert!(check(&f).unwrap());
+    assert!(!check(d).unwrap());
+}
+
+#[test]
+fn format_call_comments() {
+    let d = r#"component A(a, b) -> (y, z) {
+    Nand(a, // first
+      a) -> y;
+    u_mux: Mux(s: a, /* kept */
+        # select
+        a: b) -> (
+        // the output
+        y: z);
+}
+"#;
+    let expected = r#"component A(a, b) -> (y, z) {
+    Nand(
+        a, // first
+        a,
+    ) -> y;
+    u_mux: Mux(
+        s: a, /* kept */
+        # select
+        a: b,
+    ) -> (
+        // the output
+        y: z,
+    );
+}
+"#;
+    let f = format_str(d).unwrap();
+    assert_eq!(f, expected);
+    assert_eq!(format_str(&f).unwrap(), f);
+
+    // The comments are not arguments
+    use crate::bit::Bit::*;
+    let d = "component Not(a) -> y { Nand(a, // first\n a) -> (\n# out\ny); }";
+    let mut c = crate::parser::parse_str(d).unwrap().create_named("Not").unwrap();
+    assert_eq!(c.update(&[H]), vec![L]);
+}
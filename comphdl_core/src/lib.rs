@@ -8,12 +8,24 @@ extern crate lalrpop_util;
 #[macro_use]
 extern crate log;
 
+pub mod ast;
 pub mod emit_json;
 pub mod wave_json;
 pub mod parser;
+pub mod diagnostic;
+pub mod fmt;
+pub mod lint;
+pub mod project;
+pub mod suggest;
+pub mod table;
+pub mod testbench;
 pub mod bit;
 pub mod component;
 pub mod simulation;
+pub mod event;
+pub mod flatten;
+pub mod compiled;
+pub mod parallel;
 lalrpop_mod!{
     #[allow(clippy::all)]
     pub comphdl1
@@ -0,0 +1,376 @@
+// Lint pass: warnings about signals which are probably a mistake, but do
+// not prevent the component from being simulated.
+//
+// The level of each lint can be changed with LintConfig, and lints can be
+// disabled inside one component with a comment:
+// component Foo(a) -> x {
+//     // comphdl: allow(unused, undriven)
+// }
+use crate::ast::{CompDef, Statement};
+use crate::diagnostic::{Diagnostic, DiagnosticKind, Severity, Span};
+use crate::parser::{CompInfo, Elaborated};
+use crate::suggest;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Lint {
+    // Signals which are read, but nothing drives them
+    Undriven,
+    // Signals and inputs which are driven, but never read
+    Unused,
+    // Outputs of the component which are not driven
+    UnconnectedOutput,
+    // Reading _ in a component which also routes outputs to _
+    DiscardedRead,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 4] = [Lint::Undriven, Lint::Unused, Lint::UnconnectedOutput, Lint::DiscardedRead];
+
+    // Name used in the allow comments
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::Undriven => "undriven",
+            Lint::Unused => "unused",
+            Lint::UnconnectedOutput => "unconnected_output",
+            Lint::DiscardedRead => "discarded_read",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::ALL.iter().cloned().find(|l| l.name() == name)
+    }
+    // The lint which reported a diagnostic
+    pub fn of(kind: &DiagnosticKind) -> Option<Lint> {
+        match kind {
+            DiagnosticKind::UndrivenSignal { .. } => Some(Lint::Undriven),
+            DiagnosticKind::UnusedSignal { .. } => Some(Lint::Unused),
+            DiagnosticKind::UnconnectedOutput { .. } => Some(Lint::UnconnectedOutput),
+            DiagnosticKind::DiscardedRead => Some(Lint::DiscardedRead),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Level {
+    Allow,
+    Warn,
+    // Report as an error
+    Deny,
+}
+
+// All the lints are warnings by default
+#[derive(Debug, Clone, Default)]
+pub struct LintConfig {
+    levels: HashMap<Lint, Level>,
+}
+
+impl LintConfig {
+    pub fn with(mut self, lint: Lint, level: Level) -> Self {
+        self.levels.insert(lint, level);
+        self
+    }
+    pub fn level(&self, lint: Lint) -> Level {
+        self.levels.get(&lint).cloned().unwrap_or(Level::Warn)
+    }
+    // Filter the diagnostics of the lint pass, and turn the denied ones
+    // into errors
+    pub fn apply(&self, lints: &[Diagnostic]) -> Vec<Diagnostic> {
+        lints.iter().filter_map(|d| {
+            let level = Lint::of(&d.kind).map(|l| self.level(l)).unwrap_or(Level::Warn);
+            match level {
+                Level::Allow => None,
+                Level::Warn => Some(d.clone()),
+                Level::Deny => Some(Diagnostic { severity: Severity::Error, ..d.clone() }),
+            }
+        }).collect()
+    }
+}
+
+// Run all the lints on the elaborated components. The definitions are
+// only used to find the allow comments
+pub(crate) fn check(defs: &[CompDef], all: &[(CompInfo, Vec<Elaborated>)]) -> Vec<Diagnostic> {
+    let mut warnings = vec![];
+    let mut allowed = HashMap::new();
+    for def in defs {
+        let mut lints = HashSet::new();
+        allow_comments(&def.name, &def.body, &mut lints, &mut warnings);
+        allowed.insert(def.name.as_str(), lints);
+    }
+
+    // Truth tables have no signals to check
+    let tables: HashSet<&str> = defs.iter().filter(|d| d.table.is_some()).map(|d| d.name.as_str()).collect();
+    for (c_zero, body) in all {
+        if tables.contains(c_zero.name.as_str()) {
+            continue;
+        }
+        // Reg<8> uses the comments of Reg
+        let template = c_zero.name.split('<').next().unwrap();
+        let allowed = allowed.get(template);
+        warnings.extend(component(c_zero, body).into_iter().filter(|d| {
+            match (Lint::of(&d.kind), allowed) {
+                (Some(l), Some(allowed)) => !allowed.contains(&l),
+                _ => true,
+            }
+        }));
+    }
+
+    warnings
+}
+
+fn allow_comments(name: &str, body: &[Statement], lints: &mut HashSet<Lint>, warnings: &mut Vec<Diagnostic>) {
+    for st in body {
+        match st {
+            Statement::Comment(c) => {
+                let text = c.text.trim_start_matches(&['/', '*', '#'][..]);
+                let text = text.trim_end_matches(&['/', '*'][..]).trim();
+                let list = text.strip_prefix("comphdl:")
+                    .and_then(|x| x.trim().strip_prefix("allow("))
+                    .and_then(|x| x.strip_suffix(')'));
+                for l in list.iter().flat_map(|x| x.split(',')).map(|x| x.trim()) {
+                    match Lint::from_name(l) {
+                        Some(l) => {
+                            lints.insert(l);
+                        }
+                        None => warnings.push(Diagnostic::warning(DiagnosticKind::UnknownLint {
+                            name: l.to_string()
+                        }).in_component(name).with_span(Some(c.span))),
+                    }
+                }
+            }
+            Statement::For { body, .. } => allow_comments(name, body, lints, warnings),
+            Statement::If { then, otherwise, .. } => {
+                allow_comments(name, then, lints, warnings);
+                allow_comments(name, otherwise, lints, warnings);
+            }
+            Statement::Call(_) | Statement::Assignment(_) | Statement::Expression(_) => {}
+        }
+    }
+}
+
+// Signals connected by assignments
+struct Groups<'a> {
+    parent: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> Groups<'a> {
+    fn find(&self, mut x: &'a str) -> &'a str {
+        while let Some(&p) = self.parent.get(x) {
+            x = p;
+        }
+        x
+    }
+    fn union(&mut self, a: &'a str, b: &'a str) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parent.insert(a, b);
+        }
+    }
+}
+
+fn is_underscore(bit: &str) -> bool {
+    bit.split('$').next() == Some("_")
+}
+
+// _ and the constants '0, '1, 'x are never reported
+fn ignored(bit: &str) -> bool {
+    bit.starts_with('\'') || is_underscore(bit)
+}
+
+// Bits with a warning, grouped by lint and array name
+type Findings<'a> = BTreeMap<(Lint, &'a str), Vec<(&'a str, Option<Span>)>>;
+
+fn component(c_zero: &CompInfo, body: &[Elaborated]) -> Vec<Diagnostic> {
+    let mut warnings = vec![];
+    let mut groups = Groups { parent: HashMap::new() };
+    // Span of the first statement which drives or reads each signal
+    let mut drivers: HashMap<&str, Option<Span>> = HashMap::new();
+    let mut readers: HashMap<&str, Option<Span>> = HashMap::new();
+    let mut all_bits: BTreeMap<&str, ()> = BTreeMap::new();
+    // Inside the component the inputs are drivers and the outputs readers,
+    // at their declaration
+    let port_span = |x: &str| c_zero.port_span(x).or(c_zero.span);
+    for x in &c_zero.inputs {
+        drivers.entry(x).or_insert(port_span(x));
+    }
+    for x in &c_zero.outputs {
+        readers.entry(x).or_insert(port_span(x));
+    }
+    let mut writes_underscore = false;
+    let mut reads_underscore = vec![];
+    for st in body {
+        match st {
+            Elaborated::Call(c) => {
+                for x in &c.inputs {
+                    readers.entry(x).or_insert(c.span);
+                }
+                for x in &c.outputs {
+                    drivers.entry(x).or_insert(c.span);
+                }
+                writes_underscore |= c.outputs.iter().any(|x| is_underscore(x));
+                if c.inputs.iter().any(|x| is_underscore(x)) {
+                    reads_underscore.push(c.span);
+                }
+            }
+            Elaborated::Assignment { left, right, span } => {
+                for (a, b) in left.iter().zip(right) {
+                    // Assignments to _ are ignored
+                    if !is_underscore(a) && !is_underscore(b) {
+                        groups.union(a, b);
+                    }
+                }
+                if right.iter().any(|x| is_underscore(x)) {
+                    reads_underscore.push(*span);
+                }
+            }
+        }
+        let (inputs, outputs) = match st {
+            Elaborated::Call(c) => (&c.inputs, &c.outputs),
+            Elaborated::Assignment { left, right, .. } => (right, left),
+        };
+        for x in inputs.iter().chain(outputs) {
+            all_bits.insert(x, ());
+        }
+    }
+    for x in c_zero.inputs.iter().chain(&c_zero.outputs) {
+        all_bits.insert(x, ());
+    }
+
+    let mut driven = HashSet::new();
+    let mut read = HashSet::new();
+    for &x in drivers.keys() {
+        driven.insert(groups.find(x));
+    }
+    for &x in readers.keys() {
+        read.insert(groups.find(x));
+    }
+
+    // Group the findings by array, so a[7:0] is only reported once
+    let mut found: Findings = BTreeMap::new();
+    let outputs: HashSet<&str> = c_zero.outputs.iter().map(|x| x.as_str()).collect();
+    for &bit in all_bits.keys() {
+        if ignored(bit) {
+            continue;
+        }
+        let g = groups.find(bit);
+        let base = bit.split('$').next().unwrap();
+        if outputs.contains(bit) && !driven.contains(g) {
+            found.entry((Lint::UnconnectedOutput, base)).or_default().push((bit, port_span(bit)));
+        } else if let (Some(&span), false) = (readers.get(bit), driven.contains(g)) {
+            found.entry((Lint::Undriven, base)).or_default().push((bit, span));
+        } else if let (Some(&span), false) = (drivers.get(bit), read.contains(g)) {
+            found.entry((Lint::Unused, base)).or_default().push((bit, span));
+        }
+    }
+    for ((lint, base), bits) in found {
+        let total = all_bits.keys().filter(|x| x.split('$').next() == Some(base)).count();
+        let signal = if bits.len() == total {
+            base.to_string()
+        } else {
+            let v: Vec<_> = bits.iter().map(|(x, _)| pretty(x)).collect();
+            v.join(", ")
+        };
+        // A read of an undriven signal is often a typo of a driven one
+        let mut help = None;
+        if lint == Lint::Undriven {
+            let mut names: Vec<&str> = drivers.keys().filter(|x| !ignored(x))
+                .map(|x| x.split('$').next().unwrap()).collect();
+            names.sort();
+            names.dedup();
+            help = suggest::did_you_mean(base, names).map(|x| suggest::help(&x));
+        }
+        let kind = match lint {
+            Lint::UnconnectedOutput => DiagnosticKind::UnconnectedOutput { port: signal },
+            Lint::Undriven => DiagnosticKind::UndrivenSignal { signal },
+            _ => DiagnosticKind::UnusedSignal { signal },
+        };
+        warnings.push(Diagnostic::warning(kind).in_component(&c_zero.name).with_span(bits[0].1).with_help(help));
+    }
+
+    if writes_underscore {
+        for span in reads_underscore {
+            warnings.push(Diagnostic::warning(DiagnosticKind::DiscardedRead)
+                .in_component(&c_zero.name).with_span(span));
+        }
+    }
+
+    warnings
+}
+
+// a$3$1 is written as a[3][1]
+fn pretty(bit: &str) -> String {
+    let mut parts = bit.split('$');
+    let mut s = parts.next().unwrap().to_string();
+    for i in parts {
+        s.push_str(&format!("[{}]", i));
+    }
+    s
+}
+
+#[test]
+fn lints() {
+    use crate::parser::parse_str;
+    let d = r#"
+component A(a, b[1:0], c) -> (x, y[1:0]) {
+    Nand(a, u) -> x;
+    Nand(b[1]) -> y[1];
+    Nand(a) -> _;
+    Nand(_) -> t;
+    Nand(t) -> v;
+}
+component B(a) -> x {
+    // comphdl: allow(unused, unconnected_output, bogus)
+}
+component C<N>(a) -> x {
+    # comphdl: allow(unused)
+    x = 1;
+}
+component D(a) -> x {
+    C<2>(a) -> x;
+}
+    "#;
+    let cf = parse_str(d).unwrap();
+    let w: Vec<_> = cf.warnings().iter().map(|d| (d.code(), d.kind.to_string())).collect();
+    let expected = vec![
+        ("W0005", "unknown lint `bogus`"),
+        ("W0001", "signal `u` is read but nothing drives it"),
+        ("W0002", "signal `b[0]` is driven but never read"),
+        ("W0002", "signal `c` is driven but never read"),
+        ("W0002", "signal `v` is driven but never read"),
+        ("W0003", "output `y[0]` is not connected"),
+        ("W0004", "`_` is read here, but it is always unconnected, even if an output is routed to it"),
+    ];
+    let expected: Vec<_> = expected.into_iter().map(|(a, b)| (a, b.to_string())).collect();
+    assert_eq!(w, expected);
+    assert_eq!(cf.warnings()[1].location.unwrap().line.0, 3);
+
+    // Ports are reported where they are declared
+    let d = r#"
+component Add(
+    a[3:0],
+    b[3:0]
+) -> (
+    s[1:0],
+    co
+) {
+    Nand(a[2:0], b[3:0]) -> s[0];
+}
+    "#;
+    let add = parse_str(d).unwrap();
+    let w: Vec<_> = add.warnings().iter().map(|d| {
+        let l = d.location.unwrap();
+        (d.code(), d.kind.to_string(), l.line.0, l.column.0)
+    }).collect();
+    assert_eq!(w, vec![
+        ("W0002", "signal `a[3]` is driven but never read".to_string(), 3, 5),
+        ("W0003", "output `co` is not connected".to_string(), 7, 5),
+        ("W0003", "output `s[1]` is not connected".to_string(), 6, 5),
+    ]);
+
+    let config = LintConfig::default()
+        .with(Lint::Unused, Level::Allow)
+        .with(Lint::Undriven, Level::Deny);
+    let w = cf.lints(&config);
+    assert_eq!(w.len(), 4);
+    assert!(w[1].is_error());
+}
@@ -0,0 +1,280 @@
+// Bit-parallel simulation: up to 64 independent input vectors are simulated
+// at once, one in each bit (lane) of a u64. It runs the same program as
+// Compiled, with a Word instead of a Bit for each net, so a Nand gate is
+// evaluated for all the lanes with a few bitwise operations.
+//
+// This is meant for exhaustive checks and truth tables: a 16 input component
+// needs 1024 updates instead of 65536.
+use crate::bit::{Bit, InfiniteInputIterator};
+use crate::compiled::{Compiled, Op};
+use crate::component::{Component, Structural};
+
+pub const LANES: usize = 64;
+
+// Updates allowed for a group of lanes to settle in truth_table
+const MAX_UPDATES: usize = 1000;
+
+// One Bit for each lane, in two planes: `one` is set in the lanes which may
+// be 1 and `zero` in the lanes which may be 0, so X sets both
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Word {
+    one: u64,
+    zero: u64,
+}
+
+impl Word {
+    pub const X: Word = Word { one: !0, zero: !0 };
+
+    pub fn splat(b: Bit) -> Word {
+        match b {
+            Bit::L => Word { one: 0, zero: !0 },
+            Bit::H => Word { one: !0, zero: 0 },
+            Bit::X => Word::X,
+        }
+    }
+    pub fn from_lanes(bits: &[Bit]) -> Word {
+        assert!(bits.len() <= LANES);
+        let mut w = Word::X;
+        for (lane, &b) in bits.iter().enumerate() {
+            w.set(lane, b);
+        }
+        w
+    }
+    pub fn get(self, lane: usize) -> Bit {
+        match (self.one >> lane & 1, self.zero >> lane & 1) {
+            (1, 0) => Bit::H,
+            (0, 1) => Bit::L,
+            _ => Bit::X,
+        }
+    }
+    pub fn set(&mut self, lane: usize, b: Bit) {
+        let mask = 1 << lane;
+        let (one, zero) = match b {
+            Bit::L => (0, mask),
+            Bit::H => (mask, 0),
+            Bit::X => (mask, mask),
+        };
+        self.one = self.one & !mask | one;
+        self.zero = self.zero & !mask | zero;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Parallel {
+    compiled: Compiled,
+    values: Vec<Word>,
+    // The primitives other than Nand have one copy for each lane, because
+    // they may have state
+    cells: Vec<Vec<Box<dyn Component>>>,
+    unstable: bool,
+    buf: Vec<Bit>,
+}
+
+impl Parallel {
+    pub fn new(compiled: Compiled) -> Self {
+        let mut cells = vec![vec![]; compiled.flat.components.len()];
+        for op in &compiled.program {
+            if let Op::Call { cell, .. } = *op {
+                cells[cell] = vec![compiled.flat.components[cell].comp.clone(); LANES];
+            }
+        }
+        let values = vec![Word::X; compiled.feedback.len()];
+
+        Self { compiled, values, cells, unstable: true, buf: vec![] }
+    }
+    pub fn num_inputs(&self) -> usize {
+        self.compiled.num_inputs()
+    }
+    pub fn num_outputs(&self) -> usize {
+        self.compiled.num_outputs()
+    }
+    pub fn needs_update(&self) -> bool {
+        self.unstable
+    }
+    // Set every net back to X, the state of the primitives is kept
+    pub fn reset(&mut self) {
+        for w in &mut self.values {
+            *w = Word::X;
+        }
+        self.unstable = true;
+    }
+    // Same as Component::update for each lane, there must be one input
+    // Word for each input of the component
+    pub fn update_words(&mut self, input: &[Word]) -> Vec<Word> {
+        assert_eq!(input.len(), self.num_inputs());
+        if self.unstable || self.values[1..=input.len()] != *input {
+            self.values[1..=input.len()].copy_from_slice(input);
+            let mut passes = 0;
+            self.unstable = true;
+            while self.unstable && passes < self.compiled.passes {
+                self.unstable = self.run();
+                passes += 1;
+            }
+        }
+
+        self.compiled.outputs.iter().map(|&net| self.values[net]).collect()
+    }
+    // Update with one input vector for each lane, up to 64, returns the
+    // outputs of each lane. The unused lanes are X
+    pub fn update(&mut self, inputs: &[Vec<Bit>]) -> Vec<Vec<Bit>> {
+        assert!(inputs.len() <= LANES);
+        let words: Vec<_> = (0..self.num_inputs()).map(|i| {
+            let bits: Vec<_> = inputs.iter().map(|v| v[i]).collect();
+            Word::from_lanes(&bits)
+        }).collect();
+        let output = self.update_words(&words);
+
+        (0..inputs.len()).map(|lane| output.iter().map(|w| w.get(lane)).collect()).collect()
+    }
+    // The outputs for every combination of the inputs, in the order of
+    // InfiniteInputIterator. Each group of 64 starts with all the nets X,
+    // and is updated until it settles
+    pub fn truth_table(&mut self) -> Vec<Vec<Bit>> {
+        let n = self.num_inputs();
+        assert!(n < 32, "too many inputs for a truth table: {}", n);
+        let mut combinations = InfiniteInputIterator::new(n).take(1 << n);
+        let mut table = Vec::with_capacity(1 << n);
+        loop {
+            let inputs: Vec<_> = combinations.by_ref().take(LANES).collect();
+            if inputs.is_empty() {
+                break;
+            }
+            self.reset();
+            let mut output = self.update(&inputs);
+            for _ in 1..MAX_UPDATES {
+                if !self.needs_update() {
+                    break;
+                }
+                output = self.update(&inputs);
+            }
+            table.extend(output);
+        }
+
+        table
+    }
+    // Run the program once, returns true if a feedback net changed
+    fn run(&mut self) -> bool {
+        let mut changed = false;
+        for k in 0..self.compiled.program.len() {
+            match self.compiled.program[k] {
+                Op::Nand { out, args: (start, end) } => {
+                    // The output may be 1 if any input may be 0, and it may
+                    // be 0 if all the inputs may be 1
+                    let mut w = Word { one: 0, zero: !0 };
+                    for &a in &self.compiled.args[start..end] {
+                        w.one |= self.values[a].zero;
+                        w.zero &= self.values[a].one;
+                    }
+                    changed |= self.set(out, w);
+                }
+                Op::Call { cell, outs, args: (start, end) } => {
+                    let mut output = vec![Word::X; self.cells[cell][0].num_outputs()];
+                    for lane in 0..LANES {
+                        self.buf.clear();
+                        for &a in &self.compiled.args[start..end] {
+                            self.buf.push(self.values[a].get(lane));
+                        }
+                        let comp = &mut self.cells[cell][lane];
+                        for (w, b) in output.iter_mut().zip(comp.update(&self.buf)) {
+                            w.set(lane, b);
+                        }
+                        changed |= comp.needs_update();
+                    }
+                    for (o, w) in output.into_iter().enumerate() {
+                        changed |= self.set(outs + o, w);
+                    }
+                }
+            }
+        }
+
+        changed
+    }
+    fn set(&mut self, net: usize, w: Word) -> bool {
+        if self.values[net] == w {
+            return false;
+        }
+        self.values[net] = w;
+
+        self.compiled.feedback[net]
+    }
+}
+
+impl Compiled {
+    pub fn parallel(&self) -> Parallel {
+        Parallel::new(self.clone())
+    }
+}
+
+impl Structural {
+    pub fn truth_table(&self) -> Vec<Vec<Bit>> {
+        self.compile().with_passes(LANES).parallel().truth_table()
+    }
+}
+
+#[test]
+fn parallel_lanes() {
+    use crate::bit::Bit::*;
+    use crate::parser::parse_str;
+    // Same as Nand::update
+    for &a in &[L, H, X] {
+        for &b in &[L, H, X] {
+            let expected = crate::component::Nand::new(2).update(&[a, b]);
+            let mut w = Word { one: 0, zero: !0 };
+            for x in &[Word::splat(a), Word::splat(b)] {
+                w.one |= x.zero;
+                w.zero &= x.one;
+            }
+            assert_eq!(vec![w.get(5)], expected);
+        }
+    }
+
+    let d = r#"
+table Xor(a, b) -> y { 00 -> 0; 01 -> 1; 10 -> 1; 11 -> 0; }
+component Full(a, b, c) -> (s, co) {
+    Xor(a, b) -> t;
+    Xor(t, c) -> s;
+    co = (a & b) | (t & c);
+}
+component Add4(a[3:0], b[3:0]) -> (s[3:0], co) {
+    Full(a[0], b[0], 0) -> (s[0], c0);
+    Full(a[1], b[1], c0) -> (s[1], c1);
+    Full(a[2], b[2], c1) -> (s[2], c2);
+    Full(a[3], b[3], c2) -> (s[3], co);
+}
+component SrLatch(n_s, n_r) -> (q, n_q) {
+    Nand(n_s, n_q) -> q;
+    Nand(n_r, q) -> n_q;
+}
+"#;
+    let cf = parse_str(d).unwrap();
+    let add4 = cf.create_named("Add4").unwrap().clone_as_structural().unwrap();
+
+    // 256 combinations, in 4 groups of 64 lanes
+    let table = add4.truth_table();
+    assert_eq!(table.len(), 256);
+    let mut c = add4.compile();
+    for (input, output) in InfiniteInputIterator::new(8).zip(&table) {
+        assert_eq!(&c.update(&input), output);
+        let a = Bit::bit8_into_u8(&[&[L; 4][..], &input[..4]].concat());
+        let b = Bit::bit8_into_u8(&[&[L; 4][..], &input[4..]].concat());
+        assert_eq!(Bit::bit8_into_u8(&[&[L; 3][..], &output[4..], &output[..4]].concat()), a + b);
+    }
+
+    // The lanes are independent, X stays in its own lane
+    let mut p = add4.compile().parallel();
+    let inputs = vec![vec![L, L, L, H, L, L, L, H], vec![L, L, L, X, L, L, L, L], vec![H; 8]];
+    let out = p.update(&inputs);
+    assert_eq!(out, vec![
+        vec![L, L, H, L, L],
+        vec![L, L, L, X, L],
+        vec![H, H, H, L, H],
+    ]);
+
+    // A latch keeps its state in each lane
+    let latch = cf.create_named("SrLatch").unwrap().clone_as_structural().unwrap();
+    let mut p = latch.compile().with_passes(4).parallel();
+    assert_eq!(p.update(&[vec![L, H], vec![H, L], vec![H, H]]), vec![vec![H, L], vec![L, H], vec![X, X]]);
+    assert_eq!(p.update(&[vec![H, H], vec![H, H], vec![H, H]]), vec![vec![H, L], vec![L, H], vec![X, X]]);
+    assert!(!p.needs_update());
+    assert_eq!(latch.truth_table(), vec![vec![H, H], vec![H, L], vec![L, H], vec![X, X]]);
+}
@@ -1,50 +1,172 @@
+use crate::bit::Bit;
 use crate::component::{ComponentIndex, Index, Component, CompIo, Structural, Nand, ConstantBit, Stdin, RcBufRead, Stdout, RcWrite};
 use crate::comphdl1;
-use std::collections::HashMap;
+use crate::ast::{constant_signal, expand_bits, instance_name, Assignment, BitArray, CompCall, CompDef, Env, Expr, ExprAssignment, File, LogicOp, Signal, Statement, Table};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::io::{BufRead, Cursor, Write};
 use std::fmt;
+use std::path::PathBuf;
+use crate::project;
+use crate::suggest;
+use crate::table::TruthTable;
+use crate::testbench::TestSuite;
+use crate::lint::{self, LintConfig};
+use crate::diagnostic::{CompileError, Diagnostic, DiagnosticKind, Lines, Span};
+use lalrpop_util::{ErrorRecovery, ParseError};
+
+// Named connections of a call, Mux(s: sel[1:0], a: x), with the bits of
+// each argument in the order they were written
+pub type NamedPorts = Vec<(String, Vec<String>)>;
 
 #[derive(Debug, Clone)]
 pub struct CompInfo {
     pub name: String,
     pub inputs: Vec<String>,
     pub outputs: Vec<String>,
+    pub span: Option<Span>,
+    // Only used in calls, resolved into inputs and outputs by
+    // CompDefinition::new once the callee is known
+    pub named_inputs: Option<NamedPorts>,
+    pub named_outputs: Option<NamedPorts>,
+    // Doc comments of the definition and of its ports, by port name
+    pub doc: Option<String>,
+    pub port_docs: Vec<(String, String)>,
+    // Where each port is declared, by port name
+    pub port_spans: Vec<(String, Span)>,
+    // Only used in calls: the instance name, u_alu: Add8(a, b) -> s;
+    pub label: Option<String>,
 }
 
 impl CompInfo {
     pub fn new(name: String, inputs: Vec<String>, outputs: Vec<String>) -> Self {
         CompInfo {
-            name, inputs, outputs,
+            name, inputs, outputs, span: None, named_inputs: None, named_outputs: None,
+            doc: None, port_docs: vec![], port_spans: vec![], label: None,
         }
     }
-    pub fn verify(&mut self) {
-        let mut repetitions = HashMap::new();
-        for s in self.inputs.iter() {
-            if let Some(_) = repetitions.get(s) {
-                let e = format!("Input names must be unique, {}.{} isn't", self.name, s);
-                error!("{}", e);
-                panic!("{}", e);
+    pub fn with_doc(mut self, doc: Option<String>, port_docs: Vec<(String, String)>) -> Self {
+        self.doc = doc;
+        self.port_docs = port_docs;
+        self
+    }
+    // The doc comment of a port, the bits of an array share it: a$3 is a
+    pub fn port_doc(&self, port: &str) -> Option<&str> {
+        let base = port.split('$').next().unwrap();
+        self.port_docs.iter().find(|(p, _)| p == base).map(|(_, doc)| doc.as_str())
+    }
+    pub fn with_port_spans(mut self, port_spans: Vec<(String, Span)>) -> Self {
+        self.port_spans = port_spans;
+        self
+    }
+    // The declaration of a port, the bits of an array share it
+    pub fn port_span(&self, port: &str) -> Option<Span> {
+        let base = port.split('$').next().unwrap();
+        self.port_spans.iter().find(|(p, _)| p == base).map(|(_, span)| *span)
+    }
+    pub fn with_label(mut self, label: Option<String>) -> Self {
+        self.label = label;
+        self
+    }
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+    pub fn with_ports(mut self, inputs: Option<NamedPorts>, outputs: Option<NamedPorts>) -> Self {
+        self.named_inputs = inputs;
+        self.named_outputs = outputs;
+        self
+    }
+    // Reorder the named connections of a call to match the ports of the
+    // callee
+    fn resolve_ports(&self, callee: &CompInfo) -> Result<CompInfo, Vec<Diagnostic>> {
+        let mut errors = vec![];
+        let mut resolve = |named: &Option<NamedPorts>, positional: &[String], ports: &[String]| {
+            let named = match named {
+                Some(x) => x,
+                None => return positional.to_vec(),
+            };
+            // The bits of an array port are grouped: D$7..D$0 is port D
+            let mut groups: Vec<(&str, usize)> = vec![];
+            for p in ports {
+                let base = p.split('$').next().unwrap();
+                match groups.last_mut() {
+                    Some((name, n)) if *name == base => *n += 1,
+                    _ => groups.push((base, 1)),
+                }
+            }
+            let mut connected: HashMap<&str, &[String]> = HashMap::new();
+            for (port, bits) in named {
+                if !groups.iter().any(|(name, _)| name == port) {
+                    let similar = suggest::did_you_mean(port, groups.iter().map(|(name, _)| *name));
+                    errors.push(self.error(DiagnosticKind::UnknownPort {
+                        callee: callee.name.clone(), port: port.clone()
+                    }).with_help(similar.map(|x| suggest::help(&x))));
+                } else if connected.insert(port, bits).is_some() {
+                    errors.push(self.error(DiagnosticKind::DuplicatePortConnection { port: port.clone() }));
+                }
             }
-            repetitions.insert(s, ());
+            let mut v = vec![];
+            for (port, width) in groups {
+                match connected.get(port) {
+                    None => errors.push(self.error(DiagnosticKind::MissingPort {
+                        callee: callee.name.clone(), port: port.to_string()
+                    })),
+                    Some(bits) if bits.len() != width => errors.push(self.error(DiagnosticKind::PortWidthMismatch {
+                        callee: callee.name.clone(),
+                        port: port.to_string(),
+                        expected: width,
+                        found: bits.len(),
+                    })),
+                    Some(bits) => v.extend_from_slice(bits),
+                }
+            }
+            v
+        };
+        let inputs = resolve(&self.named_inputs, &self.inputs, &callee.inputs);
+        let outputs = resolve(&self.named_outputs, &self.outputs, &callee.outputs);
+
+        if !errors.is_empty() {
+            return Err(errors);
         }
 
+        Ok(CompInfo { inputs, outputs, named_inputs: None, named_outputs: None, ..self.clone() })
+    }
+    pub fn verify(&self) -> Result<(), Vec<Diagnostic>> {
+        let mut errors = vec![];
+        let mut repetitions = HashMap::new();
         // Output names must also be unique, example: 1-to-4
         // quad(a) -> (a, a, a, a)
         // The alternative is
         // quad(a) -> (a0, a1, a2, a3) { a0 = a; a1 = a; a2 = a; a3 = a; }
-        for s in self.outputs.iter() {
-            if let Some(_) = repetitions.get(s) {
-                let e = format!("Output names must be unique, '{}.{}' isn't", self.name, s);
-                error!("{}", e);
-                panic!("{}", e);
+        for s in self.inputs.iter().chain(self.outputs.iter()) {
+            if repetitions.insert(s, ()).is_some() {
+                errors.push(self.error(DiagnosticKind::DuplicatePort { port: s.clone() }));
             }
-            repetitions.insert(s, ());
         }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+    fn error(&self, kind: DiagnosticKind) -> Diagnostic {
+        Diagnostic::error(kind).in_component(&self.name).with_span(self.span)
     }
 }
 
+// Statement of a component body after elaboration, with every array
+// expanded to single bits
+#[derive(Debug, Clone)]
+pub enum Elaborated {
+    Call(CompInfo),
+    // x$1, x$0 = a, b
+    Assignment {
+        left: Vec<String>,
+        right: Vec<String>,
+        span: Option<Span>,
+    },
+}
+
 #[derive(Clone, Debug)]
 struct Assignments {
     // Each entry in v is a vector of signals that are equivalent:
@@ -57,12 +179,13 @@ impl Assignments {
     fn new() -> Self {
         Self { v: vec![], }
     }
-    fn add(&mut self, c: &CompInfo) {
-        if c.inputs.len() != c.outputs.len() {
-            error!("Unbalanced assignment: {} on the left, and {} on the right", c.outputs.len(), c.inputs.len());
-            panic!("Unbalanced assignment");
+    fn add(&mut self, left: &[String], right: &[String]) -> Result<(), DiagnosticKind> {
+        if left.len() != right.len() {
+            return Err(DiagnosticKind::UnbalancedAssignment {
+                left: left.len(), right: right.len()
+            });
         }
-        for (left, right) in c.inputs.iter().zip(c.outputs.iter()) {
+        for (left, right) in left.iter().zip(right.iter()) {
             if left == right {
                 continue;
             }
@@ -82,15 +205,13 @@ impl Assignments {
                 for x in ass {
                     if x == left {
                         if left_pos.is_some() {
-                            error!("Duplicate assignment (left_pos: {:?})", left_pos);
-                            panic!("Duplicate");
+                            return Err(DiagnosticKind::DuplicateAssignment { signal: left.clone() });
                         }
                         left_pos = Some(i);
                     }
                     if x == right {
                         if right_pos.is_some() {
-                            error!("Duplicate assignment (right_pos: {:?})", left_pos);
-                            panic!("Duplicate");
+                            return Err(DiagnosticKind::DuplicateAssignment { signal: right.clone() });
                         }
                         right_pos = Some(i);
                     }
@@ -116,15 +237,13 @@ impl Assignments {
                     let merge = self.v.swap_remove(j);
                     self.v[i].extend(merge);
                 }
-                (Some(i), Some(j)) if i == j => {
+                (Some(_), Some(_)) => {
                     // Do nothing, they are already in the same group
                 }
-                _ => {
-                    error!("Impossible branch taken");
-                    unreachable!()
-                }
             }
         }
+
+        Ok(())
     }
 }
 
@@ -138,24 +257,34 @@ pub struct CompDefinition {
     comp: Vec<CompId>, // global component id, including c_zero
     connections: HashMap<ComponentIndex, Vec<ComponentIndex>>, // connections[local_comp_id][output_id]
     generics: HashMap<usize, (usize, usize)>,
+    // Instance names of the local components
+    labels: Vec<Option<String>>,
 }
 
 impl CompDefinition {
     //TODO: warning: the function has a cyclomatic complexity of 29
     fn new(components: &HashMap<CompId, CompInfo>,
            comp_id: &HashMap<String, CompId>,
+           templates: &[&str],
+           invalid: &[&str],
            c_zero: &CompInfo,
-           other: &[CompInfo]
-    ) -> Result<Self, String> {
+           other: &[Elaborated]
+    ) -> Result<Self, Vec<Diagnostic>> {
         let mut comp = vec![];
+        // Span of the statement which created each local component
+        let mut spans = vec![];
+        let mut labels = vec![];
+        let mut used_labels = HashMap::new();
+        let mut errors = vec![];
         let mut assignments = Assignments::new();
         let mut signals = HashMap::new();
-        let name = &c_zero.name;
 
         for c in [c_zero].iter() {
             info!("Inserting {:#?}", c);
             let c_id = comp_id[&c.name];
             comp.push(c_id);
+            spans.push(c.span);
+            labels.push(None);
             let l_id = comp.len() - 1;
             // Remember the weirdness: inputs = outputs
             for (j, n) in c.inputs.iter().enumerate() {
@@ -171,47 +300,104 @@ impl CompDefinition {
         // If a gate can have an undefined number of inputs, store it here
         let mut generics = HashMap::new();
 
-        for c in other {
-            // Prevent recursive definitions
-            if c_zero.name == c.name {
-                return Err(format!("Recursive definition of component {}", name));
+        // Mux(s: sel, a: x) is converted to Mux(sel, x) once the callee is
+        // known. Calls with errors in their ports are skipped
+        let mut resolved = Vec::with_capacity(other.len());
+        for st in other {
+            let c = match st {
+                Elaborated::Call(c) => c,
+                Elaborated::Assignment { left, right, span } => {
+                    if let Err(kind) = assignments.add(left, right) {
+                        errors.push(Diagnostic::error(kind).in_component(&c_zero.name).with_span(*span));
+                    }
+                    continue;
+                }
+            };
+            let named = c.named_inputs.is_some() || c.named_outputs.is_some();
+            match comp_id.get(&c.name) {
+                Some(c_id) if named => match c.resolve_ports(&components[c_id]) {
+                    Ok(x) => resolved.push(Some(Cow::Owned(x))),
+                    Err(e) => {
+                        errors.extend(e.into_iter().map(|d| d.in_component(&c_zero.name)));
+                        resolved.push(None);
+                    }
+                },
+                _ => resolved.push(Some(Cow::Borrowed(c))),
             }
-            
+        }
 
-            // Process assignments
-            if c.name == "actually, I'm just an assignment" {
-                assignments.add(&c);
+        for c in resolved.iter().flatten() {
+            // Prevent recursive definitions
+            if c_zero.name == c.name {
+                errors.push(c.error(DiagnosticKind::RecursiveDefinition)
+                            .in_component(&c_zero.name));
                 continue;
             }
+
             info!("Inserting {:#?}", c);
             let c_id = match comp_id.get(&c.name) {
                 Some(a) => *a,
-                None => return Err(format!("Component {} not found", c.name)),
+                // The elaborator already reported why this instance of a
+                // parametric component is missing, like Buf<0> with a[-1:0]
+                None if c.name.find('<').is_some_and(|i| templates.contains(&&c.name[..i])) => continue,
+                // Same for the components with errors in their header
+                None if invalid.contains(&c.name.as_str()) => continue,
+                None => {
+                    let similar = similar_component(comp_id, templates, &c.name);
+                    errors.push(c.error(DiagnosticKind::ComponentNotFound {
+                        name: c.name.clone()
+                    }).in_component(&c_zero.name).with_help(similar.map(|x| suggest::help(&x))));
+                    continue;
+                }
             };
 
             // Verify than number of inputs and outputs match
             if is_builtin(c.name.as_str()) {
                 // Builtin gates can have a generic number of inputs or outputs,
-                // we dont check them here, but they are checked when creating
-                // these components (create_builtin)
+                // but only some combinations are valid
+                if !builtin_arity(&c.name, c.inputs.len(), c.outputs.len()) {
+                    errors.push(c.error(DiagnosticKind::BuiltinArity {
+                        name: c.name.clone(),
+                        inputs: c.inputs.len(),
+                        outputs: c.outputs.len(),
+                    }).in_component(&c_zero.name));
+                    continue;
+                }
             } else {
+                let mut wrong_arity = false;
                 if components[&c_id].inputs.len() != c.inputs.len() {
-                    return Err(format!("Wrong number of inputs in {} definition: component \
-                        {} has {} inputs but {} were supplied",
-                        c_zero.name, c.name,
-                        components[&c_id].inputs.len(), c.inputs.len())
-                    );
+                    errors.push(c.error(DiagnosticKind::WrongNumberOfInputs {
+                        callee: c.name.clone(),
+                        expected: components[&c_id].inputs.len(),
+                        found: c.inputs.len(),
+                    }).in_component(&c_zero.name));
+                    wrong_arity = true;
                 }
                 if components[&c_id].outputs.len() != c.outputs.len() {
-                    return Err(format!("Wrong number of outputs in {} definition: component \
-                        {} has {} outputs but {} were supplied",
-                        c_zero.name, c.name,
-                        components[&c_id].outputs.len(), c.outputs.len())
-                    );
+                    errors.push(c.error(DiagnosticKind::WrongNumberOfOutputs {
+                        callee: c.name.clone(),
+                        expected: components[&c_id].outputs.len(),
+                        found: c.outputs.len(),
+                    }).in_component(&c_zero.name));
+                    wrong_arity = true;
+                }
+                if wrong_arity {
+                    continue;
+                }
+            }
+
+            if let Some(label) = &c.label {
+                if used_labels.insert(label, ()).is_some() {
+                    errors.push(c.error(DiagnosticKind::DuplicateInstance {
+                        name: label.clone()
+                    }).in_component(&c_zero.name));
+                    continue;
                 }
             }
 
             comp.push(c_id);
+            spans.push(c.span);
+            labels.push(c.label.clone());
             let l_id = comp.len() - 1;
             generics.insert(l_id, (c.inputs.len(), c.outputs.len()));
             for (j, n) in c.inputs.iter().enumerate() {
@@ -224,55 +410,48 @@ impl CompDefinition {
             }
         }
 
-        // Verify that arrays have unique names: (a, a[3:0]) should fail
-        let mut array_names = HashMap::new();
-        for (s, _con) in &signals {
+        let error_at = |kind, con: &[ComponentIndex]| {
+            let span = con.first().and_then(|x| spans[x.c_id]);
+            Diagnostic::error(kind).in_component(&c_zero.name).with_span(span)
+        };
+
+        // Verify that arrays have unique names: (a, a[3:0]) should fail, and
+        // that they always have the same dimensions: (a[1][0], a[3:0]) too
+        let mut names: Vec<(&str, &[ComponentIndex])> = signals.iter()
+            .map(|(s, con)| (s.as_str(), con.as_slice()))
+            .chain(assignments.v.iter().flat_map(|g| g.iter().map(|s| (s.as_str(), &[][..]))))
+            .collect();
+        names.sort_by_key(|&(s, _)| s);
+        let mut dimensions = HashMap::new();
+        let mut reported = HashMap::new();
+        for (s, con) in names {
             let parts: Vec<_> = s.split('$').collect();
-            if parts.len() > 1 {
-                if parts.len() != 2 {
-                    return Err(format!(
-                        "Only 1D arrays are supported at the moment, got {:?} in component {}",
-                        parts, c_zero.name));
-                }
-                array_names.insert(parts[0], 1); // 1D array
+            // _ is always unconnected, it can be used with any dimension
+            if parts[0] == "_" {
+                continue;
             }
-        }
-
-        for group in assignments.v.iter() {
-            for name in group.iter() {
-                let parts: Vec<_> = name.split('$').collect();
-                if parts.len() > 1 {
-                    if parts.len() != 2 {
-                        return Err(format!(
-                            "Only 1D arrays are supported at the moment, got {:?} in component {}",
-                            parts, c_zero.name));
-                    }
-                    array_names.insert(parts[0], 1); // 1D array
-                }
+            let dim = parts.len() - 1;
+            let expected = *dimensions.entry(parts[0]).or_insert(dim);
+            // Report each array only once
+            if expected == dim || reported.insert(parts[0], ()).is_some() {
+                continue;
             }
-        }
-
-        for group in assignments.v.iter() {
-            for name in group.iter() {
-                let parts: Vec<_> = name.split('$').collect();
-                if parts.len() == 1 {
-                    if let Some(_) = array_names.get(parts[0]) {
-                        return Err(format!("Signal `{}` is used as an array, but also as a bit, in component {}", name, c_zero.name));
-                
-                    }
+            let kind = if expected == 0 || dim == 0 {
+                DiagnosticKind::ArrayUsedAsBit { signal: parts[0].to_string() }
+            } else {
+                DiagnosticKind::ArrayDimensionMismatch {
+                    signal: parts[0].to_string(), expected, found: dim
                 }
-            }
+            };
+            errors.push(error_at(kind, con));
         }
 
-        debug!("ARRAY NAMES: {:?}", array_names);
+        debug!("ARRAY DIMENSIONS: {:?}", dimensions);
 
-        for (name, _dimension) in array_names {
-            if let Some(_) = signals.get(&name.to_string()) {
-                return Err(format!("Signal `{}` is used as an array, but also as a bit, in component {}", name, c_zero.name));
-            }
+        if !errors.is_empty() {
+            return Err(errors);
         }
 
-
         // Apply assignments
         for ass in assignments.v.iter() {
             let ass2 = &ass[0];
@@ -305,21 +484,587 @@ impl CompDefinition {
                 }
             }
             if from.len() > 1 {
-                return Err(format!("Component {}:\nSignal {} is connected to more than one output:\n{:#?}",
-                       c_zero.name, s, con));
+                errors.push(error_at(DiagnosticKind::MultipleDrivers {
+                    signal: s.to_string(),
+                    drivers: from.len(),
+                }, &from[1..]));
+                continue;
             }
             // Remove duplicate connections (can be created using assignments)
             let to = to_set.drain().map(|(k, _v)| k).collect();
             if from.len() == 1 {
                 connections.insert(from[0].clone(), to);
             } else { // from.len() == 0
-                // panic?
+                // Undriven signals are reported by the lint pass
             }
         }
 
         debug!("Signals: {:#?}", signals);
 
-        Ok(Self { comp, connections, generics })
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(Self { comp, connections, generics, labels })
+    }
+}
+
+// Find the cycles in the graph of components using other components:
+// A -> B -> A. Components which use themselves directly are reported by
+// the elaborator and CompDefinition::new. The graph is built after the
+// elaboration, so A<N> may use B<N - 1> under an `if`, while C -> A<3> -> C
+// is a cycle
+fn recursion_cycles(all: &[(CompInfo, Vec<Elaborated>)]) -> Vec<Diagnostic> {
+    let mut graph: HashMap<&str, Vec<&CompInfo>> = HashMap::new();
+    for (c_zero, other) in all {
+        let calls = graph.entry(&c_zero.name).or_default();
+        for x in other {
+            // A loop calls the same component many times
+            match x {
+                Elaborated::Call(c) if !calls.iter().any(|y| y.name == c.name) => calls.push(c),
+                _ => {}
+            }
+        }
+    }
+
+    #[derive(Copy, Clone, PartialEq)]
+    enum State { Visiting, Done }
+    struct Dfs<'a> {
+        graph: HashMap<&'a str, Vec<&'a CompInfo>>,
+        state: HashMap<&'a str, State>,
+        stack: Vec<&'a str>,
+        errors: Vec<Diagnostic>,
+    }
+    impl<'a> Dfs<'a> {
+        fn visit(&mut self, name: &'a str) {
+            self.state.insert(name, State::Visiting);
+            self.stack.push(name);
+            let calls = self.graph[name].clone();
+            for c in calls {
+                let callee = c.name.as_str();
+                if callee == name || !self.graph.contains_key(callee) {
+                    continue;
+                }
+                match self.state.get(callee) {
+                    None => self.visit(callee),
+                    Some(State::Visiting) => {
+                        let pos = self.stack.iter().position(|&x| x == callee).unwrap();
+                        let mut chain: Vec<_> = self.stack[pos..].iter().map(|x| x.to_string()).collect();
+                        chain.push(callee.to_string());
+                        self.errors.push(Diagnostic::error(DiagnosticKind::RecursiveChain { chain })
+                            .in_component(name).with_span(c.span));
+                    }
+                    Some(State::Done) => {}
+                }
+            }
+            self.stack.pop();
+            self.state.insert(name, State::Done);
+        }
+    }
+
+    let mut dfs = Dfs { graph, state: HashMap::new(), stack: vec![], errors: vec![] };
+    for (c_zero, _) in all {
+        if !dfs.state.contains_key(c_zero.name.as_str()) {
+            dfs.visit(&c_zero.name);
+        }
+    }
+
+    dfs.errors
+}
+
+// Parametric components using parametric components, Tree<N> using
+// Tree<N-1>, can be nested this deep
+pub const MAX_ELABORATION_DEPTH: usize = 256;
+
+// Expand arrays, parameters and generate statements. Parametric components
+// are only elaborated when they are used, once for each distinct set of
+// parameters: Reg<8>
+fn elaborate(defs: &[CompDef]) -> (Vec<(CompInfo, Vec<Elaborated>)>, Vec<Diagnostic>) {
+    let mut e = Elaborator {
+        templates: HashMap::new(),
+        queue: VecDeque::new(),
+        requested: HashMap::new(),
+        errors: vec![],
+        loop_indices: vec![],
+        depth: 0,
+    };
+    for def in defs {
+        if e.templates.contains_key(def.name.as_str()) {
+            e.errors.push(Diagnostic::error(DiagnosticKind::Redefinition {
+                name: def.name.clone()
+            }).in_component(&def.name).with_span(Some(def.span)));
+            continue;
+        }
+        e.templates.insert(&def.name, def);
+        if !def.is_parametric() {
+            e.queue.push_back((def, vec![], 0));
+        }
+    }
+
+    let mut all = vec![];
+    while let Some((def, values, depth)) = e.queue.pop_front() {
+        e.depth = depth;
+        let mut env = def.consts.clone();
+        env.extend(def.params.iter().cloned().zip(values.iter().cloned()));
+        let name = if def.is_parametric() {
+            instance_name(&def.name, &values)
+        } else {
+            def.name.clone()
+        };
+
+        let header = match (eval_ranges(&def.inputs, &env), eval_ranges(&def.outputs, &env)) {
+            (Ok(i), Ok(o)) => (i, o),
+            (Err(k), _) | (_, Err(k)) => {
+                e.error(&name, k, def.span);
+                continue;
+            }
+        };
+
+        let mut body = vec![];
+        e.statements(def, &name, &def.body, &mut env, &mut body);
+
+        // All the constants of a component share the same driver
+        let is_constant = |(s, _): &(String, _)| s.starts_with('\'');
+        if body.iter().any(|st| st.io().0.iter().any(is_constant)) {
+            let outputs = [Bit::L, Bit::H, Bit::X].iter()
+                .map(|&b| (constant_signal(b).to_string(), vec![]))
+                .collect();
+            body.push(Resolved::Call(ResolvedCall {
+                callee: "ConstantBit".to_string(),
+                label: None,
+                inputs: vec![],
+                outputs,
+                input_ports: None,
+                output_ports: None,
+                span: def.span,
+            }));
+        }
+
+        // The ports define the shape of the arrays, internal signals get
+        // it from the use with the most dimensions: m[3:0][7:0]
+        let mut shapes: HashMap<&str, &[(u64, u64)]> = HashMap::new();
+        for (name, ranges) in header.0.iter().chain(header.1.iter()) {
+            shapes.entry(name).or_insert(ranges);
+        }
+        let ports: HashMap<&str, ()> = shapes.keys().map(|&k| (k, ())).collect();
+        let uses = body.iter().flat_map(|st| {
+            let (inputs, outputs) = st.io();
+            inputs.iter().chain(outputs.iter())
+        });
+        for (name, ranges) in uses {
+            if ports.contains_key(name.as_str()) {
+                continue;
+            }
+            let shape = shapes.entry(name).or_insert(ranges);
+            if shape.len() < ranges.len() {
+                *shape = ranges;
+            }
+        }
+        let expand = |v: &[(String, Vec<(u64, u64)>)]| {
+            let mut bits = vec![];
+            for (name, ranges) in v {
+                let shape = shapes.get(name.as_str()).cloned().unwrap_or(&[]);
+                if ranges.is_empty() || ranges.len() >= shape.len() || name == "_" {
+                    bits.extend(expand_bits(name, ranges));
+                } else {
+                    // mem[3] is the whole row: mem[3][7:0]
+                    let mut r = ranges.clone();
+                    r.extend_from_slice(&shape[ranges.len()..]);
+                    bits.extend(expand_bits(name, &r));
+                }
+            }
+            bits
+        };
+
+        let c_zero = CompInfo::new(name.clone(), expand(&header.0), expand(&header.1))
+            .with_span(def.span)
+            .with_doc(def.doc.clone(), def.port_docs.clone())
+            .with_port_spans(def.inputs.iter().chain(&def.outputs).map(|p| (p.name.clone(), p.span)).collect());
+        let named = |v: &[(String, Vec<(u64, u64)>)], ports: &Option<Vec<(String, usize)>>| {
+            ports.as_ref().map(|ports| {
+                let mut i = 0;
+                ports.iter().map(|(port, n)| {
+                    let bits = expand(&v[i..i + n]);
+                    i += n;
+                    (port.clone(), bits)
+                }).collect()
+            })
+        };
+        let mut other = vec![];
+        let mut temps = 0;
+        for st in &body {
+            match st {
+                Resolved::Call(c) => other.push(Elaborated::Call(
+                    CompInfo::new(c.callee.clone(), expand(&c.inputs), expand(&c.outputs))
+                        .with_span(c.span)
+                        .with_label(c.label.clone())
+                        .with_ports(named(&c.inputs, &c.input_ports), named(&c.outputs, &c.output_ports))
+                )),
+                Resolved::Assignment { left, right, span } => other.push(Elaborated::Assignment {
+                    left: expand(left),
+                    right: expand(right),
+                    span: Some(*span),
+                }),
+                Resolved::Expression { left, inputs, expr, span } => {
+                    // A constant is one entry of `inputs` for each bit
+                    let mut i = 0;
+                    let leaves = expr.signals().into_iter().map(|s| {
+                        let n = match s {
+                            Signal::Constant(c) => c.bits.len(),
+                            Signal::Bits(_) => 1,
+                        };
+                        i += n;
+                        expand(&inputs[i - n..i])
+                    }).collect::<Vec<_>>();
+                    let mut l = Lowering { gates: vec![], temps, span: *span };
+                    match l.expr(expr, &mut leaves.into_iter()) {
+                        Ok(right) => {
+                            temps = l.temps;
+                            other.extend(l.gates);
+                            other.push(Elaborated::Assignment { left: expand(left), right, span: Some(*span) });
+                        }
+                        Err(k) => e.error(&name, k, *span),
+                    }
+                }
+            }
+        }
+
+        all.push((c_zero, other));
+    }
+
+    (all, e.errors)
+}
+
+// Array ranges of each signal, after substituting parameters
+type Ranges = Vec<(String, Vec<(u64, u64)>)>;
+
+fn eval_ranges(v: &[BitArray], env: &Env) -> Result<Ranges, DiagnosticKind> {
+    let mut r = vec![];
+    for x in v {
+        r.push((x.name.clone(), x.eval(env)?));
+    }
+    Ok(r)
+}
+
+// Constants are replaced by one signal for each bit: '0, '1 or 'x
+fn eval_signals(v: &[Signal], env: &Env) -> Result<Ranges, DiagnosticKind> {
+    let mut r = vec![];
+    for x in v {
+        match x {
+            Signal::Bits(x) => r.push((x.name.clone(), x.eval(env)?)),
+            Signal::Constant(c) => {
+                r.extend(c.bits.iter().map(|&b| (constant_signal(b).to_string(), vec![])));
+            }
+        }
+    }
+    Ok(r)
+}
+
+// Component call with all the parameters and loop variables substituted
+struct ResolvedCall {
+    callee: String,
+    // Inside loops the values of the loop variables are appended: u[3]
+    label: Option<String>,
+    inputs: Ranges,
+    outputs: Ranges,
+    // Port names, and the number of entries of `inputs` and `outputs`
+    // used by each one
+    input_ports: Option<Vec<(String, usize)>>,
+    output_ports: Option<Vec<(String, usize)>>,
+    span: Span,
+}
+
+enum Resolved {
+    Call(ResolvedCall),
+    Assignment { left: Ranges, right: Ranges, span: Span },
+    // `inputs` are the signals of the expression, from left to right
+    Expression { left: Ranges, inputs: Ranges, expr: Expr, span: Span },
+}
+
+impl Resolved {
+    // Signals read and written by this statement
+    fn io(&self) -> (&Ranges, &Ranges) {
+        match self {
+            Resolved::Call(c) => (&c.inputs, &c.outputs),
+            Resolved::Assignment { left, right, .. } => (right, left),
+            Resolved::Expression { left, inputs, .. } => (inputs, left),
+        }
+    }
+}
+
+// Boolean expressions are lowered to Nand gates. The intermediate results
+// are internal signals %t0, %t1, ... which cannot clash with user names
+struct Lowering {
+    gates: Vec<Elaborated>,
+    temps: usize,
+    span: Span,
+}
+
+impl Lowering {
+    fn nand(&mut self, inputs: Vec<String>) -> String {
+        let t = format!("%t{}", self.temps);
+        self.temps += 1;
+        self.gates.push(Elaborated::Call(
+            CompInfo::new("Nand".to_string(), inputs, vec![t.clone()]).with_span(self.span)
+        ));
+        t
+    }
+    fn not(&mut self, v: Vec<String>) -> Vec<String> {
+        v.into_iter().map(|x| self.nand(vec![x])).collect()
+    }
+    // Bits of the expression. `leaves` are the bits of each signal
+    fn expr<I>(&mut self, e: &Expr, leaves: &mut I) -> Result<Vec<String>, DiagnosticKind>
+        where I: Iterator<Item=Vec<String>>
+    {
+        Ok(match e {
+            Expr::Signal(_) => leaves.next().unwrap(),
+            Expr::Concat(v) => {
+                let mut bits = vec![];
+                for e in v {
+                    bits.extend(self.expr(e, leaves)?);
+                }
+                bits
+            }
+            Expr::Not(a) => self.inverted(a, leaves)?,
+            Expr::Binary(_, LogicOp::And, _) => {
+                let n = self.inverted(e, leaves)?;
+                self.not(n)
+            }
+            Expr::Binary(a, LogicOp::Or, b) => {
+                // a | b = !a NAND !b
+                let (a, b) = (self.inverted(a, leaves)?, self.inverted(b, leaves)?);
+                check_widths("|", &a, &b)?;
+                a.into_iter().zip(b).map(|(x, y)| self.nand(vec![x, y])).collect()
+            }
+            Expr::Binary(a, LogicOp::Xor, b) => {
+                let (a, b) = (self.expr(a, leaves)?, self.expr(b, leaves)?);
+                check_widths("^", &a, &b)?;
+                a.into_iter().zip(b).map(|(x, y)| {
+                    let n = self.nand(vec![x.clone(), y.clone()]);
+                    let p = self.nand(vec![x, n.clone()]);
+                    let q = self.nand(vec![y, n]);
+                    self.nand(vec![p, q])
+                }).collect()
+            }
+        })
+    }
+    // Bits of !e, avoiding double negations: !(a & b) is one Nand
+    fn inverted<I>(&mut self, e: &Expr, leaves: &mut I) -> Result<Vec<String>, DiagnosticKind>
+        where I: Iterator<Item=Vec<String>>
+    {
+        Ok(match e {
+            Expr::Not(a) => self.expr(a, leaves)?,
+            Expr::Binary(a, LogicOp::And, b) => {
+                let (a, b) = (self.expr(a, leaves)?, self.expr(b, leaves)?);
+                check_widths("&", &a, &b)?;
+                a.into_iter().zip(b).map(|(x, y)| self.nand(vec![x, y])).collect()
+            }
+            _ => {
+                let v = self.expr(e, leaves)?;
+                self.not(v)
+            }
+        })
+    }
+}
+
+fn check_widths(op: &str, a: &[String], b: &[String]) -> Result<(), DiagnosticKind> {
+    if a.len() == b.len() {
+        Ok(())
+    } else {
+        Err(DiagnosticKind::OperandWidthMismatch { op: op.to_string(), left: a.len(), right: b.len() })
+    }
+}
+
+struct Elaborator<'a> {
+    templates: HashMap<&'a str, &'a CompDef>,
+    // Parametric components waiting to be elaborated
+    queue: VecDeque<(&'a CompDef, Vec<i64>, usize)>,
+    requested: HashMap<String, ()>,
+    errors: Vec<Diagnostic>,
+    // Values of the enclosing loop variables, outermost first
+    loop_indices: Vec<i64>,
+    // Number of parametric components between the component being
+    // elaborated and a non parametric one
+    depth: usize,
+}
+
+impl<'a> Elaborator<'a> {
+    fn error(&mut self, name: &str, kind: DiagnosticKind, span: Span) {
+        self.errors.push(Diagnostic::error(kind).in_component(name).with_span(Some(span)));
+    }
+    // Unroll loops and evaluate conditions, appending the resulting calls
+    // to `other`
+    fn statements(&mut self, def: &'a CompDef, name: &str, body: &'a [Statement],
+                  env: &mut Env, other: &mut Vec<Resolved>) {
+        for st in body {
+            match st {
+                Statement::Call(call) => {
+                    if let Some(c) = self.call(def, name, call, env) {
+                        other.push(Resolved::Call(c));
+                    }
+                }
+                Statement::Assignment(a) => {
+                    if let Some(c) = self.assignment(name, a, env) {
+                        other.push(c);
+                    }
+                }
+                Statement::Expression(a) => {
+                    if let Some(c) = self.expression(name, a, env) {
+                        other.push(c);
+                    }
+                }
+                Statement::Comment(_) => {}
+                Statement::For { var, start, end, body, span } => {
+                    let (start, end) = match (start.eval(env), end.eval(env)) {
+                        (Ok(a), Ok(b)) => (a, b),
+                        (Err(k), _) | (_, Err(k)) => {
+                            self.error(name, k, *span);
+                            continue;
+                        }
+                    };
+                    // The loop variable shadows parameters with the same name
+                    let old = env.get(var).cloned();
+                    for i in start..end {
+                        env.insert(var.clone(), i);
+                        self.loop_indices.push(i);
+                        self.statements(def, name, body, env, other);
+                        self.loop_indices.pop();
+                    }
+                    match old {
+                        Some(x) => env.insert(var.clone(), x),
+                        None => env.remove(var),
+                    };
+                }
+                Statement::If { cond, then, otherwise, span } => {
+                    match cond.eval(env) {
+                        Ok(true) => self.statements(def, name, then, env, other),
+                        Ok(false) => self.statements(def, name, otherwise, env, other),
+                        Err(k) => self.error(name, k, *span),
+                    }
+                }
+            }
+        }
+    }
+    fn assignment(&mut self, name: &str, a: &Assignment, env: &Env) -> Option<Resolved> {
+        for x in &a.left {
+            if let Signal::Constant(c) = x {
+                self.error(name, DiagnosticKind::DrivenConstant { constant: c.to_string() }, a.span);
+                return None;
+            }
+        }
+
+        match (eval_signals(&a.left, env), eval_signals(&a.right, env)) {
+            (Ok(left), Ok(right)) => Some(Resolved::Assignment { left, right, span: a.span }),
+            (Err(k), _) | (_, Err(k)) => {
+                self.error(name, k, a.span);
+                None
+            }
+        }
+    }
+    fn expression(&mut self, name: &str, a: &ExprAssignment, env: &Env) -> Option<Resolved> {
+        for x in &a.left {
+            if let Signal::Constant(c) = x {
+                self.error(name, DiagnosticKind::DrivenConstant { constant: c.to_string() }, a.span);
+                return None;
+            }
+        }
+        let signals: Vec<Signal> = a.right.signals().into_iter().cloned().collect();
+
+        match (eval_signals(&a.left, env), eval_signals(&signals, env)) {
+            (Ok(left), Ok(inputs)) => Some(Resolved::Expression {
+                left, inputs, expr: a.right.clone(), span: a.span
+            }),
+            (Err(k), _) | (_, Err(k)) => {
+                self.error(name, k, a.span);
+                None
+            }
+        }
+    }
+    fn call(&mut self, def: &'a CompDef, name: &str, call: &CompCall, env: &Env) -> Option<ResolvedCall> {
+        let mut callee = call.name.clone();
+        match self.templates.get(call.name.as_str()).cloned() {
+            Some(t) if t.name == def.name && !t.is_parametric() => {
+                self.error(name, DiagnosticKind::RecursiveDefinition, call.span);
+                return None;
+            }
+            Some(t) if t.params.len() != call.params.len() => {
+                self.error(name, DiagnosticKind::WrongNumberOfParameters {
+                    callee: call.name.clone(),
+                    expected: t.params.len(),
+                    found: call.params.len(),
+                }, call.span);
+                return None;
+            }
+            Some(t) if t.is_parametric() => {
+                let values: Result<Vec<_>, _> = call.params.iter().map(|p| p.eval(env)).collect();
+                let values = match values {
+                    Ok(v) => v,
+                    Err(k) => {
+                        self.error(name, k, call.span);
+                        return None;
+                    }
+                };
+                callee = instance_name(&t.name, &values);
+                // Reg<N> using Reg<N-1> is fine if an `if` stops the
+                // recursion, but Reg<N> using Reg<N> is not
+                if callee == name {
+                    self.error(name, DiagnosticKind::RecursiveDefinition, call.span);
+                    return None;
+                }
+                if self.requested.insert(callee.clone(), ()).is_none() {
+                    if self.depth == MAX_ELABORATION_DEPTH {
+                        let kind = DiagnosticKind::ElaborationTooDeep { max: MAX_ELABORATION_DEPTH };
+                        let help = "a component which uses itself needs a condition which stops the recursion, like `if N > 0`";
+                        self.errors.push(Diagnostic::error(kind).in_component(name)
+                            .with_span(Some(call.span)).with_help(Some(help.to_string())));
+                        return None;
+                    }
+                    self.queue.push_back((t, values, self.depth + 1));
+                }
+            }
+            None if !call.params.is_empty() && is_builtin(&call.name) => {
+                self.error(name, DiagnosticKind::WrongNumberOfParameters {
+                    callee: call.name.clone(),
+                    expected: 0,
+                    found: call.params.len(),
+                }, call.span);
+                return None;
+            }
+            _ => {}
+        }
+
+        for x in &call.outputs {
+            if let Signal::Constant(c) = x {
+                self.error(name, DiagnosticKind::DrivenConstant { constant: c.to_string() }, call.span);
+                return None;
+            }
+        }
+
+        // Constants use one entry for each bit
+        let ports = |names: &Option<Vec<String>>, signals: &[Signal]| {
+            names.as_ref().map(|names| {
+                names.iter().cloned().zip(signals.iter().map(|s| match s {
+                    Signal::Bits(_) => 1,
+                    Signal::Constant(c) => c.bits.len(),
+                })).collect()
+            })
+        };
+        let input_ports = ports(&call.input_ports, &call.inputs);
+        let output_ports = ports(&call.output_ports, &call.outputs);
+        let label = call.label.as_ref().map(|l| {
+            let indices: String = self.loop_indices.iter().map(|i| format!("[{}]", i)).collect();
+            format!("{}{}", l, indices)
+        });
+
+        match (eval_signals(&call.inputs, env), eval_signals(&call.outputs, env)) {
+            (Ok(inputs), Ok(outputs)) => Some(ResolvedCall {
+                callee, label, inputs, outputs, input_ports, output_ports, span: call.span
+            }),
+            (Err(k), _) | (_, Err(k)) => {
+                self.error(name, k, call.span);
+                None
+            }
+        }
     }
 }
 
@@ -328,42 +1073,120 @@ pub struct ComponentFactory {
     comp_id: HashMap<String, CompId>,
     components: HashMap<CompId, Rc<CompInfo>>,
     comp_def: HashMap<CompId, Rc<CompDefinition>>,
+    tables: HashMap<CompId, TruthTable>,
     cache: RefCell<HashMap<CompId, Box<dyn Component>>>,
     stdin_bufread: Option<RcBufRead>,
     stdout_bufwrite: Option<RcWrite>,
+    // Found by the lint pass, before applying any LintConfig
+    lints: Vec<Diagnostic>,
+    tests: TestSuite,
 }
 
 impl ComponentFactory {
-    fn new(all: Vec<(CompInfo, Vec<CompInfo>)>) -> Result<Self, String> {
+    pub(crate) fn new(defs: Vec<CompDef>) -> Result<Self, CompileError> {
         let mut components = HashMap::new();
         let mut comp_id = HashMap::new();
         let mut comp_def = HashMap::new();
+        let (all, mut errors) = elaborate(&defs);
+        // Components which use themselves through other components would
+        // never stop creating
+        errors.extend(recursion_cycles(&all));
 
         insert_special_components(&mut components, &mut comp_id);
         let mut i = components.len();
 
+        // Components with errors in their header are not defined, so the
+        // remaining definitions can still be checked
+        let mut defined = Vec::with_capacity(all.len());
+        let mut invalid = vec![];
         for &(ref c_zero, ref _other) in all.iter() {
-            let mut c_zero = c_zero.clone();
-            c_zero.verify();
-            if let Some(_) = comp_id.get(&c_zero.name) {
-                return Err(format!("Redefinition of component {}", c_zero.name));
+            if let Err(e) = c_zero.verify() {
+                errors.extend(e);
+                defined.push(false);
+                invalid.push(c_zero.name.as_str());
+                continue;
+            }
+            if comp_id.contains_key(&c_zero.name) {
+                errors.push(c_zero.error(DiagnosticKind::Redefinition {
+                    name: c_zero.name.clone()
+                }));
+                defined.push(false);
+                continue;
             }
             comp_id.insert(c_zero.name.clone(), CompId(i));
-            components.insert(CompId(i), c_zero);
+            components.insert(CompId(i), c_zero.clone());
+            defined.push(true);
 
             i += 1;
         }
 
-        for (c_zero, other) in all {
-            let def = CompDefinition::new(&components, &comp_id, &c_zero, &other)?;
-            let g_id = comp_id[&c_zero.name];
-            comp_def.insert(g_id, def);
+        let templates: Vec<&str> = defs.iter().filter(|d| d.is_parametric()).map(|d| d.name.as_str()).collect();
+        let table_defs: HashMap<&str, &Table> = defs.iter()
+            .filter_map(|d| d.table.as_ref().map(|t| (d.name.as_str(), t)))
+            .collect();
+        let mut tables = HashMap::new();
+        for ((c_zero, other), defined) in all.iter().zip(defined) {
+            if !defined {
+                continue;
+            }
+            // Truth tables have no body, they are evaluated by lookup
+            if let Some(t) = table_defs.get(c_zero.name.as_str()) {
+                match TruthTable::compile(c_zero, t) {
+                    Ok(t) => {
+                        tables.insert(comp_id[&c_zero.name], t);
+                    }
+                    Err(e) => errors.extend(e),
+                }
+                continue;
+            }
+            match CompDefinition::new(&components, &comp_id, &templates, &invalid, c_zero, other) {
+                Ok(def) => {
+                    let g_id = comp_id[&c_zero.name];
+                    comp_def.insert(g_id, def);
+                }
+                Err(e) => errors.extend(e),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(CompileError::new(errors));
         }
 
+        let lints = lint::check(&defs, &all);
         let components = components.into_iter().map(|(k, v)| (k, Rc::new(v))).collect();
         let comp_def = comp_def.into_iter().map(|(k, v)| (k, Rc::new(v))).collect();
 
-        Ok(Self { components, comp_id, comp_def, cache: RefCell::new(HashMap::new()), stdin_bufread: None, stdout_bufwrite: None })
+        Ok(Self { components, comp_id, comp_def, tables, cache: RefCell::new(HashMap::new()), stdin_bufread: None, stdout_bufwrite: None, lints,
+                  tests: TestSuite::new(vec![], None, Lines::new(vec![])) })
+    }
+    // Warnings of the lint pass, with the default configuration
+    pub fn warnings(&self) -> Vec<Diagnostic> {
+        self.lints(&LintConfig::default())
+    }
+    pub fn lints(&self, config: &LintConfig) -> Vec<Diagnostic> {
+        config.apply(&self.lints)
+    }
+    pub(crate) fn lints_mut(&mut self) -> &mut Vec<Diagnostic> {
+        &mut self.lints
+    }
+    // Test blocks of the source file, run them with `tests().run(&cf)`
+    pub fn tests(&self) -> &TestSuite {
+        &self.tests
+    }
+    pub(crate) fn set_tests(&mut self, tests: TestSuite) {
+        self.tests = tests;
+    }
+    // Names which can be passed to create_named, except the instances of
+    // parametric components like Reg<8>
+    pub fn component_names(&self) -> Vec<&str> {
+        let mut v: Vec<_> = self.comp_id.keys().map(|x| x.as_str()).filter(|x| !x.contains('<')).collect();
+        v.sort();
+        v
+    }
+    // A known component with a name similar to `name`, for "did you mean"
+    // hints when create_named fails
+    pub fn suggest_component(&self, name: &str) -> Option<String> {
+        suggest::did_you_mean(name, self.component_names())
     }
     pub fn create_named(&self, name: &str) -> Option<Box<dyn Component>> {
         info!("Creating component {}", name);
@@ -387,6 +1210,9 @@ impl ComponentFactory {
         */
 
         info!("Creating component with id {}: {}", c_id.0, name);
+        if let Some(t) = self.tables.get(&c_id) {
+            return Box::new(t.clone());
+        }
         let def = &self.comp_def[&c_id];
 
         let c_zero = CompIo::c_zero(inputs.len(), outputs.len());
@@ -400,17 +1226,16 @@ impl ComponentFactory {
             //assert!(&self.components[new_id].name != name);
             let (num_i, num_o) = def.generics[&local_id];
             let boxed_gate = if is_builtin(&self.components[&new_id].name) {
-                if let Some(c) = self.create_builtin(new_id, num_i, num_o) {
-                    info!("Created builtin gate {}", self.components[&new_id].name);
-                    c
-                } else {
-                    error!("Error creating builtin gate {}. Wrong number of inputs/outputs?", self.components[&new_id].name);
-                    panic!("Error creating builtin gate {}. Wrong number of inputs/outputs?", self.components[&new_id].name);
-                }
+                // The number of inputs and outputs was already checked by
+                // CompDefinition::new, so this cannot fail
+                let c = self.create_builtin(new_id, num_i, num_o)
+                    .expect("builtin arity checked in CompDefinition::new");
+                info!("Created builtin gate {}", self.components[&new_id].name);
+                c
             } else {
                 self.create(new_id)
             };
-            let x = CompIo::new(boxed_gate);
+            let x = CompIo::new(boxed_gate).with_label(def.labels[local_id].clone());
             c.push(x);
         }
 
@@ -499,106 +1324,95 @@ fn insert_special_components(components: &mut HashMap<CompId, CompInfo>,
     //i += 1;
 }
 
+// Components which are always defined
+pub const BUILTINS: [&str; 4] = ["Nand", "ConstantBit", "Stdin", "Stdout"];
+
 fn is_builtin(name: &str) -> bool {
-    ["Nand", "ConstantBit", "Stdin", "Stdout"].contains(&name)
+    BUILTINS.contains(&name)
 }
 
-// Line/column code taken from
-// https://github.com/gluon-lang/gluon/blob/f8326d21a14b5f21d203e9c43fa5bb7f0688a74c/base/src/source.rs
-struct Lines {
-    starting_bytes: Vec<usize>,
-    end: usize,
+// Valid number of inputs and outputs of the builtin gates, must be kept in
+// sync with ComponentFactory::create_builtin
+fn builtin_arity(name: &str, num_inputs: usize, num_outputs: usize) -> bool {
+    matches!((num_inputs, num_outputs, name),
+        (_, 1, "Nand") | (0, 3, "ConstantBit") | (1, 9, "Stdin") | (9, 0, "Stdout"))
 }
 
-impl Lines {
-    /// Creates a mapping for `src`
-    pub fn new<I>(src: I) -> Lines
-    where
-        I: IntoIterator<Item = u8>,
-    {
-        use std::iter;
+// Imports are not allowed, use project::parse_project to parse more than
+// one file
+pub fn parse_str(bs: &str) -> Result<ComponentFactory, CompileError> {
+    let no_files: HashMap<PathBuf, String> = HashMap::new();
+    project::parse_source(PathBuf::new(), None, bs.to_string(), &no_files)
+}
 
-        let mut len = 0;
-        let starting_bytes = {
-            let input_indices = src.into_iter()
-                .inspect(|_| len += 1)
-                .enumerate()
-                .filter(|&(_, b)| b == b'\n')
-                .map(|(i, _)| i + 1); // index of first char in the line
+// Only parse the source code, without resolving the components. Used by
+// the tools which work with a single file, like the formatter
+pub fn parse_ast(source: &str) -> Result<File, CompileError> {
+    parse_syntax(source).map_err(|d| {
+        let mut e = CompileError::new(d);
+        e.locate(&Lines::new(source.bytes()));
+        e
+    })
+}
 
-            iter::once(0).chain(input_indices).collect()
-        };
-        Lines {
-            starting_bytes,
-            end: len,
-        }
-    } 
-    /// Returns the byte offset of the start of `line_number`
-    pub fn line(&self, line_number: Line) -> Option<usize> {
-        let line_number = line_number.0 - 1;
-        self.starting_bytes.get(line_number).cloned()
-    }
-    /// Returns the line and column location of `byte`
-    pub fn location(&self, byte: usize) -> Option<Location> {
-        if byte <= self.end {
-            let line_index = self.line_number_at_byte(byte);
-
-            self.line(line_index).map(|line_byte| {
-                Location {
-                    line: line_index,
-                    column: Column(byte - line_byte + 1),
-                    absolute: byte,
-                }
-            })
-        } else {
-            None
-        }
+// All the syntax errors of the file, in the order they appear in the source
+pub(crate) fn parse_syntax(source: &str) -> Result<File, Vec<Diagnostic>> {
+    let mut errors = vec![];
+    let result = comphdl1::FileParser::new().parse(&mut errors, source);
+    let mut d: Vec<_> = errors.into_iter().map(|e| syntax_error(e.error, source.len())).collect();
+    match result {
+        Ok(ast) if d.is_empty() => return Ok(ast),
+        Ok(_) => {}
+        Err(e) => d.push(syntax_error(e, source.len())),
     }
-    /// Returns which line `byte` points to
-    pub fn line_number_at_byte(&self, byte: usize) -> Line {
-        let num_lines = self.starting_bytes.len();
+    d.sort_by_key(|x| x.span.map(|s| s.start));
 
-        Line(
-            (0..num_lines)
-                .filter(|&i| self.starting_bytes[i] > byte)
-                .map(|i| i - 1)
-                .next()
-                .unwrap_or(num_lines - 1) + 1
-        )
-    }
+    Err(d)
 }
 
-#[derive(Copy, Clone, Debug)]
-struct Line(usize);
-#[derive(Copy, Clone, Debug)]
-struct Column(usize);
-#[derive(Copy, Clone, Debug)]
-struct Location {
-    line: Line,
-    column: Column,
-    absolute: usize,
+// Used by the grammar for errors found in actions. The parser goes on with
+// a placeholder value, which is never used because the file has errors
+pub(crate) fn recover<T>(errors: &mut Vec<ErrorRecovery<usize, T, Diagnostic>>, message: String, span: Span) {
+    errors.push(ErrorRecovery {
+        error: ParseError::User {
+            error: Diagnostic::error(DiagnosticKind::Syntax { message }).with_span(Some(span))
+        },
+        dropped_tokens: vec![],
+    });
 }
 
-impl fmt::Display for Location {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "line {}, col {}", self.line.0, self.column.0)
-    }
+// Parametric components are only in comp_id once they are used, so the
+// templates are also candidates
+fn similar_component(comp_id: &HashMap<String, CompId>, templates: &[&str], name: &str) -> Option<String> {
+    let mut names: Vec<&str> = comp_id.keys().map(|x| x.as_str()).filter(|x| !x.contains('<')).collect();
+    names.extend(templates);
+    names.sort();
+    names.dedup();
+
+    suggest::did_you_mean(name, names)
 }
 
-pub fn parse_str(bs: &str) -> Result<ComponentFactory, String> {
-    let c = comphdl1::FileParser::new().parse(&bs);
+// eof: length of the source code, used to point at unexpected end of file
+pub(crate) fn syntax_error<T: fmt::Display>(e: ParseError<usize, T, Diagnostic>, eof: usize) -> Diagnostic {
+    let (message, span) = match e {
+        ParseError::InvalidToken { location } => {
+            ("invalid token".to_string(), Span::new(location, location))
+        }
+        ParseError::UnrecognizedToken { token: None, expected } => {
+            (format!("unexpected end of file, expected one of {}", expected.join(", ")),
+             Span::new(eof, eof))
+        }
+        ParseError::UnrecognizedToken { token: Some((l, t, r)), expected } => {
+            (format!("unexpected token `{}`, expected one of {}", t, expected.join(", ")),
+             Span::new(l, r))
+        }
+        ParseError::ExtraToken { token: (l, t, r) } => {
+            (format!("extra token `{}`", t), Span::new(l, r))
+        }
+        ParseError::User { error } => return error,
+    };
 
-    c.map_err(|e| {
-        let line_map = Lines::new(bs.bytes());
-        format!("{}", e.map_location(|x|
-            line_map.location(x).unwrap_or(
-                Location {
-                    line: Line(-1i8 as usize),
-                    column: Column(-1i8 as usize),
-                    absolute: -1i8 as usize
-                }
-        )))
-    }).and_then(|c| ComponentFactory::new(c))
+    Diagnostic::error(DiagnosticKind::Syntax { message }).with_span(Some(span))
 }
 
 #[test]
@@ -611,7 +1425,7 @@ fn compdef_test() {
     ];
     
     for x in d {
-        println!("{:#?}", comphdl1::CompDefParser::new().parse(x).unwrap());
+        println!("{:#?}", comphdl1::CompDefParser::new().parse(&mut vec![], x).unwrap());
     }
 }
 
@@ -632,7 +1446,7 @@ component Or3b(a, b, c) -> x {
 }
     "#;
 
-    let pd = comphdl1::FileParser::new().parse(d).unwrap();
+    let pd = comphdl1::FileParser::new().parse(&mut vec![], d).unwrap().components;
     let cf = ComponentFactory::new(pd);
     println!("{:#?}", cf);
     assert!(cf.is_err());
@@ -649,7 +1463,7 @@ component Or3(a, b, c) -> x {
     Or2(ab, c) -> x;
 }
     "#;
-    let pd = comphdl1::FileParser::new().parse(d).unwrap();
+    let pd = comphdl1::FileParser::new().parse(&mut vec![], d).unwrap().components;
     let cf = ComponentFactory::new(pd);
     println!("{:#?}", cf);
     assert!(cf.is_ok());
@@ -669,7 +1483,7 @@ component Or2(a, b, c) -> x {
 }
     "#;
 
-    let pd = comphdl1::FileParser::new().parse(d).unwrap();
+    let pd = comphdl1::FileParser::new().parse(&mut vec![], d).unwrap().components;
     let cf = ComponentFactory::new(pd);
     println!("{:#?}", cf);
     assert!(cf.is_err());
@@ -682,7 +1496,7 @@ component ArrayTest1D(a[3:0]) -> b[3:0] {
 }
     "#;
 
-    let pd = comphdl1::FileParser::new().parse(d).unwrap();
+    let pd = comphdl1::FileParser::new().parse(&mut vec![], d).unwrap().components;
     let cf = ComponentFactory::new(pd);
     println!("{:#?}", cf);
     let cf = cf.unwrap();
@@ -760,8 +1574,793 @@ component AssignNothingArray(a[3:0], b[3:0]) -> x[3:0] {
 }
     "#;
 
-    let pd = comphdl1::FileParser::new().parse(d).unwrap();
+    let pd = comphdl1::FileParser::new().parse(&mut vec![], d).unwrap().components;
     let cf = ComponentFactory::new(pd);
     println!("{:#?}", cf);
     assert!(!cf.is_err());
 }
+
+// The codes of the errors found in the source code, sorted
+#[cfg(test)]
+fn error_codes(source: &str) -> Vec<&'static str> {
+    let mut codes: Vec<_> = parse_str(source).unwrap_err().diagnostics.iter().map(|d| d.code()).collect();
+    codes.sort_unstable();
+    codes
+}
+
+#[test]
+fn diagnostics_instead_of_panics() {
+    let d = r#"
+component Dup(a, a) -> x {
+    Nand(a) -> x;
+}
+
+component Unbalanced(a[1:0]) -> x[3:0] {
+    x[3:0] = a[1:0];
+}
+
+component BadNand(a) -> x {
+    Nand(a) -> (x, y);
+}
+
+component NotFound(a) -> x {
+    Foo(a) -> x;
+}
+
+component UseDup(a) -> x {
+    Dup(a, a) -> x;
+}
+    "#;
+
+    // Dup is not reported as not found
+    assert_eq!(error_codes(d), vec!["E0002", "E0003", "E0007", "E0010"]);
+
+    let e = parse_str(d).unwrap_err();
+    let not_found = e.diagnostics.iter().find(|d| d.code() == "E0007").unwrap();
+    assert_eq!(not_found.component.as_ref().unwrap(), "NotFound");
+    let loc = not_found.location.unwrap();
+    assert_eq!((loc.line.0, loc.column.0), (15, 5));
+}
+
+#[test]
+fn syntax_error_location() {
+    let d = "component Or2(a, b) -> x {\n    Nand(a, b) -> x\n}";
+    let e = parse_str(d).unwrap_err();
+    assert_eq!(e.diagnostics.len(), 1);
+    assert_eq!(e.diagnostics[0].code(), "E0001");
+    let loc = e.diagnostics[0].location.unwrap();
+    assert_eq!((loc.line.0, loc.column.0), (3, 1));
+}
+
+#[test]
+fn syntax_error_recovery() {
+    // Every statement, header and test statement with an error is reported
+    let d = r#"
+component A(a) -> y {
+    Nand(a a) -> y;
+    Nand(a) -> w;
+    Nand(a, s: a) -> z;
+}
+
+component B(a, b c) -> y {
+    Nand(a, b) -> y;
+}
+
+component C(a) -> y {
+    x = 7;
+    Nand(a) -> y;
+}
+
+table T(a) -> y { 0 -> 1; 2 -> 0; }
+
+test "t" for C {
+    frob;
+    expect y = ;
+}
+
+component D(a) -> y {
+"#;
+    let e = parse_str(d).unwrap_err();
+    let found: Vec<_> = e.diagnostics.iter().map(|d| {
+        let loc = d.location.unwrap();
+        assert_eq!(d.code(), "E0001");
+        (loc.line.0, loc.column.0)
+    }).collect();
+    assert_eq!(found, vec![(3, 12), (5, 5), (8, 18), (13, 9), (17, 27), (20, 5), (21, 16), (25, 1)]);
+
+    // The formatter uses the same parser
+    let e = parse_ast(d).unwrap_err();
+    assert_eq!(e.diagnostics.len(), 8);
+}
+
+#[test]
+fn parametric_width() {
+    let d = r#"
+component DLatch(E, D) -> Q {
+    Nand(D, E) -> n_S;
+    Nand(n_S, E) -> n_R;
+    Nand(n_S, n_Q) -> Q;
+    Nand(n_R, Q) -> n_Q;
+}
+
+component Reg<N>(E, D[N-1:0]) -> Q[N-1:0] {
+    RegHalf<N/2>(E, D[N-1:N/2]) -> Q[N-1:N/2];
+    RegHalf<N/2>(E, D[N/2-1:0]) -> Q[N/2-1:0];
+}
+
+component RegHalf<N>(E, D[N-1:0]) -> Q[N-1:0] {
+    DLatch(E, D[0]) -> Q[0];
+    Buf<N-1>(D[N-1:1]) -> Q[N-1:1];
+}
+
+component Buf<N>(a[N-1:0]) -> x[N-1:0] {
+    x[N-1:0] = a[N-1:0];
+}
+
+component Reg8(E, D[7:0]) -> Q[7:0] {
+    Reg<8>(E, D[7:0]) -> Q[7:0];
+}
+
+component Reg16(E, D[15:0]) -> Q[15:0] {
+    Reg<2*8>(E, D[15:0]) -> Q[15:0];
+}
+    "#;
+
+    let cf = parse_str(d).unwrap();
+    let s = cf.create_named("Reg8").unwrap();
+    assert_eq!(s.num_inputs(), 9);
+    assert_eq!(s.num_outputs(), 8);
+    let s = cf.create_named("Reg16").unwrap();
+    assert_eq!(s.num_inputs(), 17);
+    // One definition per distinct parameter set
+    assert!(cf.create_named("Reg<8>").is_some());
+    assert!(cf.create_named("Reg<16>").is_some());
+    assert!(cf.create_named("RegHalf<4>").is_some());
+    assert!(cf.create_named("Reg<4>").is_none());
+}
+
+#[test]
+fn parametric_errors() {
+    let d = r#"
+component Buf<N>(a[N-1:0]) -> x[N-1:0] {
+    x[N-1:0] = a[N-1:0];
+}
+
+component WrongParams(a) -> x {
+    Buf<1, 2>(a) -> x;
+}
+
+component WrongWidth(a[3:0]) -> x[3:0] {
+    Buf<2>(a[3:0]) -> x[3:0];
+}
+
+component Negative(a) -> x {
+    Buf<0>() -> ();
+}
+
+component Undefined(a) -> x {
+    Buf<M>(a) -> x;
+}
+    "#;
+
+    // Buf<0> is not reported as not found
+    assert_eq!(error_codes(d), vec!["E0008", "E0009", "E0015", "E0016", "E0017"]);
+}
+
+#[test]
+fn recursive_templates() {
+    let d = r#"
+component Not(a) -> x {
+    Nand(a) -> x;
+}
+component Delay<N>(a) -> x {
+    if N > 0 {
+        Delay<N-1>(a) -> b;
+        Not(b) -> x;
+    } else {
+        Not(a) -> x;
+    }
+}
+component Top(a) -> x {
+    Delay<3>(a) -> x;
+}
+    "#;
+    let cf = parse_str(d).unwrap();
+    let c = cf.create_named("Top").unwrap();
+    let s = c.as_structural().unwrap();
+    let flat = s.flatten();
+    assert_eq!(flat.cells.len(), 1 + 4);
+
+    let codes = error_codes(r#"
+component Same<N>(a) -> x {
+    if N > 0 {
+        Same<N>(a) -> x;
+    } else {
+        Nand(a) -> x;
+    }
+}
+component Top(a) -> x {
+    Same<2>(a) -> x;
+}
+    "#);
+    assert_eq!(codes, vec!["E0006"]);
+
+    // Nothing stops the recursion
+    let d = r#"
+component Grow<N>(a) -> x {
+    Grow<N+1>(a) -> x;
+}
+component Top(a) -> x {
+    Grow<1>(a) -> x;
+}
+    "#;
+    assert_eq!(error_codes(d), vec!["E0036"]);
+    let e = parse_str(d).unwrap_err();
+    let d = &e.diagnostics[0];
+    assert_eq!(d.code(), "E0036");
+    assert_eq!(d.kind.to_string(), format!("parametric components are nested more than {} levels deep", MAX_ELABORATION_DEPTH));
+    assert_eq!(d.component.as_deref(), Some(&*format!("Grow<{}>", MAX_ELABORATION_DEPTH)));
+}
+
+#[test]
+fn generate_loops() {
+    let d = r#"
+component Buf(a) -> x {
+    a = x;
+}
+
+component Bufw<N>(a[N-1:0]) -> x[N-1:0] {
+    for i in 0..N {
+        Buf(a[i]) -> x[i];
+    }
+}
+
+component Chain(a[3:0]) -> x[3:0] {
+    // The first stage has no previous stage
+    for i in 0..4 {
+        if i == 0 {
+            Buf(a[0]) -> x[0];
+        } else if i < 3 {
+            Buf(x[i-1]) -> x[i];
+        } else {
+            Nand(x[i-1], a[i]) -> x[i];
+        }
+    }
+}
+
+component Bufw2(a[1:0]) -> x[1:0] {
+    Bufw<2>(a[1:0]) -> x[1:0];
+}
+
+component Nested(a[3:0]) -> x[15:0] {
+    for i in 0..4 {
+        for j in 0..4 {
+            Nand(a[i], a[j]) -> x[4*i+j];
+        }
+    }
+}
+    "#;
+
+    let cf = parse_str(d).unwrap();
+    let s = cf.create_named("Nested").unwrap();
+    assert_eq!(s.num_outputs(), 16);
+    let s = s.as_structural().unwrap();
+    assert_eq!(s.components.len(), 1 + 16);
+    let s = cf.create_named("Chain").unwrap();
+    assert_eq!(s.as_structural().unwrap().components.len(), 1 + 4);
+
+    // The loop body is expanded into the same calls as the handwritten ones
+    let pd = comphdl1::FileParser::new().parse(&mut vec![], d).unwrap().components;
+    let (all, errors) = elaborate(&pd);
+    assert!(errors.is_empty());
+    let (_, bufw) = all.iter().find(|(c, _)| c.name == "Bufw<2>").unwrap();
+    let calls: Vec<_> = bufw.iter().map(|st| match st {
+        Elaborated::Call(c) => c,
+        _ => panic!("expected a call"),
+    }).collect();
+    assert_eq!(calls[0].inputs, vec!["a$0"]);
+    assert_eq!(calls[0].outputs, vec!["x$0"]);
+    assert_eq!(calls[1].inputs, vec!["a$1"]);
+}
+
+#[test]
+fn array_2d() {
+    let d = r#"
+component Buf(a) -> x {
+    a = x;
+}
+
+component Row<N>(a[N-1:0]) -> x[N-1:0] {
+    for i in 0..N {
+        Buf(a[i]) -> x[i];
+    }
+}
+
+component Mem(mem[3:0][7:0]) -> (row[7:0], rows[15:0], bit) {
+    // Whole row slicing
+    Row<8>(mem[3]) -> row[7:0];
+    Row<16>(mem[1:0]) -> rows[15:0];
+    Buf(mem[2][5]) -> bit;
+}
+
+component Internal(a[7:0], b[7:0]) -> (x[7:0], y[7:0]) {
+    m[1][7:0] = a[7:0];
+    m[0][7:0] = b[7:0];
+    x[7:0] = m[1];
+    y[7:0] = m[0];
+}
+    "#;
+
+    let cf = parse_str(d).unwrap();
+    let s = cf.create_named("Mem").unwrap();
+    assert_eq!(s.num_inputs(), 32);
+    assert_eq!(s.num_outputs(), 8 + 16 + 1);
+    // Ports are flattened in row-major order
+    assert_eq!(&s.port_names().input[..3], &["mem$3$7", "mem$3$6", "mem$3$5"]);
+    assert_eq!(s.port_names().input[8], "mem$2$7");
+    assert!(cf.create_named("Internal").is_some());
+}
+
+#[test]
+fn array_dimension_mismatch() {
+    let d = r#"
+component A(a[3:0]) -> x {
+    Nand(a[1][0]) -> x;
+}
+
+component B(a[1:0]) -> x {
+    Nand(a) -> x;
+}
+    "#;
+
+    assert_eq!(error_codes(d), vec!["E0012", "E0037"]);
+}
+
+#[test]
+fn constants() {
+    let d = r#"
+component K(a) -> (x[7:0], y[3:0], z, n) {
+    x[7:0] = 8'hA5;
+    y[3:0] = 4'b1x_0;
+    z = 'x;
+    Nand(a, 1) -> n;
+}
+    "#;
+
+    use crate::bit::Bit::*;
+    let cf = parse_str(d).unwrap();
+    let mut s = cf.create_named("K").unwrap();
+    let mut out = vec![];
+    for _ in 0..4 {
+        out = s.update(&[H]);
+    }
+    assert_eq!(out, vec![H, L, H, L, L, H, L, H, L, H, X, L, X, L]);
+
+    let d = r#"
+component A(a) -> x {
+    1 = a;
+}
+    "#;
+    let e = parse_str(d).unwrap_err();
+    assert_eq!(e.diagnostics[0].kind, DiagnosticKind::DrivenConstant { constant: "1".into() });
+
+    for (d, message) in [
+        ("component A() -> x { x = 2; }", "single bit"),
+        ("component A() -> x[3:0] { x[3:0] = 4'h1F; }", "does not fit"),
+        ("component A() -> x[3:0] { x[3:0] = 4'b102; }", "invalid digit"),
+        ("component A(a) -> y { Nand(a, 99999999999'b1) -> y; }", "larger than 4096 bits"),
+        ("component A(a) -> y { Nand(a, 4097'b1) -> y; }", "larger than 4096 bits"),
+    ].iter() {
+        let e = parse_str(d).unwrap_err();
+        assert!(e.diagnostics[0].to_string().contains(message), "{}", e);
+    }
+    // The error points at the literal
+    let d = "component A(a) -> y { Nand(a, 99999999999'b1) -> y; }";
+    let e = parse_str(d).unwrap_err();
+    let span = e.diagnostics[0].span.unwrap();
+    assert_eq!(&d[span.start..span.end], "99999999999'b1");
+}
+
+#[test]
+fn named_ports() {
+    let d = r#"
+component Sub(a[1:0], b) -> (x[1:0], y) {
+    x[1:0] = a[1:0];
+    y = b;
+}
+component Top(p[1:0], r) -> (u[1:0], v) {
+    Sub(b: r, a: p[1:0]) -> (y: v, x: u[1:0]);
+}
+    "#;
+    use crate::bit::Bit::*;
+    let cf = parse_str(d).unwrap();
+    let mut s = cf.create_named("Top").unwrap();
+    let mut out = vec![];
+    for _ in 0..4 {
+        out = s.update(&[H, L, X]);
+    }
+    assert_eq!(out, vec![H, L, X]);
+
+    let d = r#"
+component Sub(a, b) -> x {
+    Nand(a, b) -> x;
+}
+component Top(p, q) -> (u, v, w) {
+    Sub(a: p, c: q) -> (x: u);
+    Sub(a: p, a: q, b: q) -> (x: v);
+    Sub(a: p[1:0], b: q) -> (x: w);
+}
+    "#;
+    assert_eq!(error_codes(d), vec!["E0023", "E0024", "E0025", "E0026"]);
+
+    let e = parse_str("component A(a, b) -> x { Nand(a, y: b) -> x; }").unwrap_err();
+    assert!(e.to_string().contains("cannot mix named and positional ports"));
+}
+
+#[test]
+fn typed_ast() {
+    use crate::ast::{Range, Statement};
+    let d = r#"
+component Buf(a[1:0]) -> x[1:0] {
+    // Copy the input
+    x[1:0] = (a[1], 0);
+    Nand(a[0]) -> _;
+}
+    "#;
+    let f = comphdl1::FileParser::new().parse(&mut vec![], d).unwrap();
+    let body = &f.components[0].body;
+    match &body[0] {
+        Statement::Comment(c) => assert_eq!(c.text, "// Copy the input"),
+        x => panic!("expected a comment, found {:?}", x),
+    }
+    match &body[1] {
+        Statement::Assignment(a) => {
+            assert_eq!(a.left.len(), 1);
+            assert_eq!(a.right.len(), 2);
+            match &a.right[0] {
+                Signal::Bits(b) => {
+                    assert!(matches!(b.ranges[0], Range::Bit(_)));
+                    assert_eq!(&d[b.span.start..b.span.end], "a[1]");
+                }
+                x => panic!("expected a signal, found {:?}", x),
+            }
+        }
+        x => panic!("expected an assignment, found {:?}", x),
+    }
+    assert!(matches!(body[2], Statement::Call(_)));
+}
+
+#[test]
+fn indirect_recursion() {
+    let d = r#"
+component A(a) -> x {
+    B(a) -> x;
+}
+component B(a) -> x {
+    for i in 0..1 {
+        C(a) -> x;
+    }
+}
+component C(a) -> x {
+    A(a) -> x;
+}
+component P<N>(a) -> x {
+    R(a) -> x;
+}
+component R(a) -> x {
+    P<3>(a) -> x;
+}
+component Ok(a) -> x {
+    A(a) -> x;
+    Missing(a) -> x;
+}
+    "#;
+    let e = parse_str(d).unwrap_err();
+    let chains: Vec<_> = e.diagnostics.iter().map(|d| d.kind.clone()).collect();
+    // The other errors are still reported
+    assert_eq!(chains, vec![
+        DiagnosticKind::RecursiveChain { chain: vec!["A".into(), "B".into(), "C".into(), "A".into()] },
+        DiagnosticKind::RecursiveChain { chain: vec!["R".into(), "P<3>".into(), "R".into()] },
+        DiagnosticKind::ComponentNotFound { name: "Missing".into() },
+    ]);
+    assert_eq!(e.diagnostics[0].component.as_deref(), Some("C"));
+    assert_eq!(e.diagnostics[0].location.unwrap().line.0, 11);
+
+    // Parametric components may use each other until a condition stops
+    // them, or the elaboration is too deep
+    let d = r#"
+component P<N>(a) -> x {
+    if N > 0 {
+        Q<N - 1>(a) -> x;
+    } else {
+        x = a;
+    }
+}
+component Q<N>(a) -> x {
+    P<N>(a) -> x;
+}
+component Top(a) -> x {
+    P<3>(a) -> x;
+}
+    "#;
+    let cf = parse_str(d).unwrap();
+    assert!(cf.create_named("Top").is_some());
+    let d = r#"
+component P<N>(a) -> x {
+    Q<N + 1>(a) -> x;
+}
+component Q<N>(a) -> x {
+    P<N>(a) -> x;
+}
+component Top(a) -> x {
+    P<0>(a) -> x;
+}
+    "#;
+    assert_eq!(error_codes(d), vec!["E0036"]);
+}
+
+#[test]
+fn did_you_mean() {
+    let d = r#"
+component Mux(sel, a[1:0]) -> y {
+    Nand(a[1], sel) -> y;
+}
+component Reg<N>(d[N-1:0]) -> q[N-1:0] {
+    q[N-1:0] = d[N-1:0];
+}
+component Top(s, x[1:0]) -> (y, q[7:0]) {
+    mux(sel: s, b: x[1:0]) -> (y: y);
+    Rg<8>(x[0], x[0], x[0], x[0], x[0], x[0], x[0], x[0]) -> q[7:0];
+    Nand(x[0]) -> tmp;
+    Nadn(tmq) -> u;
+}
+    "#;
+    let e = parse_str(d).unwrap_err();
+    let errors: Vec<_> = e.diagnostics.iter().map(|d| (d.kind.to_string(), d.help.clone())).collect();
+    let help = |x: &str| Some(format!("did you mean `{}`?", x));
+    assert_eq!(errors, vec![
+        ("component `mux` not found".to_string(), help("Mux")),
+        ("component `Rg` not found".to_string(), help("Reg")),
+        ("component `Nadn` not found".to_string(), None),
+    ]);
+    assert!(e.to_string().contains("\n    help: did you mean `Mux`?"));
+
+    let d = d.replace("mux(", "Mux(").replace("Rg<", "Reg<").replace("Nadn", "Nand");
+    let e = parse_str(&d).unwrap_err();
+    let errors: Vec<_> = e.diagnostics.iter().map(|d| (d.kind.to_string(), d.help.clone())).collect();
+    assert_eq!(errors, vec![
+        ("component `Mux` has no port named `b`".to_string(), help("a")),
+        ("port `a` of component `Mux` is not connected".to_string(), None),
+    ]);
+
+    let d = d.replace("b: x", "a: x");
+    let cf = parse_str(&d).unwrap();
+    assert!(cf.create_named("top").is_none());
+    assert_eq!(cf.suggest_component("top").as_deref(), Some("Top"));
+    assert_eq!(cf.suggest_component("Reg"), None);
+    assert_eq!(cf.component_names(), vec!["ConstantBit", "Mux", "Nand", "Stdin", "Stdout", "Top"]);
+    let undriven = cf.warnings().into_iter().find(|w| w.code() == "W0001").unwrap();
+    assert_eq!(undriven.help, help("tmp"));
+}
+
+#[test]
+fn expressions() {
+    let d = r#"
+component E(a, b, c, v[1:0], w[1:0]) -> (x, y, z, n, m[1:0], k[2:0]) {
+    x = (a & !b) | c;
+    y = a ^ b;
+    z = !(a & b);
+    n = !!a;
+    m[1:0] = v[1:0] & !w[1:0] | 2'b00;
+    k[2:0] = (a | 1, v[1:0] ^ w[1:0]);
+}
+    "#;
+    use crate::bit::Bit::{self, *};
+    let cf = parse_str(d).unwrap();
+    assert!(cf.warnings().is_empty());
+    let mut s = cf.create_named("E").unwrap();
+    let b = |x: bool| Bit::from_bool(x);
+    for i in 0..128u32 {
+        let t = |j: u32| (i >> j) & 1 == 1;
+        let (a, bb, c) = (t(0), t(1), t(2));
+        let (v, w) = ([t(3), t(4)], [t(5), t(6)]);
+        let input = vec![b(a), b(bb), b(c), b(v[0]), b(v[1]), b(w[0]), b(w[1])];
+        let mut out = vec![];
+        for _ in 0..8 {
+            out = s.update(&input);
+        }
+        let expected = vec![
+            b(a && !bb || c), b(a ^ bb), b(!(a && bb)), b(a),
+            b(v[0] && !w[0]), b(v[1] && !w[1]),
+            H, b(v[0] ^ w[0]), b(v[1] ^ w[1]),
+        ];
+        assert_eq!(out, expected, "{:?}", input);
+    }
+
+    // Double negations are removed, and !(a & b) is a single gate
+    let count = |d: &str| {
+        let cf = parse_str(d).unwrap();
+        let s = cf.create_named("C").unwrap();
+        s.as_structural().unwrap().components.len() - 1
+    };
+    assert_eq!(count("component C(a, b) -> x { x = !(a & b); }"), 1);
+    assert_eq!(count("component C(a, b) -> x { x = !!(a & b); }"), 2);
+    assert_eq!(count("component C(a, b) -> x { x = !a | !b; }"), 1);
+    assert_eq!(count("component C(a, b) -> x { x = a; }"), 0);
+
+    let d = "component W(a[3:0], b) -> x[3:0] { x[3:0] = a[3:0] & b; }";
+    assert_eq!(error_codes(d), vec!["E0030"]);
+    let e = parse_str(d).unwrap_err();
+    assert_eq!(e.diagnostics[0].to_string(),
+        "error[E0030]: operands of `&` have different widths: 4 and 1 bits (in component W, line 1, col 36)");
+    assert_eq!(error_codes("component W(a) -> x { (x, 1) = !a; }"), vec!["E0022"]);
+}
+
+#[test]
+fn doc_comments() {
+    use crate::emit_json;
+    let d = r#"
+// Comments are allowed outside of the definitions
+# like this one
+
+/// Inverts the input
+component Not(
+    /// Any signal
+    a, # not a doc comment
+) -> y {
+    Nand(a, a) -> y;
+}
+
+/// Not documented: the comment below is in between
+// plain comment
+component Top(x[1:0] /* two bits */) -> (
+    /// Inverted bits
+    /// of x
+    y[1:0],
+) {
+    Not(x[1]) -> y[1];
+    Not(x[0]) -> y[0];
+}
+    "#;
+    let cf = parse_str(d).unwrap();
+    let not = cf.create_named("Not").unwrap();
+    let info = &not.as_structural().unwrap().info;
+    assert_eq!(info.doc.as_deref(), Some("Inverts the input"));
+    assert_eq!(info.port_doc("a"), Some("Any signal"));
+    assert_eq!(info.port_doc("y"), None);
+    let top = cf.create_named("Top").unwrap();
+    let info = &top.as_structural().unwrap().info;
+    assert_eq!(info.doc, None);
+    assert_eq!(info.port_doc("y$1"), Some("Inverted bits\nof x"));
+    assert_eq!(info.port_doc("x$1"), None);
+
+    // The netlist has the docs as attributes
+    let json = emit_json::from_structural(not.as_structural().unwrap()).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(v["modules"]["Not"]["attributes"]["doc"], "Inverts the input");
+    assert_eq!(v["modules"]["Not"]["netnames"]["a"]["attributes"]["doc"], "Any signal");
+    assert!(v["modules"]["Not"]["netnames"].get("y").is_none());
+
+    let e = parse_str("component A(a b) -> y {}").unwrap_err();
+    assert_eq!(e.diagnostics[0].kind.to_string(), "expected `,` before port `b`");
+    let e = parse_str("component A(, a) -> y {}").unwrap_err();
+    assert_eq!(e.diagnostics[0].kind.to_string(), "expected a port before `,`");
+}
+
+#[test]
+fn instance_names() {
+    let d = r#"
+component Not(a) -> y {
+    Nand(a) -> y;
+}
+component Inner(a) -> y {
+    u_not: Not(a) -> y;
+}
+component Outer(a[1:0]) -> y[1:0] {
+    for i in 0..2 {
+        u_in: Inner(a[i]) -> y[i];
+    }
+}
+component Top(a[1:0]) -> (y[1:0], z) {
+    u_outer: Outer(a[1:0]) -> y[1:0];
+    Not(a[0]) -> z;
+}
+"#;
+    let cf = parse_str(d).unwrap();
+    let c = cf.create_named("Top").unwrap();
+    let s = c.as_structural().unwrap();
+    let labels: Vec<_> = s.components.iter().map(|c| c.label.clone()).collect();
+    assert_eq!(labels, vec![None, Some("u_outer".to_string()), None]);
+    assert_eq!(s.instance_path(&[1, 2, 1]).unwrap(), "u_outer.u_in[1].u_not");
+    assert_eq!(s.instance_path(&[2]).unwrap(), "Not-2");
+    assert_eq!(s.instance_path(&[3]), None);
+    assert_eq!(s.find_instance("u_outer.u_in[1].u_not"), Some(vec![1, 2, 1]));
+    assert_eq!(s.find_instance("u_outer.u_in[2]"), None);
+
+    // Used as the names of the Yosys cells and of the VCD scopes
+    let json = crate::emit_json::from_structural(s).unwrap();
+    assert!(json.contains(r#""u_outer":{"#), "{}", json);
+    assert!(json.contains(r#""$Not$input.v:1$2":{"#), "{}", json);
+    let mut vcd = vec![];
+    let mut c = c;
+    let inputs = vec![vec![Bit::L, Bit::H]];
+    crate::simulation::run_simulation(&mut vcd, &mut *c, &mut inputs.into_iter(), 1).unwrap();
+    let vcd = String::from_utf8(vcd).unwrap();
+    assert!(vcd.contains("$scope module u_outer $end"), "{}", vcd);
+    assert!(vcd.contains("$scope module u_in[0] $end"), "{}", vcd);
+    assert!(vcd.contains("$scope module Not-"), "{}", vcd);
+
+    let d = r#"
+component Dup(a) -> (x, y) {
+    u: Nand(a) -> x;
+    u: Nand(a) -> y;
+}
+"#;
+    let e = parse_str(d).unwrap_err();
+    let e = &e.diagnostics[0];
+    assert_eq!(e.kind, DiagnosticKind::DuplicateInstance { name: "u".to_string() });
+    assert_eq!(e.location.as_ref().unwrap().line.0, 4);
+}
+
+#[test]
+fn file_constants() {
+    let d = r#"
+const WIDTH = 4;
+const MSB = WIDTH - 1;
+
+component Inv<WIDTH>(a[WIDTH-1:0]) -> y[WIDTH-1:0] {
+    for i in 0..WIDTH {
+        Nand(a[i]) -> y[i];
+    }
+}
+component Top(a[MSB:0]) -> y[2*WIDTH-1:0] {
+    Inv<2>(a[1:0]) -> y[1:0];
+    Inv<WIDTH>(a[MSB:0]) -> y[WIDTH+MSB:WIDTH];
+    y[MSB:2] = a[MSB:2];
+}
+
+test "inverted" for Top {
+    set a[MSB:0] = 4'b0011;
+    step;
+    expect y[WIDTH+MSB:WIDTH] = 4'b1100;
+}
+"#;
+    let cf = parse_str(d).unwrap();
+    // The parameter shadows the constant
+    assert_eq!(cf.create_named("Inv<2>").unwrap().num_inputs(), 2);
+    let c = cf.create_named("Top").unwrap();
+    assert_eq!((c.num_inputs(), c.num_outputs()), (4, 8));
+    let results = cf.tests().run(&cf);
+    assert!(results[0].passed(), "{:?}", results[0].failures);
+
+    let errors = |d: &str| {
+        let e = parse_str(d).unwrap_err();
+        let mut found: Vec<_> = e.diagnostics.iter()
+            .map(|d| (d.location.unwrap().line.0, d.kind.to_string()))
+            .collect();
+        found.sort();
+        found
+    };
+    let d = r#"
+const A = B + 1;
+const N = 2;
+const N = 3;
+"#;
+    assert_eq!(errors(d), vec![
+        (2, "undefined constant or parameter `B`".to_string()),
+        (4, "constant `N` is defined more than once".to_string()),
+    ]);
+    let d = r#"
+const N = 2;
+component C(a[N-3:0]) -> y {
+    Nand(a[0]) -> y;
+}
+component D(a[M:0]) -> y {
+    Nand(a[0]) -> y;
+}
+"#;
+    assert_eq!(errors(d), vec![
+        (3, "array index `N-3` evaluates to -1, which is negative".to_string()),
+        (6, "undefined constant or parameter `M`".to_string()),
+    ]);
+}
@@ -0,0 +1,491 @@
+// Programs split into many files: import "alu.txt"; and use alu::Add8;
+//
+// Every imported file gets a namespace, the name of the file without the
+// extension, or the name given with import "alu-v2.txt" as alu;. The components defined in the root file keep their name, and
+// the components defined in an imported file are renamed to ns::Name, so
+// they can be passed to ComponentFactory like any other component.
+use crate::ast::{for_each_call_mut, CompDef, Env, File};
+use crate::comphdl1;
+use crate::diagnostic::{CompileError, Diagnostic, DiagnosticKind, Lines, Span};
+use crate::parser::{parse_syntax, ComponentFactory};
+use crate::suggest;
+use crate::testbench::TestSuite;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+// Reads the source code of the imported files
+pub trait SourceLoader {
+    fn load(&self, path: &Path) -> io::Result<String>;
+}
+
+// Read files from the filesystem
+pub struct FsLoader;
+
+impl SourceLoader for FsLoader {
+    fn load(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+}
+
+// In-memory files, used by the tests and by the web gui
+impl SourceLoader for HashMap<PathBuf, String> {
+    fn load(&self, path: &Path) -> io::Result<String> {
+        self.get(path).cloned().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "file not found")
+        })
+    }
+}
+
+pub fn parse_file(path: &Path) -> Result<ComponentFactory, CompileError> {
+    parse_project(path, &FsLoader)
+}
+
+pub fn parse_project(root: &Path, loader: &dyn SourceLoader) -> Result<ComponentFactory, CompileError> {
+    let root = normalize(root);
+    let source = loader.load(&root).map_err(|e| {
+        Diagnostic::error(DiagnosticKind::ImportError {
+            path: root.display().to_string(),
+            message: e.to_string(),
+        })
+    })?;
+    let name = root.display().to_string();
+
+    parse_source(root, Some(name), source, loader)
+}
+
+// Parse a file which is already loaded. Diagnostics in the root file only
+// include the file name if `name` is set
+pub(crate) fn parse_source(path: PathBuf, name: Option<String>, source: String,
+                           loader: &dyn SourceLoader) -> Result<ComponentFactory, CompileError> {
+    let mut p = Project { loader, files: vec![], by_path: HashMap::new(), namespaces: HashMap::new(), errors: vec![] };
+    p.add(path, name, None, source, &mut vec![]);
+    let defs = p.resolve();
+
+    let mut errors = std::mem::take(&mut p.errors);
+    let r = if errors.is_empty() {
+        ComponentFactory::new(defs).map_err(|e| {
+            errors.extend(e.diagnostics);
+        }).ok()
+    } else {
+        None
+    };
+
+    if let Some(mut cf) = r {
+        p.locate(cf.lints_mut());
+        // Only the tests of the root file are run
+        let root = &mut p.files[0];
+        let tests = std::mem::take(&mut root.ast.tests);
+        cf.set_tests(TestSuite::new(tests, root.name.clone(), root.lines.clone()));
+        return Ok(cf);
+    }
+
+    p.locate(&mut errors);
+
+    Err(CompileError::new(errors))
+}
+
+impl<'a> Project<'a> {
+    // Every diagnostic is located using the source code of its file
+    fn locate(&self, diagnostics: &mut [Diagnostic]) {
+        for d in diagnostics.iter_mut() {
+            let f = match d.component.as_ref().and_then(|c| c.find("::").map(|i| &c[..i])) {
+                _ if d.file.is_some() => self.files.iter().find(|f| f.name == d.file),
+                Some(ns) => self.namespaces.get(ns).map(|&(i, _)| &self.files[i]),
+                None => self.files.first(),
+            };
+            if let Some(f) = f {
+                d.file = f.name.clone();
+                d.locate(&f.lines);
+                for r in d.related.iter_mut() {
+                    if let Some(g) = self.files.iter().find(|g| g.name == r.file) {
+                        r.location = r.span.and_then(|s| g.lines.location(s.start));
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct SourceFile {
+    // Shown in the diagnostics
+    name: Option<String>,
+    // None for the root file
+    namespace: Option<String>,
+    lines: Lines,
+    ast: File,
+    // Namespaces imported by this file
+    imported: HashMap<String, usize>,
+}
+
+struct Project<'a> {
+    loader: &'a dyn SourceLoader,
+    files: Vec<SourceFile>,
+    by_path: HashMap<PathBuf, usize>,
+    // Namespace: (file, location of the first import)
+    namespaces: HashMap<String, (usize, Option<(usize, Span)>)>,
+    errors: Vec<Diagnostic>,
+}
+
+impl<'a> Project<'a> {
+    // Parse a file and all the files imported by it. `stack` contains the
+    // files being imported, to detect cycles
+    fn add(&mut self, path: PathBuf, name: Option<String>, namespace: Option<String>,
+           source: String, stack: &mut Vec<PathBuf>) -> usize {
+        let ast = match parse_syntax(&source) {
+            Ok(ast) => ast,
+            Err(v) => {
+                for mut d in v {
+                    d.file = name.clone();
+                    self.errors.push(d);
+                }
+                File::default()
+            }
+        };
+        let idx = self.files.len();
+        self.files.push(SourceFile {
+            name,
+            namespace,
+            lines: Lines::new(source.bytes()),
+            ast,
+            imported: HashMap::new(),
+        });
+        self.by_path.insert(path.clone(), idx);
+        stack.push(path.clone());
+
+        let imports = self.files[idx].ast.imports.clone();
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for import in imports {
+            let import_path = normalize(&dir.join(&import.path));
+            let error = |kind| {
+                Diagnostic::error(kind)
+                    .with_span(Some(import.span))
+            };
+
+            if let Some(pos) = stack.iter().position(|x| *x == import_path) {
+                let mut chain: Vec<_> = stack[pos..].iter().map(|x| x.display().to_string()).collect();
+                chain.push(import_path.display().to_string());
+                let d = error(DiagnosticKind::ImportCycle { chain });
+                self.push_error(d, idx);
+                continue;
+            }
+
+            let ns = import.namespace();
+            if comphdl1::WordParser::new().parse(&mut vec![], &ns).is_err() {
+                // my-lib.txt would be imported as my_lib
+                let alias: String = ns.chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect();
+                let alias = if alias.starts_with(|c: char| c.is_ascii_digit()) { format!("_{}", alias) } else { alias };
+                let help = format!("give it another name: `import \"{}\" as {};`", import.path, alias);
+                let d = error(DiagnosticKind::InvalidNamespace { namespace: ns }).with_help(Some(help));
+                self.push_error(d, idx);
+                continue;
+            }
+            let other = match self.namespaces.get(&ns) {
+                Some(&(other, first)) if self.by_path.get(&import_path) != Some(&other) => {
+                    let mut d = error(DiagnosticKind::NamespaceClash { namespace: ns.clone() });
+                    if let Some((file, span)) = first {
+                        let file = self.files[file].name.clone();
+                        d = d.with_related("first imported here", file.as_deref(), span);
+                    }
+                    self.push_error(d, idx);
+                    continue;
+                }
+                Some(&(other, _)) => other,
+                None => {
+                    // Diamond imports are only loaded once
+                    let source = match self.loader.load(&import_path) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            let d = error(DiagnosticKind::ImportError {
+                                path: import.path.clone(),
+                                message: e.to_string(),
+                            });
+                            self.push_error(d, idx);
+                            continue;
+                        }
+                    };
+                    self.namespaces.insert(ns.clone(), (usize::MAX, Some((idx, import.span))));
+                    let name = Some(import_path.display().to_string());
+                    let other = self.add(import_path, name, Some(ns.clone()), source, stack);
+                    self.namespaces.insert(ns.clone(), (other, Some((idx, import.span))));
+                    other
+                }
+            };
+            self.files[idx].imported.insert(ns, other);
+        }
+
+        stack.pop();
+
+        idx
+    }
+    fn push_error(&mut self, d: Diagnostic, file: usize) {
+        let mut d = d;
+        d.file = self.files[file].name.clone();
+        self.errors.push(d);
+    }
+    // Global name of a component defined in file `file`
+    fn qualified(&self, file: usize, name: &str) -> String {
+        match &self.files[file].namespace {
+            Some(ns) => format!("{}::{}", ns, name),
+            None => name.to_string(),
+        }
+    }
+    fn defines(&self, file: usize, name: &str) -> Option<Span> {
+        self.files[file].ast.components.iter().find(|c| c.name == name).map(|c| c.span)
+    }
+    // Rename all the components and calls to their global names
+    fn resolve(&mut self) -> Vec<CompDef> {
+        let mut defs = vec![];
+        for idx in 0..self.files.len() {
+            let mut aliases: HashMap<String, (String, Span)> = HashMap::new();
+            let uses = self.files[idx].ast.uses.clone();
+            let file_name = self.files[idx].name.clone();
+            for u in uses {
+                let error = |kind| {
+                    let mut d = Diagnostic::error(kind).with_span(Some(u.span));
+                    d.file = file_name.clone();
+                    d
+                };
+                let target = match self.files[idx].imported.get(&u.namespace) {
+                    Some(&t) => t,
+                    None => {
+                        let d = error(DiagnosticKind::UnknownNamespace { namespace: u.namespace.clone() });
+                        self.errors.push(d);
+                        continue;
+                    }
+                };
+                let qualified = self.qualified(target, &u.name);
+                if self.defines(target, &u.name).is_none() {
+                    let names = self.files[target].ast.components.iter().map(|c| c.name.as_str());
+                    let similar = suggest::did_you_mean(&u.name, names)
+                        .map(|x| suggest::help(&self.qualified(target, &x)));
+                    let d = error(DiagnosticKind::ComponentNotFound { name: qualified }).with_help(similar);
+                    self.errors.push(d);
+                    continue;
+                }
+                let previous = self.defines(idx, &u.name)
+                    .map(|s| ("defined here", s))
+                    .or_else(|| aliases.get(&u.name).map(|&(_, s)| ("previously imported here", s)));
+                if let Some((message, span)) = previous {
+                    let d = error(DiagnosticKind::Redefinition { name: u.name.clone() })
+                        .with_related(message, file_name.as_deref(), span);
+                    self.errors.push(d);
+                    continue;
+                }
+                aliases.insert(u.name.clone(), (qualified, u.span));
+            }
+
+            let consts = self.constants(idx);
+            let components = std::mem::take(&mut self.files[idx].ast.components);
+            for mut def in components.iter().cloned() {
+                def.consts = consts.clone();
+                let mut errors = vec![];
+                for_each_call_mut(&mut def.body, &mut |c| {
+                    match self.resolve_name(idx, &components, &aliases, &c.name) {
+                        Ok(Some(x)) => c.name = x,
+                        Ok(None) => {}
+                        Err(k) => errors.push(Diagnostic::error(k).with_span(Some(c.span))),
+                    }
+                });
+                def.name = self.qualified(idx, &def.name);
+                for e in errors {
+                    let e = e.in_component(&def.name);
+                    self.push_error(e, idx);
+                }
+                defs.push(def);
+            }
+            // Tests of unknown components fail when they are run
+            let mut tests = std::mem::take(&mut self.files[idx].ast.tests);
+            for t in tests.iter_mut() {
+                t.consts = consts.clone();
+                if let Ok(Some(x)) = self.resolve_name(idx, &components, &aliases, &t.component) {
+                    t.component = x;
+                }
+            }
+            self.files[idx].ast.tests = tests;
+            self.files[idx].ast.components = components;
+        }
+
+        defs
+    }
+    // Evaluate the constants of a file in order, each one can use the
+    // previous ones
+    fn constants(&mut self, idx: usize) -> Env {
+        let mut env = Env::new();
+        let consts = self.files[idx].ast.consts.clone();
+        for c in consts {
+            let kind = if env.contains_key(&c.name) {
+                DiagnosticKind::DuplicateConstant { name: c.name.clone() }
+            } else {
+                match c.value.eval(&env) {
+                    Ok(x) => {
+                        env.insert(c.name, x);
+                        continue;
+                    }
+                    Err(k) => k,
+                }
+            };
+            let d = Diagnostic::error(kind).with_span(Some(c.span));
+            self.push_error(d, idx);
+        }
+
+        env
+    }
+    // Global name of a component used in file `idx`. Builtin gates and
+    // missing components keep their name
+    fn resolve_name(&self, idx: usize, components: &[CompDef], aliases: &HashMap<String, (String, Span)>,
+                    name: &str) -> Result<Option<String>, DiagnosticKind> {
+        if let Some(i) = name.find("::") {
+            let (ns, name) = (&name[..i], &name[i + 2..]);
+            if self.files[idx].namespace.as_deref() == Some(ns) {
+                Ok(Some(self.qualified(idx, name)))
+            } else if let Some(&t) = self.files[idx].imported.get(ns) {
+                Ok(Some(self.qualified(t, name)))
+            } else {
+                Err(DiagnosticKind::UnknownNamespace { namespace: ns.to_string() })
+            }
+        } else if components.iter().any(|d| d.name == name) {
+            Ok(Some(self.qualified(idx, name)))
+        } else {
+            Ok(aliases.get(name).map(|(x, _)| x.clone()))
+        }
+    }
+}
+
+// Remove . and .. from a path, without accessing the filesystem
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut v: Vec<Component> = vec![];
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir if matches!(v.last(), Some(Component::Normal(_))) => {
+                v.pop();
+            }
+            c => v.push(c),
+        }
+    }
+
+    v.iter().collect()
+}
+
+#[cfg(test)]
+fn files(v: &[(&str, &str)]) -> HashMap<PathBuf, String> {
+    v.iter().map(|(path, source)| (PathBuf::from(path), source.to_string())).collect()
+}
+
+#[test]
+fn import_and_use() {
+    let loader = files(&[
+        ("main.txt", r#"
+import "lib/gates.txt";
+use gates::Not;
+component Main(a, b) -> (x, y) {
+    Not(a) -> x;
+    gates::Or2(a, b) -> y;
+}
+"#),
+        ("lib/gates.txt", r#"
+import "../lib/./more.txt";
+component Not(a) -> x {
+    Nand(a) -> x;
+}
+component Or2(a, b) -> x {
+    Not(a) -> n_a;
+    more::Not2(b) -> n_b;
+    Nand(n_a, n_b) -> x;
+}
+"#),
+        ("lib/more.txt", r#"
+component Not2(a) -> x {
+    Nand(a) -> x;
+}
+"#),
+    ]);
+    let cf = parse_project(Path::new("main.txt"), &loader).unwrap();
+    assert!(cf.create_named("Main").is_some());
+    assert!(cf.create_named("gates::Or2").is_some());
+    assert!(cf.create_named("more::Not2").is_some());
+    assert!(cf.create_named("Or2").is_none());
+}
+
+#[test]
+fn import_errors() {
+    let loader = files(&[
+        ("a.txt", r#"
+import "b.txt";
+import "missing.txt";
+use c::Foo;
+use b::Missing;
+component Main(a) -> x {
+    b::Buf(a) -> x;
+}
+"#),
+        ("b.txt", r#"
+import "a.txt";
+component Buf(a) -> x {
+    x = a;
+}
+"#),
+    ]);
+    let e = parse_project(Path::new("a.txt"), &loader).unwrap_err();
+    let codes: Vec<_> = e.diagnostics.iter().map(|d| d.code()).collect();
+    assert_eq!(codes, vec!["E0019", "E0018", "E0021", "E0007"]);
+    assert_eq!(e.diagnostics[0].kind, DiagnosticKind::ImportCycle {
+        chain: vec!["a.txt".into(), "b.txt".into(), "a.txt".into()],
+    });
+    assert_eq!(e.diagnostics[0].file.as_deref(), Some("b.txt"));
+    assert_eq!(e.diagnostics[0].location.unwrap().line.0, 2);
+}
+
+#[test]
+fn import_alias() {
+    let loader = files(&[
+        ("main.txt", r#"
+import "lib/my-gates.txt" as gates;
+import "lib/my-gates.txt";
+import "lib/2.txt";
+use gates::Not;
+component Main(a) -> x {
+    Not(a) -> x;
+}
+"#),
+        ("lib/my-gates.txt", "component Not(a) -> x { Nand(a) -> x; }"),
+        ("lib/2.txt", "component Buf(a) -> x { x = a; }"),
+    ]);
+    let e = parse_project(Path::new("main.txt"), &loader).unwrap_err();
+    let codes: Vec<_> = e.diagnostics.iter().map(|d| d.code()).collect();
+    assert_eq!(codes, vec!["E0038", "E0038"]);
+    assert_eq!(e.diagnostics[0].to_string().lines().next().unwrap(),
+               "error[E0038]: `my-gates` cannot be used as a namespace (main.txt, line 3, col 1)");
+    assert_eq!(e.diagnostics[0].help.as_deref(), Some("give it another name: `import \"lib/my-gates.txt\" as my_gates;`"));
+    assert_eq!(e.diagnostics[1].help.as_deref(), Some("give it another name: `import \"lib/2.txt\" as _2;`"));
+
+    let mut loader = loader;
+    loader.insert(PathBuf::from("main.txt"), "import \"lib/my-gates.txt\" as gates;\nuse gates::Not;".to_string());
+    let cf = parse_project(Path::new("main.txt"), &loader).unwrap();
+    assert!(cf.create_named("gates::Not").is_some());
+}
+
+#[test]
+fn use_redefinition() {
+    let loader = files(&[
+        ("main.txt", r#"
+import "x/alu.txt";
+import "y/alu.txt";
+use alu::Add;
+use alu::Add;
+component Add(a) -> x {
+    x = a;
+}
+"#),
+        ("x/alu.txt", "component Add(a) -> x { x = a; }"),
+        ("y/alu.txt", "component Add(a) -> x { x = a; }"),
+    ]);
+    let e = parse_project(Path::new("main.txt"), &loader).unwrap_err();
+    let codes: Vec<_> = e.diagnostics.iter().map(|d| d.code()).collect();
+    assert_eq!(codes, vec!["E0020", "E0005", "E0005"]);
+    assert_eq!(e.diagnostics[0].related[0].location.unwrap().line.0, 2);
+    assert_eq!(e.diagnostics[1].related[0].message, "defined here");
+    assert_eq!(e.diagnostics[2].related[0].message, "defined here");
+}
@@ -1,8 +1,85 @@
 use vcd::{ self, Value, TimescaleUnit, SimulationCommand };
 use crate::bit::Bit;
-use crate::component::Component;
+use crate::component::{Component, Structural};
+use crate::flatten::{find_prims, structural};
+use std::fmt;
 use std::io;
 
+// settle watches the components during at most this many of the last
+// updates, to find the ones which keep changing
+const OSCILLATION_WINDOW: usize = 64;
+
+// The outputs did not settle after `updates` updates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Oscillation {
+    pub updates: usize,
+    // Instance path of the primitives whose outputs changed during the last
+    // updates: Clk2-1.Nand-1
+    pub components: Vec<String>,
+    // The outputs after the last update
+    pub output: Vec<Bit>,
+}
+
+impl fmt::Display for Oscillation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the component did not settle after {} updates", self.updates)?;
+        if !self.components.is_empty() {
+            write!(f, ", these components keep changing: {}", self.components.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Structural {
+    // Update until the signals stop changing, at most `max_iters` times, and
+    // return the stable outputs
+    pub fn settle(&mut self, input: &[Bit], max_iters: usize) -> Result<Vec<Bit>, Oscillation> {
+        // Already settled with this input, even if no updates are allowed
+        if !self.needs_update() && self.components[0].output == input {
+            return Ok(self.output());
+        }
+        // Everything changes during the first updates, so only the second
+        // half is watched
+        let window = (max_iters / 2).clamp(1, OSCILLATION_WINDOW);
+        let watch = max_iters.saturating_sub(window);
+        let mut prims = vec![];
+        let mut last = vec![];
+        let mut toggled = vec![];
+        let mut output = self.output();
+        for i in 0..max_iters {
+            if i == watch {
+                find_prims(self, &mut vec![], &mut prims);
+                last = prim_outputs(self, &prims);
+                toggled = vec![false; prims.len()];
+            }
+            output = self.update(input);
+            if !self.needs_update() {
+                return Ok(output);
+            }
+            if i >= watch {
+                let now = prim_outputs(self, &prims);
+                for (t, (a, b)) in toggled.iter_mut().zip(last.iter().zip(&now)) {
+                    *t |= a != b;
+                }
+                last = now;
+            }
+        }
+        let components = prims.iter().zip(&toggled)
+            .filter(|(_, &t)| t)
+            .map(|(path, _)| self.instance_path(path).unwrap())
+            .collect();
+
+        Err(Oscillation { updates: max_iters, components, output })
+    }
+}
+
+fn prim_outputs(top: &Structural, prims: &[Vec<usize>]) -> Vec<Vec<Bit>> {
+    prims.iter().map(|path| {
+        let (&c_id, parent) = path.split_last().unwrap();
+        structural(top, parent).components[c_id].output.clone()
+    }).collect()
+}
+
 pub fn run_simulation(w: &mut dyn io::Write,
                   c: &mut dyn Component,
                   inputs: &mut dyn Iterator<Item=Vec<Bit>>,
@@ -52,3 +129,47 @@ pub fn run_simulation(w: &mut dyn io::Write,
     Ok(())
 }
 
+
+#[test]
+fn settle_or_oscillate() {
+    use crate::bit::Bit::*;
+    use crate::parser::parse_str;
+    let d = r#"
+component Not(a) -> y {
+    Nand(a) -> y;
+}
+component Chain(a) -> y {
+    Not(a) -> b;
+    Not(b) -> c;
+    Not(c) -> y;
+}
+component Clk2(a) -> x {
+    Nand(a, x) -> x;
+}
+component Blink(en) -> (y, b) {
+    Chain(en) -> b;
+    u_clk: Clk2(en) -> y;
+}
+"#;
+    let cf = parse_str(d).unwrap();
+    let mut chain = cf.create_named("Chain").unwrap().clone_as_structural().unwrap();
+    assert_eq!(chain.settle(&[L], 10), Ok(vec![H]));
+    assert_eq!(chain.settle(&[H], 10), Ok(vec![L]));
+    assert_eq!(chain.settle(&[H], 0), Ok(vec![L]));
+    let e = chain.settle(&[L], 0).unwrap_err();
+    assert_eq!((e.updates, e.output), (0, vec![L]));
+    // Not enough updates to go through the chain
+    let mut chain = cf.create_named("Chain").unwrap().clone_as_structural().unwrap();
+    let e = chain.settle(&[L], 2).unwrap_err();
+    assert_eq!(e.components, vec!["Not-2.Nand-1"]);
+
+    let mut blink = cf.create_named("Blink").unwrap().clone_as_structural().unwrap();
+    assert_eq!(blink.settle(&[L], 100), Ok(vec![H, H]));
+    let e = blink.settle(&[H], 100).unwrap_err();
+    assert_eq!(e.updates, 100);
+    assert_eq!(e.components, vec!["u_clk.Nand-1"]);
+    assert_eq!(e.to_string(), "the component did not settle after 100 updates, \
+        these components keep changing: u_clk.Nand-1");
+    // The chain is stable while the clock toggles
+    assert_eq!(e.output[1], L);
+}
@@ -0,0 +1,59 @@
+// "Did you mean" hints: find the known name which is closest to a name
+// that was not found, so typos can be reported with the likely fix
+
+// Levenshtein distance, ignoring case: `mux` and `Mux` are at distance 0
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().flat_map(char::to_lowercase).collect();
+    let b: Vec<char> = b.chars().flat_map(char::to_lowercase).collect();
+    // Only two rows of the table are needed
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, x) in a.iter().enumerate() {
+        cur[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let substitution = prev[j] + if x == y { 0 } else { 1 };
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    prev[b.len()]
+}
+
+// The closest candidate, if it is close enough to be a typo. Long names
+// allow more edits than short ones. On ties the first candidate wins
+pub fn did_you_mean<'a, I>(name: &str, candidates: I) -> Option<String>
+    where I: IntoIterator<Item=&'a str>
+{
+    let max = std::cmp::max(1, name.chars().count() / 3);
+    let mut best: Option<(usize, &str)> = None;
+    for c in candidates {
+        if c == name {
+            continue;
+        }
+        let d = edit_distance(name, c);
+        if d <= max && best.map(|(b, _)| d < b).unwrap_or(true) {
+            best = Some((d, c));
+        }
+    }
+
+    best.map(|(_, c)| c.to_string())
+}
+
+// The message shown after a diagnostic
+pub fn help(suggestion: &str) -> String {
+    format!("did you mean `{}`?", suggestion)
+}
+
+#[test]
+fn suggestions() {
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    assert_eq!(edit_distance("", "abc"), 3);
+    assert_eq!(edit_distance("Mux", "mux"), 0);
+    let names = ["Nand", "Mux_4_1", "Or2", "Xor2", "And2"];
+    assert_eq!(did_you_mean("Nnad", names.iter().cloned()), None);
+    assert_eq!(did_you_mean("Mux_41", names.iter().cloned()), Some("Mux_4_1".to_string()));
+    assert_eq!(did_you_mean("nand", names.iter().cloned()), Some("Nand".to_string()));
+    assert_eq!(did_you_mean("Or3", names.iter().cloned()), Some("Or2".to_string()));
+    assert_eq!(did_you_mean("Adder", names.iter().cloned()), None);
+}
@@ -0,0 +1,295 @@
+// Truth tables: combinational components defined by a list of rows. They
+// are simulated by lookup, and lowered to Nand gates to emit netlists
+use crate::ast::Table;
+use crate::bit::Bit;
+use crate::component::{Component, CompIo, Index, Nand, PortNames, Structural};
+use crate::diagnostic::{Diagnostic, DiagnosticKind};
+use crate::parser::CompInfo;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// The lookup table has 2^inputs entries
+pub const MAX_INPUTS: usize = 16;
+
+// Inputs and outputs of a row, an X input matches both values
+type Row = (Vec<Bit>, Vec<Bit>);
+
+// Input literal of a product term: input index and value
+type Literal = (usize, bool);
+
+#[derive(Debug, Clone)]
+pub struct TruthTable {
+    info: Rc<CompInfo>,
+    rows: Rc<Vec<Row>>,
+    // Outputs for every value of the inputs, the first input is the most
+    // significant bit of the index. All X when no row matches
+    lookup: Rc<Vec<Vec<Bit>>>,
+}
+
+impl TruthTable {
+    // The rows must have one bit for each port bit
+    pub(crate) fn compile(info: &CompInfo, table: &Table) -> Result<Self, Vec<Diagnostic>> {
+        let error = |kind, span| Diagnostic::error(kind).in_component(&info.name).with_span(span);
+        let (n_in, n_out) = (info.inputs.len(), info.outputs.len());
+        if n_in > MAX_INPUTS {
+            let kind = DiagnosticKind::TableTooLarge { inputs: n_in, max: MAX_INPUTS };
+            return Err(vec![error(kind, info.span)]);
+        }
+        let mut errors = vec![];
+        for row in &table.rows {
+            for &(side, bits, expected) in &[("input", &row.inputs, n_in), ("output", &row.outputs, n_out)] {
+                if bits.len() != expected {
+                    let kind = DiagnosticKind::TableRowWidth {
+                        side: side.to_string(), expected, found: bits.len()
+                    };
+                    errors.push(error(kind, Some(row.span)));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let rows = table.rows.iter().map(|r| (r.inputs.clone(), r.outputs.clone())).collect();
+
+        Ok(Self::new(Rc::new(info.clone()), rows))
+    }
+    pub fn new(info: Rc<CompInfo>, rows: Vec<Row>) -> Self {
+        let n_in = info.inputs.len();
+        // The first matching row wins
+        let lookup = (0..1usize << n_in).map(|i| {
+            rows.iter().find(|(inputs, _)| matches(inputs, i))
+                .map(|(_, outputs)| outputs.clone())
+                .unwrap_or_else(|| vec![Bit::X; info.outputs.len()])
+        }).collect();
+
+        Self { info, rows: Rc::new(rows), lookup: Rc::new(lookup) }
+    }
+    // Equivalent Nand circuit, as a sum of products. Each row which drives
+    // an output to 1 is a product term, but if an earlier row takes some of
+    // its values, the remaining values are used as separate terms.
+    // Unknown outputs are driven to 0
+    pub fn to_nand(&self) -> Structural {
+        let n_in = self.num_inputs();
+        let n_out = self.num_outputs();
+        let mut terms: Vec<Vec<Vec<Literal>>> = vec![vec![]; n_out];
+        let mut taken = vec![false; 1 << n_in];
+        for (inputs, outputs) in self.rows.iter() {
+            let values: Vec<usize> = (0..1usize << n_in).filter(|&i| matches(inputs, i)).collect();
+            let won: Vec<usize> = values.iter().cloned().filter(|&i| !taken[i]).collect();
+            let row_terms: Vec<Vec<Literal>> = if won.len() == values.len() {
+                vec![inputs.iter().enumerate().filter_map(|(i, b)| match b {
+                    Bit::L => Some((i, false)),
+                    Bit::H => Some((i, true)),
+                    Bit::X => None,
+                }).collect()]
+            } else {
+                won.iter().map(|&v| (0..n_in).map(|i| (i, input_bit(v, i, n_in))).collect()).collect()
+            };
+            for &v in &won {
+                taken[v] = true;
+            }
+            for (j, b) in outputs.iter().enumerate() {
+                if *b == Bit::H {
+                    terms[j].extend(row_terms.iter().cloned());
+                }
+            }
+        }
+
+        // The inverted inputs and the product terms are shared by all the
+        // outputs
+        let mut c = vec![CompIo::c_zero(n_in, n_out)];
+        let mut inverted: HashMap<usize, usize> = HashMap::new();
+        let mut products: HashMap<Vec<Literal>, usize> = HashMap::new();
+        for (j, terms) in terms.iter().enumerate() {
+            let mut sources = vec![];
+            for term in terms {
+                let id = match products.get(term) {
+                    Some(&id) => id,
+                    None => {
+                        let mut literals = vec![];
+                        for &(i, value) in term {
+                            if value {
+                                literals.push((0, i));
+                            } else {
+                                let id = match inverted.get(&i) {
+                                    Some(&id) => id,
+                                    None => gate(&mut c, &[(0, i)]),
+                                };
+                                inverted.insert(i, id);
+                                literals.push((id, 0));
+                            }
+                        }
+                        gate(&mut c, &literals)
+                    }
+                };
+                products.insert(term.clone(), id);
+                if !sources.contains(&(id, 0)) {
+                    sources.push((id, 0));
+                }
+            }
+            // Nand of the inverted terms: an output without terms is 0
+            let id = gate(&mut c, &sources);
+            c[id].add_connection(0, Index::new(0, j));
+        }
+
+        Structural::new(c, Rc::clone(&self.info))
+    }
+}
+
+// Value of input i in the lookup index
+fn input_bit(index: usize, i: usize, n_in: usize) -> bool {
+    (index >> (n_in - 1 - i)) & 1 == 1
+}
+
+fn matches(inputs: &[Bit], index: usize) -> bool {
+    inputs.iter().enumerate().all(|(i, b)| match b {
+        Bit::L => !input_bit(index, i, inputs.len()),
+        Bit::H => input_bit(index, i, inputs.len()),
+        Bit::X => true,
+    })
+}
+
+// Add a Nand gate connected to the given outputs, returns its index
+fn gate(c: &mut Vec<CompIo>, sources: &[(usize, usize)]) -> usize {
+    let id = c.len();
+    c.push(CompIo::new(Box::new(Nand::new(sources.len()))));
+    for (k, &(from, port)) in sources.iter().enumerate() {
+        c[from].add_connection(port, Index::new(id, k));
+    }
+
+    id
+}
+
+impl Component for TruthTable {
+    fn update(&mut self, input: &[Bit]) -> Vec<Bit> {
+        assert_eq!(input.len(), self.num_inputs());
+        let mut index = 0;
+        let mut unknown = vec![];
+        for (i, b) in input.iter().enumerate() {
+            index <<= 1;
+            match b {
+                Bit::L => {}
+                Bit::H => index |= 1,
+                Bit::X => unknown.push(input.len() - 1 - i),
+            }
+        }
+        // With X inputs, an output is only known if it has the same value
+        // for all the values of the X inputs
+        let mut out = self.lookup[index].clone();
+        for k in 1..1usize << unknown.len() {
+            let mut other = index;
+            for (b, &pos) in unknown.iter().enumerate() {
+                if (k >> b) & 1 == 1 {
+                    other |= 1 << pos;
+                }
+            }
+            for (o, x) in out.iter_mut().zip(&self.lookup[other]) {
+                if o != x {
+                    *o = Bit::X;
+                }
+            }
+        }
+
+        out
+    }
+    fn needs_update(&self) -> bool {
+        false // The output depends only on the inputs
+    }
+    fn num_inputs(&self) -> usize {
+        self.info.inputs.len()
+    }
+    fn num_outputs(&self) -> usize {
+        self.info.outputs.len()
+    }
+    fn name(&self) -> &str {
+        &self.info.name
+    }
+    fn port_names(&self) -> PortNames {
+        PortNames::new_vec(self.info.inputs.clone(), self.info.outputs.clone())
+    }
+    fn clone_as_structural(&self) -> Option<Structural> {
+        Some(self.to_nand())
+    }
+    fn lower(&self) -> Option<Structural> {
+        Some(self.to_nand())
+    }
+    fn box_clone(&self) -> Box<dyn Component> {
+        Box::new((*self).clone())
+    }
+}
+
+#[test]
+fn truth_tables() {
+    use crate::emit_json;
+    use crate::parser::parse_str;
+    let d = r#"
+table Prio(a[3:0]) -> (y[1:0], valid) {
+    // Priority encoder, the first matching row wins
+    1xxx -> 111;
+    01-- -> 101; # same as 01xx
+    0010 -> 011;
+    0001 -> 001;
+    0000 -> xx0;
+}
+table Partial(a, b) -> x {
+    11 -> 1;
+}
+component Top(a[3:0]) -> (y[1:0], valid, p) {
+    Prio(a[3:0]) -> (y[1:0], valid);
+    Partial(a[1], a[0]) -> p;
+}
+    "#;
+    let cf = parse_str(d).unwrap();
+    assert!(cf.warnings().is_empty());
+    let bits = |s: &str| crate::ast::table_bits(s).unwrap();
+    let mut c = cf.create_named("Prio").unwrap();
+    assert_eq!(c.update(&bits("0110")), bits("101"));
+    assert_eq!(c.update(&bits("1000")), bits("111"));
+    assert_eq!(c.update(&bits("0000")), bits("xx0"));
+    // Known as long as all the values of the X inputs agree
+    assert_eq!(c.update(&bits("01x0")), bits("101"));
+    assert_eq!(c.update(&bits("x100")), bits("1x1"));
+
+    // The Nand circuit has the same outputs, with 0 instead of X
+    let mut nand = c.clone_as_structural().unwrap();
+    for i in 0..16u8 {
+        let input = Bit::from_u8(i)[4..].to_vec();
+        let expected: Vec<_> = c.update(&input).into_iter()
+            .map(|b| if b == Bit::X { Bit::L } else { b })
+            .collect();
+        // Inverters, product terms and outputs: three gate delays
+        for _ in 0..3 {
+            nand.update(&input);
+        }
+        assert_eq!(nand.update(&input), expected);
+    }
+    let mut p = cf.create_named("Partial").unwrap();
+    assert_eq!(p.update(&bits("11")), bits("1"));
+    assert_eq!(p.update(&bits("10")), bits("x"));
+
+    // Each table is a module of the netlist
+    let top = cf.create_named("Top").unwrap();
+    let json = emit_json::from_structural(top.as_structural().unwrap()).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&json).unwrap();
+    let modules = v["modules"].as_object().unwrap();
+    let mut names: Vec<_> = modules.keys().collect();
+    names.sort();
+    assert_eq!(names, vec!["Partial", "Prio", "Top"]);
+    assert!(modules["Prio"]["cells"].as_object().unwrap().keys().all(|x| x.starts_with("$Nand$")));
+
+    let d = r#"
+table T(a, b) -> x {
+    1 -> 1;
+    11 -> 10;
+}
+table U(a[16:0]) -> x {}
+    "#;
+    let e = parse_str(d).unwrap_err();
+    let errors: Vec<_> = e.diagnostics.iter().map(|d| (d.code(), d.kind.to_string())).collect();
+    assert_eq!(errors, vec![
+        ("E0028", "truth table row has 1 input bits, but the component has 2".to_string()),
+        ("E0028", "truth table row has 2 output bits, but the component has 1".to_string()),
+        ("E0029", "truth table has 17 inputs, but at most 16 are supported".to_string()),
+    ]);
+    assert!(parse_str("table T(a) -> x { 2 -> 1; }").is_err());
+}
@@ -0,0 +1,260 @@
+// Test blocks: drive a component with `set` and `step`, and check its
+// outputs with `expect`:
+//
+// test "select a" for Mux_4_1 {
+//     set s1 = 0, s0 = 0, a = 1, b = 0, c = 0, d = 0;
+//     step;
+//     expect y = 1;
+// }
+//
+// The inputs which were never set are X
+use crate::ast::{expand_bits, Env, PortValue, TestDef, TestStatement};
+use crate::bit::Bit;
+use crate::diagnostic::{Diagnostic, DiagnosticKind, Lines};
+use crate::parser::ComponentFactory;
+use crate::suggest;
+
+// A step fails if the component is still changing after this many updates
+pub const MAX_UPDATES: usize = 10_000;
+
+// The tests defined in the root file of a project
+#[derive(Debug, Clone)]
+pub struct TestSuite {
+    pub tests: Vec<TestDef>,
+    // Used to locate the failures
+    file: Option<String>,
+    lines: Lines,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub name: String,
+    // Empty if the test passed
+    pub failures: Vec<Diagnostic>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl TestSuite {
+    pub(crate) fn new(tests: Vec<TestDef>, file: Option<String>, lines: Lines) -> Self {
+        Self { tests, file, lines }
+    }
+    pub fn run(&self, cf: &ComponentFactory) -> Vec<TestResult> {
+        self.tests.iter().map(|test| {
+            let mut failures = run_test(cf, test);
+            for d in failures.iter_mut() {
+                d.file = self.file.clone();
+                d.locate(&self.lines);
+            }
+            TestResult { name: test.name.clone(), failures }
+        }).collect()
+    }
+}
+
+// Failed expectations do not stop the test, but any other error does
+fn run_test(cf: &ComponentFactory, test: &TestDef) -> Vec<Diagnostic> {
+    let mut c = match cf.create_named(&test.component) {
+        Some(c) => c,
+        None => {
+            let help = cf.suggest_component(&test.component).map(|x| suggest::help(&x));
+            let kind = DiagnosticKind::ComponentNotFound { name: test.component.clone() };
+            return vec![Diagnostic::error(kind).with_span(Some(test.span)).with_help(help)];
+        }
+    };
+    let ports = c.port_names();
+    let mut input = vec![Bit::X; c.num_inputs()];
+    let mut output = vec![Bit::X; c.num_outputs()];
+    let mut failures = vec![];
+    for st in &test.body {
+        match st {
+            TestStatement::Set(values, _) => {
+                for v in values {
+                    match port_bits(c.name(), (&ports.input, &ports.output), "input", v, &test.consts) {
+                        Ok(bits) => {
+                            for (i, b) in bits.into_iter().zip(&v.value.bits) {
+                                input[i] = *b;
+                            }
+                        }
+                        Err(d) => {
+                            failures.extend(d);
+                            return failures;
+                        }
+                    }
+                }
+            }
+            TestStatement::Step(span) => {
+                let settled = match c.as_structural_mut() {
+                    Some(s) => s.settle(&input, MAX_UPDATES),
+                    // Truth tables settle in one update
+                    None => Ok(c.update(&input)),
+                };
+                match settled {
+                    Ok(x) => output = x,
+                    Err(e) => {
+                        let kind = DiagnosticKind::NotSettled { updates: e.updates };
+                        let help = Some(format!("these components keep changing: {}", e.components.join(", ")))
+                            .filter(|_| !e.components.is_empty());
+                        failures.push(Diagnostic::error(kind).with_span(Some(*span)).with_help(help));
+                        return failures;
+                    }
+                }
+            }
+            TestStatement::Expect(values, _) => {
+                for v in values {
+                    match port_bits(c.name(), (&ports.output, &ports.input), "output", v, &test.consts) {
+                        Ok(bits) => {
+                            let found: Vec<Bit> = bits.into_iter().map(|i| output[i]).collect();
+                            if found != v.value.bits {
+                                let kind = DiagnosticKind::TestFailed {
+                                    port: v.port.to_string(),
+                                    expected: v.value.to_string(),
+                                    found: literal(&found),
+                                };
+                                failures.push(Diagnostic::error(kind).with_span(Some(v.span)));
+                            }
+                        }
+                        Err(d) => {
+                            failures.extend(d);
+                            return failures;
+                        }
+                    }
+                }
+            }
+            TestStatement::Comment(_) => {}
+        }
+    }
+
+    failures
+}
+
+// Index of each bit of `v` in `ports`. An array port can be used without
+// ranges: a means a[3:0]. The other ports are only used to report ports
+// with the wrong direction
+fn port_bits(callee: &str, (ports, other): (&[String], &[String]), direction: &str, v: &PortValue, consts: &Env)
+             -> Result<Vec<usize>, Vec<Diagnostic>> {
+    let error = |kind| vec![Diagnostic::error(kind).with_span(Some(v.span))];
+    let ranges = v.port.eval(consts).map_err(error)?;
+    let name = &v.port.name;
+    let base = |x: &String| x.split('$').next() == Some(name.as_str());
+    let names: Vec<String> = if ranges.is_empty() && !ports.contains(name) {
+        ports.iter().filter(|x| base(x)).cloned().collect()
+    } else {
+        expand_bits(name, &ranges)
+    };
+
+    let bits: Option<Vec<usize>> = names.iter().map(|x| ports.iter().position(|p| p == x)).collect();
+    let bits = match bits {
+        Some(bits) if !bits.is_empty() => bits,
+        _ => {
+            if other.iter().any(base) {
+                return Err(error(DiagnosticKind::WrongPortDirection {
+                    callee: callee.to_string(), port: name.clone(), direction: direction.to_string()
+                }));
+            }
+            let mut names: Vec<&str> = ports.iter().map(|x| x.split('$').next().unwrap()).collect();
+            names.dedup();
+            let help = suggest::did_you_mean(name, names).map(|x| suggest::help(&x));
+            let kind = DiagnosticKind::UnknownPort { callee: callee.to_string(), port: v.port.to_string() };
+            return Err(vec![Diagnostic::error(kind).with_span(Some(v.span)).with_help(help)]);
+        }
+    };
+    if bits.len() != v.value.bits.len() {
+        return Err(error(DiagnosticKind::PortWidthMismatch {
+            callee: callee.to_string(),
+            port: v.port.to_string(),
+            expected: bits.len(),
+            found: v.value.bits.len(),
+        }));
+    }
+
+    Ok(bits)
+}
+
+// The value of some bits, written as a constant: 1 or 4'b01x0
+fn literal(bits: &[Bit]) -> String {
+    let b: String = bits.iter().map(|b| match b {
+        Bit::L => '0',
+        Bit::H => '1',
+        Bit::X => 'x',
+    }).collect();
+    match bits.len() {
+        1 if bits[0] == Bit::X => "'x".to_string(),
+        1 => b,
+        n => format!("{}'b{}", n, b),
+    }
+}
+
+#[test]
+fn test_blocks() {
+    use crate::parser::parse_str;
+    let d = r#"
+component Not(a) -> y {
+    Nand(a, a) -> y;
+}
+component And2(a[1:0]) -> y {
+    Nand(a[1], a[0]) -> n;
+    Not(n) -> y;
+}
+component Osc(en) -> y {
+    Nand(en, y) -> y;
+}
+test "and" for And2 {
+    step;
+    expect y = 'x;
+    set a = 2'b11;
+    step;
+    expect y = 1;
+    set a[0] = 0;
+    step;
+    expect y = 1, y = 0;
+}
+test "typo" for Amd2 {}
+test "direction" for And2 {
+    set y = 1;
+}
+test "width" for And2 {
+    expect a[1:0] = 1;
+}
+test "missing" for And2 {
+    set b = 1;
+}
+test "oscillator" for Osc {
+    set en = 0;
+    step;
+    expect y = 1;
+    set en = 1;
+    step;
+}
+    "#;
+    let cf = parse_str(d).unwrap();
+    let results = cf.tests().run(&cf);
+    let names: Vec<_> = results.iter().map(|r| (r.name.as_str(), r.passed())).collect();
+    assert_eq!(names, vec![
+        ("and", false), ("typo", false), ("direction", false),
+        ("width", false), ("missing", false), ("oscillator", false),
+    ]);
+    let errors: Vec<Vec<_>> = results.iter().map(|r| {
+        r.failures.iter().map(|d| (d.code(), d.kind.to_string(), d.help.clone())).collect()
+    }).collect();
+    let help = |x: &str| Some(suggest::help(x));
+    assert_eq!(errors, vec![
+        vec![("E0031", "`y` is 0, expected 1".to_string(), None)],
+        vec![("E0007", "component `Amd2` not found".to_string(), help("And2"))],
+        vec![("E0032", "`y` is not an input of component `And2`".to_string(), None)],
+        vec![("E0032", "`a` is not an output of component `And2`".to_string(), None)],
+        vec![("E0023", "component `And2` has no port named `b`".to_string(), help("a"))],
+        vec![("E0033", format!("the component did not settle after {} updates", MAX_UPDATES),
+            Some("these components keep changing: Nand-1".to_string()))],
+    ]);
+    // The failed expectation is the first y of the last expect
+    let location = results[0].failures[0].location.unwrap();
+    assert_eq!((location.line.0, location.column.0), (20, 12));
+
+    let cf = parse_str("component A(a) -> y { Nand(a, a) -> y; }\ntest \"w\" for A { set a = 2'b11; }").unwrap();
+    let e = &cf.tests().run(&cf)[0].failures;
+    assert_eq!(e[0].code(), "E0026");
+}
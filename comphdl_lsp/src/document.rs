@@ -0,0 +1,208 @@
+// Documents open in the editor. The client only sends the edits, which are
+// applied to the text, and after every edit the document is parsed again.
+// The last AST which could be parsed is kept, so the features still work
+// while the user is typing an incomplete statement
+use comphdl::ast::File;
+use comphdl::diagnostic::Span;
+use comphdl::parser::parse_ast;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+// Position in the protocol: the character is counted in UTF-16 code units
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub character: usize,
+}
+
+impl Position {
+    pub fn from_json(v: &Value) -> Option<Self> {
+        Some(Position {
+            line: v["line"].as_u64()? as usize,
+            character: v["character"].as_u64()? as usize,
+        })
+    }
+    pub fn to_json(self) -> Value {
+        json!({ "line": self.line, "character": self.character })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub uri: String,
+    pub version: i64,
+    pub text: String,
+    // Byte offset of the start of each line
+    line_starts: Vec<usize>,
+    pub ast: Option<File>,
+    // True if the AST is older than the text
+    pub stale: bool,
+}
+
+impl Document {
+    pub fn new(uri: String, version: i64, text: String) -> Self {
+        let mut d = Document { uri, version, text, line_starts: vec![], ast: None, stale: true };
+        d.reparse();
+        d
+    }
+    // A change with a range replaces that range, without a range it
+    // replaces the whole text
+    pub fn apply_change(&mut self, change: &Value) {
+        let text = change["text"].as_str().unwrap_or("");
+        let range = &change["range"];
+        match (Position::from_json(&range["start"]), Position::from_json(&range["end"])) {
+            (Some(start), Some(end)) => {
+                let start = self.offset(start);
+                let end = self.offset(end).max(start);
+                self.text.replace_range(start..end, text);
+            }
+            _ => self.text = text.to_string(),
+        }
+        // The offsets of the next change refer to the updated text
+        self.line_starts = line_starts(&self.text);
+    }
+    pub fn reparse(&mut self) {
+        self.line_starts = line_starts(&self.text);
+        match parse_ast(&self.text) {
+            Ok(ast) => {
+                self.ast = Some(ast);
+                self.stale = false;
+            }
+            Err(_) => self.stale = true,
+        }
+    }
+    // Byte offset of a position, positions past the end of a line are
+    // moved to the end of the line
+    pub fn offset(&self, pos: Position) -> usize {
+        let start = match self.line_starts.get(pos.line) {
+            Some(&x) => x,
+            None => return self.text.len(),
+        };
+        let end = self.line_starts.get(pos.line + 1).cloned().unwrap_or(self.text.len());
+        let mut units = 0;
+        for (i, c) in self.text[start..end].char_indices() {
+            if units >= pos.character || c == '\n' {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+
+        end
+    }
+    pub fn position(&self, offset: usize) -> Position {
+        let mut offset = offset.min(self.text.len());
+        while !self.text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        let character = self.text[self.line_starts[line]..offset].encode_utf16().count();
+
+        Position { line, character }
+    }
+    pub fn range(&self, span: Span) -> Value {
+        json!({
+            "start": self.position(span.start).to_json(),
+            "end": self.position(span.end).to_json(),
+        })
+    }
+    // The component name at the offset: Add8, or alu::Add8
+    pub fn word_at(&self, offset: usize) -> Option<(String, Span)> {
+        let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == ':';
+        let offset = offset.min(self.text.len());
+        if !self.text.is_char_boundary(offset) {
+            return None;
+        }
+        let start = self.text[..offset].rfind(|c| !is_word(c)).map(|i| i + 1).unwrap_or(0);
+        let end = self.text[offset..].find(|c| !is_word(c)).map(|i| offset + i).unwrap_or(self.text.len());
+        // Named arguments: the colon in `a: x` is not part of the name
+        let word = &self.text[start..end];
+        let trimmed_start = start + (word.len() - word.trim_start_matches(':').len());
+        let word = word.trim_matches(':');
+        if word.is_empty() {
+            return None;
+        }
+
+        Some((word.to_string(), Span::new(trimmed_start, trimmed_start + word.len())))
+    }
+}
+
+fn line_starts(text: &str) -> Vec<usize> {
+    std::iter::once(0).chain(text.match_indices('\n').map(|(i, _)| i + 1)).collect()
+}
+
+// Only file URIs are supported, the other documents get a path which does
+// not exist, so they can still be analyzed on their own
+pub fn uri_to_path(uri: &str) -> PathBuf {
+    let path = match uri.find("://") {
+        Some(i) => &uri[i + 3..],
+        None => uri,
+    };
+    let bytes = path.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    PathBuf::from(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+pub fn path_to_uri(path: &Path) -> String {
+    let mut uri = "file://".to_string();
+    for b in path.to_string_lossy().bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => uri.push(b as char),
+            b => uri.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    uri
+}
+
+#[test]
+fn edits_and_positions() {
+    let mut d = Document::new("file:///a.txt".to_string(), 1, "component A(a) -> y {\n}\n".to_string());
+    assert!(!d.stale);
+    let pos = |line, character| json!({ "line": line, "character": character });
+    d.apply_change(&json!({ "range": { "start": pos(1, 0), "end": pos(1, 0) }, "text": "    Nand(a, a) -> ñ;\n" }));
+    d.apply_change(&json!({ "range": { "start": pos(1, 18), "end": pos(1, 19) }, "text": "y" }));
+    d.reparse();
+    assert_eq!(d.text, "component A(a) -> y {\n    Nand(a, a) -> y;\n}\n");
+    assert!(!d.stale);
+
+    // A broken edit keeps the old AST
+    d.apply_change(&json!({ "range": { "start": pos(0, 0), "end": pos(0, 9) }, "text": "compo" }));
+    d.reparse();
+    assert!(d.stale);
+    assert_eq!(d.ast.as_ref().unwrap().components[0].name, "A");
+
+    // UTF-16: the emoji is two code units
+    d.apply_change(&json!({ "text": "# 😀 x\nNand" }));
+    assert_eq!(d.offset(Position { line: 0, character: 5 }), 7);
+    assert_eq!(d.position(7), Position { line: 0, character: 5 });
+    assert_eq!(d.offset(Position { line: 0, character: 99 }), 8);
+    assert_eq!(d.offset(Position { line: 9, character: 0 }), d.text.len());
+    assert_eq!(d.position(d.text.len()), Position { line: 1, character: 4 });
+
+    d.apply_change(&json!({ "text": "alu::Add8(a: x) -> y;" }));
+    assert_eq!(d.word_at(6), Some(("alu::Add8".to_string(), Span::new(0, 9))));
+    assert_eq!(d.word_at(11), Some(("a".to_string(), Span::new(10, 11))));
+
+    let path = Path::new("/tmp/my designs/ñ.txt");
+    assert_eq!(path_to_uri(path), "file:///tmp/my%20designs/%C3%B1.txt");
+    assert_eq!(uri_to_path(&path_to_uri(path)), path);
+}
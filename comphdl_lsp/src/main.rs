@@ -0,0 +1,47 @@
+// Language server for comphdl, talking JSON-RPC over stdin and stdout.
+// Editors start it as `comphdl_lsp`, the logs are written to stderr
+#[macro_use]
+extern crate log;
+
+mod document;
+mod rpc;
+mod server;
+
+use server::Server;
+use std::io::{self, BufReader};
+use std::process;
+
+fn main() {
+    env_logger::init();
+    let stdin = io::stdin();
+    let mut input = BufReader::new(stdin.lock());
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let mut server = Server::new();
+    loop {
+        let message = match rpc::read_message(&mut input) {
+            Ok(Some(m)) => m,
+            Ok(None) => break,
+            Err(e) => {
+                error!("invalid message: {}", e);
+                continue;
+            }
+        };
+        // Exit with an error if the client did not ask for a shutdown
+        if message["method"] == "exit" {
+            process::exit(if server.is_shutdown() { 0 } else { 1 });
+        }
+        let mut replies = server.handle(&message);
+        // The diagnostics wait until there are no more messages in the
+        // buffer, so a burst of changes is compiled once
+        if input.buffer().is_empty() {
+            replies.extend(server.publish());
+        }
+        for reply in replies {
+            if let Err(e) = rpc::write_message(&mut output, &reply) {
+                error!("cannot write to stdout: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
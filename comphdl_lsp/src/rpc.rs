@@ -0,0 +1,76 @@
+// JSON-RPC messages as sent by the language server protocol: a header with
+// the length of the content, an empty line, and the JSON content
+use serde_json::{json, Value};
+use std::io::{self, BufRead, Write};
+
+// Returns None at the end of the input
+pub fn read_message(r: &mut dyn BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        // Content-Type is the only other header, and it is optional
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        if name.eq_ignore_ascii_case("Content-Length") {
+            let value = parts.next().unwrap_or("").trim();
+            length = Some(value.parse::<usize>().map_err(invalid)?);
+        }
+    }
+    let length = length.ok_or_else(|| invalid("missing Content-Length header"))?;
+    let mut content = vec![0; length];
+    r.read_exact(&mut content)?;
+
+    serde_json::from_slice(&content).map(Some).map_err(invalid)
+}
+
+pub fn write_message(w: &mut dyn Write, message: &Value) -> io::Result<()> {
+    let content = message.to_string();
+    write!(w, "Content-Length: {}\r\n\r\n{}", content.len(), content)?;
+    w.flush()
+}
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+pub fn response(id: &Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+pub fn error_response(id: &Value, code: i64, message: String) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+pub fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+// Error codes defined by JSON-RPC
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+
+#[test]
+fn framing() {
+    let mut out = vec![];
+    write_message(&mut out, &notification("exit", Value::Null)).unwrap();
+    write_message(&mut out, &response(&json!(1), json!("é"))).unwrap();
+    let text = String::from_utf8(out.clone()).unwrap();
+    assert!(text.starts_with("Content-Length: 47\r\n\r\n{"));
+
+    let mut r = io::Cursor::new(out);
+    assert_eq!(read_message(&mut r).unwrap().unwrap()["method"], "exit");
+    // The length is in bytes, not in chars
+    assert_eq!(read_message(&mut r).unwrap().unwrap()["result"], "é");
+    assert!(read_message(&mut r).unwrap().is_none());
+
+    let mut r = io::Cursor::new(b"Content-Type: x\r\n\r\n{}".to_vec());
+    assert!(read_message(&mut r).is_err());
+}
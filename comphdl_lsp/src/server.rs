@@ -0,0 +1,582 @@
+// Language features. The server keeps the open documents, and every
+// request is answered using the last AST of each document. The imported
+// files which are not open are read from the filesystem. The diagnostics of
+// the documents affected by a change are compiled when `publish` is called
+use crate::document::{path_to_uri, uri_to_path, Document, Position};
+use crate::rpc::{self, error_response, notification, response};
+use comphdl::ast::{CompDef, File};
+use comphdl::diagnostic::{Severity, Span};
+use comphdl::fmt::header;
+use comphdl::parser::BUILTINS;
+use comphdl::project::{normalize, parse_project, FsLoader, SourceLoader};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Values defined by the protocol
+const SEVERITY_ERROR: u8 = 1;
+const SEVERITY_WARNING: u8 = 2;
+const SYMBOL_MODULE: u8 = 2;
+const SYMBOL_FIELD: u8 = 8;
+const SYMBOL_FUNCTION: u8 = 12;
+const COMPLETION_FIELD: u8 = 5;
+const COMPLETION_MODULE: u8 = 9;
+const SYNC_INCREMENTAL: u8 = 2;
+
+#[derive(Default)]
+pub struct Server {
+    // By normalized path, so the imports find the open documents
+    docs: HashMap<PathBuf, Document>,
+    // Open documents whose diagnostics must be published again
+    stale: BTreeSet<PathBuf>,
+    shutdown: bool,
+}
+
+// The open documents are used instead of the saved files
+struct Overlay<'a>(&'a HashMap<PathBuf, Document>);
+
+impl SourceLoader for Overlay<'_> {
+    fn load(&self, path: &Path) -> io::Result<String> {
+        match self.0.get(path) {
+            Some(d) => Ok(d.text.clone()),
+            None => FsLoader.load(path),
+        }
+    }
+}
+
+// A component definition found by name
+struct Definition {
+    path: PathBuf,
+    def: CompDef,
+    // Used to convert the spans of the definition to positions
+    doc: Document,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown
+    }
+    // Returns the messages to send to the client: the response, if the
+    // message is a request, and the notifications. The diagnostics are
+    // sent by `publish`
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let id = match message.get("id") {
+            Some(id) => id,
+            None => return self.notification(method, params),
+        };
+        if self.shutdown {
+            return vec![error_response(id, rpc::INVALID_REQUEST, "the server is shutting down".to_string())];
+        }
+        let result = match method {
+            "initialize" => Some(initialize()),
+            "shutdown" => {
+                self.shutdown = true;
+                Some(Value::Null)
+            }
+            "textDocument/definition" => self.at_position(params).map(|(path, offset)| self.definition(&path, offset)),
+            "textDocument/hover" => self.at_position(params).map(|(path, offset)| self.hover(&path, offset)),
+            "textDocument/completion" => self.at_position(params).map(|(path, offset)| self.completion(&path, offset)),
+            "textDocument/documentSymbol" => {
+                let path = self.document_path(params);
+                self.docs.get(&path).map(document_symbols)
+            }
+            _ => {
+                let message = format!("unknown method `{}`", method);
+                return vec![error_response(id, rpc::METHOD_NOT_FOUND, message)];
+            }
+        };
+
+        match result {
+            Some(result) => vec![response(id, result)],
+            None => vec![error_response(id, rpc::INVALID_PARAMS, "the document is not open".to_string())],
+        }
+    }
+    fn notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let path = self.document_path(params);
+        let doc = &params["textDocument"];
+        match method {
+            "textDocument/didOpen" => {
+                let text = doc["text"].as_str().unwrap_or("").to_string();
+                let uri = doc["uri"].as_str().unwrap_or("").to_string();
+                let version = doc["version"].as_i64().unwrap_or(0);
+                self.docs.insert(path.clone(), Document::new(uri, version, text));
+            }
+            "textDocument/didChange" => {
+                let d = match self.docs.get_mut(&path) {
+                    Some(d) => d,
+                    None => return vec![],
+                };
+                if let Some(changes) = params["contentChanges"].as_array() {
+                    for change in changes {
+                        d.apply_change(change);
+                    }
+                }
+                d.version = doc["version"].as_i64().unwrap_or(d.version);
+                // Only the edited document is parsed again
+                d.reparse();
+            }
+            "textDocument/didClose" => {
+                let uri = match self.docs.remove(&path) {
+                    Some(d) => d.uri,
+                    None => return vec![],
+                };
+                // The documents which import it now read the saved file
+                self.mark_stale(&path);
+                let params = json!({ "uri": uri, "diagnostics": [] });
+                return vec![notification("textDocument/publishDiagnostics", params)];
+            }
+            _ => return vec![],
+        }
+        self.mark_stale(&path);
+
+        vec![]
+    }
+    // A change can fix or break the documents which import this one
+    fn mark_stale(&mut self, changed: &Path) {
+        let stale: Vec<PathBuf> = self.docs.keys()
+            .filter(|path| *path == changed || self.dependencies(path).contains(changed))
+            .cloned()
+            .collect();
+        self.stale.extend(stale);
+    }
+    // The files imported by a file, directly or not
+    fn dependencies(&self, path: &Path) -> HashSet<PathBuf> {
+        let mut found = HashSet::new();
+        let mut queue = vec![path.to_path_buf()];
+        while let Some(path) = queue.pop() {
+            let ast = match self.document(&path).and_then(|d| d.ast) {
+                Some(ast) => ast,
+                None => continue,
+            };
+            for (_, import) in imported_paths(&path, &ast) {
+                if found.insert(import.clone()) {
+                    queue.push(import);
+                }
+            }
+        }
+
+        found
+    }
+    fn document_path(&self, params: &Value) -> PathBuf {
+        normalize(&uri_to_path(params["textDocument"]["uri"].as_str().unwrap_or("")))
+    }
+    fn at_position(&self, params: &Value) -> Option<(PathBuf, usize)> {
+        let path = self.document_path(params);
+        let offset = self.docs.get(&path)?.offset(Position::from_json(&params["position"])?);
+
+        Some((path, offset))
+    }
+    // The diagnostics of the documents changed since the last call, and of
+    // the documents which import them
+    pub fn publish(&mut self) -> Vec<Value> {
+        let stale = std::mem::take(&mut self.stale);
+        stale.iter().filter(|path| self.docs.contains_key(*path)).map(|path| {
+            let d = &self.docs[path];
+            let params = json!({
+                "uri": d.uri,
+                "version": d.version,
+                "diagnostics": self.diagnostics(path),
+            });
+            notification("textDocument/publishDiagnostics", params)
+        }).collect()
+    }
+    // Compile the document as the root of a project
+    fn diagnostics(&self, path: &Path) -> Vec<Value> {
+        let doc = &self.docs[path];
+        let diagnostics = match parse_project(path, &Overlay(&self.docs)) {
+            Ok(cf) => cf.warnings().to_vec(),
+            Err(e) => e.diagnostics,
+        };
+        let name = path.display().to_string();
+
+        diagnostics.iter().map(|d| {
+            let severity = match d.severity {
+                Severity::Error => SEVERITY_ERROR,
+                Severity::Warning => SEVERITY_WARNING,
+            };
+            let mut message = d.kind.to_string();
+            // The errors in the imported files are shown in the import
+            let range = if d.file.is_none() || d.file.as_ref() == Some(&name) {
+                doc.range(d.span.unwrap_or_default())
+            } else {
+                message = d.to_string();
+                let file = d.file.as_ref().map(PathBuf::from);
+                let import = doc.ast.iter().flat_map(|ast| ast.imports.iter()).find(|i| {
+                    Some(normalize(&path.parent().unwrap_or_else(|| Path::new("")).join(&i.path))) == file
+                });
+                doc.range(import.map(|i| i.span).unwrap_or_default())
+            };
+            if let Some(help) = &d.help {
+                message.push_str(&format!("\nhelp: {}", help));
+            }
+            let related: Vec<Value> = d.related.iter().filter_map(|r| {
+                let path = r.file.as_ref().map(PathBuf::from).unwrap_or_else(|| path.to_path_buf());
+                let other = self.document(&path)?;
+                Some(json!({
+                    "location": { "uri": other.uri, "range": other.range(r.span?) },
+                    "message": r.message,
+                }))
+            }).collect();
+            json!({
+                "range": range,
+                "severity": severity,
+                "code": d.code(),
+                "source": "comphdl",
+                "message": message,
+                "relatedInformation": related,
+            })
+        }).collect()
+    }
+    // The open document, or the saved file
+    fn document(&self, path: &Path) -> Option<Document> {
+        if let Some(d) = self.docs.get(path) {
+            return Some(d.clone());
+        }
+        let text = FsLoader.load(path).ok()?;
+
+        Some(Document::new(path_to_uri(path), 0, text))
+    }
+    // Find the definition of a component used in the document `path`. The
+    // names can be qualified, alu::Add8, or imported with `use alu::Add8;`
+    fn find(&self, path: &Path, name: &str) -> Option<Definition> {
+        let doc = self.document(path)?;
+        let ast = doc.ast.as_ref()?;
+        let (namespace, name) = match name.find("::") {
+            Some(i) => (Some(name[..i].to_string()), &name[i + 2..]),
+            None => (None, name),
+        };
+        let namespace = match namespace {
+            Some(ns) => ns,
+            None => {
+                if let Some(def) = ast.components.iter().find(|c| c.name == name) {
+                    let def = def.clone();
+                    return Some(Definition { path: path.to_path_buf(), def, doc });
+                }
+                ast.uses.iter().find(|u| u.name == name)?.namespace.clone()
+            }
+        };
+        let import = imported_paths(path, ast).into_iter().find(|(ns, _)| *ns == namespace)?.1;
+        let other = self.document(&import)?;
+        let def = other.ast.as_ref()?.components.iter().find(|c| c.name == name)?.clone();
+
+        Some(Definition { path: import, def, doc: other })
+    }
+    fn definition(&self, path: &Path, offset: usize) -> Value {
+        let doc = &self.docs[path];
+        let found = doc.word_at(offset).and_then(|(word, _)| self.find(path, &word));
+        match found {
+            Some(d) => json!({ "uri": d.doc.uri, "range": d.doc.range(name_span(&d.def)) }),
+            None => Value::Null,
+        }
+    }
+    fn hover(&self, path: &Path, offset: usize) -> Value {
+        let doc = &self.docs[path];
+        let (word, span) = match doc.word_at(offset) {
+            Some(x) => x,
+            None => return Value::Null,
+        };
+        let text = match self.find(path, &word) {
+            Some(d) => {
+                let mut text = format!("```comphdl\n{}\n```", header(&d.def));
+                if let Some(doc) = &d.def.doc {
+                    text.push_str(&format!("\n\n{}", doc));
+                }
+                for (port, doc) in &d.def.port_docs {
+                    text.push_str(&format!("\n\n`{}`: {}", port, doc));
+                }
+                if d.path != path {
+                    text.push_str(&format!("\n\nDefined in `{}`", d.path.display()));
+                }
+                text
+            }
+            None if BUILTINS.contains(&word.as_str()) => format!("```comphdl\n{}\n```\n\nBuilt-in component", builtin(&word)),
+            None => return Value::Null,
+        };
+
+        json!({
+            "contents": { "kind": "markdown", "value": text },
+            "range": doc.range(span),
+        })
+    }
+    // Inside the arguments of a call, the ports of the called component.
+    // Anywhere else, the names of the components
+    fn completion(&self, path: &Path, offset: usize) -> Value {
+        let doc = &self.docs[path];
+        if let Some((callee, outputs)) = call_context(&doc.text[..offset]) {
+            let def = match self.find(path, &callee) {
+                Some(d) => d.def,
+                None => return json!([]),
+            };
+            let ports = if outputs { &def.outputs } else { &def.inputs };
+            let items: Vec<Value> = ports.iter().map(|p| {
+                let doc = def.port_docs.iter().find(|(name, _)| *name == p.name).map(|(_, doc)| doc);
+                json!({
+                    "label": p.name,
+                    "kind": COMPLETION_FIELD,
+                    "detail": p.to_string(),
+                    "documentation": doc,
+                    "insertText": format!("{}: ", p.name),
+                })
+            }).collect();
+            return json!(items);
+        }
+
+        let mut items = vec![];
+        let mut item = |label: String, detail: String, doc: Option<&String>| {
+            items.push(json!({ "label": label, "kind": COMPLETION_MODULE, "detail": detail, "documentation": doc }));
+        };
+        if let Some(ast) = &doc.ast {
+            for def in &ast.components {
+                item(def.name.clone(), header(def), def.doc.as_ref());
+            }
+            for u in &ast.uses {
+                if let Some(d) = self.find(path, &u.name) {
+                    item(u.name.clone(), header(&d.def), d.def.doc.as_ref());
+                }
+            }
+            for (ns, import) in imported_paths(path, ast) {
+                let other = self.document(&import);
+                for def in other.iter().flat_map(|d| d.ast.iter()).flat_map(|ast| ast.components.iter()) {
+                    item(format!("{}::{}", ns, def.name), header(def), def.doc.as_ref());
+                }
+            }
+        }
+        for name in BUILTINS.iter() {
+            item(name.to_string(), builtin(name), None);
+        }
+
+        json!(items)
+    }
+}
+
+fn initialize() -> Value {
+    json!({
+        "capabilities": {
+            "textDocumentSync": { "openClose": true, "change": SYNC_INCREMENTAL },
+            "definitionProvider": true,
+            "hoverProvider": true,
+            "completionProvider": { "triggerCharacters": ["(", ","] },
+            "documentSymbolProvider": true,
+        },
+        "serverInfo": { "name": "comphdl_lsp", "version": env!("CARGO_PKG_VERSION") },
+    })
+}
+
+// Namespace and path of the files imported by the file `path`
+fn imported_paths(path: &Path, ast: &File) -> Vec<(String, PathBuf)> {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    ast.imports.iter().map(|i| {
+        (i.namespace(), normalize(&dir.join(&i.path)))
+    }).collect()
+}
+
+// The ports of the builtin components, which have no definition
+fn builtin(name: &str) -> String {
+    match name {
+        "Nand" => "Nand(a, b, ...) -> y",
+        "ConstantBit" => "ConstantBit() -> (zero, one, x)",
+        "Stdin" => "Stdin(read) -> (valid, b[7:0])",
+        "Stdout" => "Stdout(write, b[7:0])",
+        _ => name,
+    }.to_string()
+}
+
+// If the text ends inside the arguments of a call, the name of the called
+// component, and whether the arguments are the outputs:
+// Add8(a: x, b: y) -> (s: z, |
+fn call_context(text: &str) -> Option<(String, bool)> {
+    let open = unclosed_paren(text)?;
+    let before = text[..open].trim_end();
+    let (outputs, before) = match before.strip_suffix("->") {
+        // Skip the inputs
+        Some(before) => {
+            let before = before.trim_end().strip_suffix(')')?;
+            (true, before[..unclosed_paren(before)?].trim_end())
+        }
+        None => (false, before),
+    };
+    // Skip the parameters of Reg<8>
+    let before = match before.strip_suffix('>') {
+        Some(b) => &b[..b.rfind('<')?],
+        None => before,
+    };
+    let start = before.rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':')).map(|i| i + 1).unwrap_or(0);
+    let name = &before[start..];
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some((name.to_string(), outputs))
+}
+
+// Position of the last unclosed parenthesis, within the current statement
+fn unclosed_paren(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' if depth == 0 => return Some(i),
+            '(' => depth -= 1,
+            ';' | '{' | '}' => return None,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn document_symbols(doc: &Document) -> Value {
+    let ast = match &doc.ast {
+        Some(ast) => ast,
+        None => return json!([]),
+    };
+    let mut symbols = vec![];
+    for def in &ast.components {
+        let ports: Vec<Value> = def.inputs.iter().map(|p| (p, "input"))
+            .chain(def.outputs.iter().map(|p| (p, "output")))
+            .map(|(p, direction)| json!({
+                "name": p.to_string(),
+                "detail": direction,
+                "kind": SYMBOL_FIELD,
+                "range": doc.range(p.span),
+                "selectionRange": doc.range(p.span),
+            })).collect();
+        symbols.push((def.span.start, json!({
+            "name": def.name,
+            "detail": header(def),
+            "kind": SYMBOL_MODULE,
+            "range": doc.range(def.span),
+            "selectionRange": doc.range(name_span(def)),
+            "children": ports,
+        })));
+    }
+    for test in &ast.tests {
+        symbols.push((test.span.start, json!({
+            "name": format!("test \"{}\"", test.name),
+            "detail": test.component,
+            "kind": SYMBOL_FUNCTION,
+            "range": doc.range(test.span),
+            "selectionRange": doc.range(test.span),
+        })));
+    }
+    symbols.sort_by_key(|(start, _)| *start);
+
+    json!(symbols.into_iter().map(|(_, s)| s).collect::<Vec<_>>())
+}
+
+// The span of a definition starts at its name
+fn name_span(def: &CompDef) -> Span {
+    Span::new(def.span.start, def.span.start + def.name.len())
+}
+
+#[cfg(test)]
+fn request(s: &mut Server, method: &str, params: Value) -> Value {
+    let out = s.handle(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }));
+    assert_eq!(out.len(), 1);
+    out[0]["result"].clone()
+}
+
+#[test]
+fn language_features() {
+    let alu = "/// Not an adder\ncomponent Add(a, b) -> (s, c) {\n    Nand(a, b) -> c;\n    Nand(a, c) -> s;\n}\n";
+    let main = "import \"lib/alu.txt\";\nuse alu::Add;\n\ncomponent Top(x) -> y {\n    Add(x, x) -> (y, _);\n    alu::Add(a: x, ) -> (s: _, c: _);\n}\ntest \"t\" for Top {}\n";
+    let mut s = Server::new();
+    let caps = request(&mut s, "initialize", json!({}));
+    assert_eq!(caps["capabilities"]["textDocumentSync"]["change"], 2);
+    let open = |s: &mut Server, uri: &str, text: &str| {
+        assert_eq!(s.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": uri, "version": 1, "text": text } },
+        })), Vec::<Value>::new());
+        s.publish()
+    };
+    let main_uri = "file:///project/main.txt";
+    let alu_uri = "file:///project/lib/alu.txt";
+    let out = open(&mut s, alu_uri, alu);
+    assert_eq!(out.len(), 1);
+    // The import of main.txt is read from the open document. alu.txt does
+    // not import main.txt, its diagnostics are not published again
+    let out = open(&mut s, main_uri, main);
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0]["params"]["uri"], main_uri);
+    let diagnostics = out[0]["params"]["diagnostics"].as_array().unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0]["severity"], 1);
+    assert_eq!(diagnostics[0]["range"]["start"], json!({ "line": 5, "character": 4 }));
+
+    let at = |line, character| json!({ "textDocument": { "uri": main_uri }, "position": { "line": line, "character": character } });
+    let def = request(&mut s, "textDocument/definition", at(4, 5));
+    assert_eq!(def["uri"], alu_uri);
+    assert_eq!(def["range"]["start"], json!({ "line": 1, "character": 10 }));
+    assert_eq!(def["range"]["end"], json!({ "line": 1, "character": 13 }));
+    assert_eq!(request(&mut s, "textDocument/definition", at(5, 10)), def);
+    assert_eq!(request(&mut s, "textDocument/definition", at(7, 14))["range"]["start"]["line"], 3);
+    assert_eq!(request(&mut s, "textDocument/definition", at(4, 9)), Value::Null);
+
+    let hover = request(&mut s, "textDocument/hover", at(4, 5));
+    assert_eq!(hover["contents"]["value"], "```comphdl\ncomponent Add(a, b) -> (s, c)\n```\n\nNot an adder\n\nDefined in `/project/lib/alu.txt`");
+    assert_eq!(hover["range"]["start"], json!({ "line": 4, "character": 4 }));
+    assert_eq!(request(&mut s, "textDocument/hover", at(4, 1)), Value::Null);
+
+    let labels = |v: Value| -> Vec<String> {
+        v.as_array().unwrap().iter().map(|x| x["label"].as_str().unwrap().to_string()).collect()
+    };
+    assert_eq!(labels(request(&mut s, "textDocument/completion", at(5, 19))), vec!["a", "b"]);
+    assert_eq!(labels(request(&mut s, "textDocument/completion", at(5, 25))), vec!["s", "c"]);
+    assert_eq!(labels(request(&mut s, "textDocument/completion", at(5, 4))),
+               vec!["Top", "Add", "alu::Add", "Nand", "ConstantBit", "Stdin", "Stdout"]);
+
+    let symbols = request(&mut s, "textDocument/documentSymbol", json!({ "textDocument": { "uri": main_uri } }));
+    assert_eq!(symbols[0]["name"], "Top");
+    assert_eq!(symbols[0]["selectionRange"]["start"], json!({ "line": 3, "character": 10 }));
+    assert_eq!(symbols[0]["children"][1]["detail"], "output");
+    assert_eq!(symbols[1]["name"], "test \"t\"");
+
+    // Incremental edit which fixes the missing input, then a broken edit
+    // which keeps the last AST
+    let change = |s: &mut Server, line, start, end, text: &str| s.handle(&json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/didChange",
+        "params": {
+            "textDocument": { "uri": main_uri, "version": 2 },
+            "contentChanges": [{
+                "range": { "start": { "line": line, "character": start }, "end": { "line": line, "character": end } },
+                "text": text,
+            }],
+        },
+    }));
+    change(&mut s, 5, 19, 19, "b: x");
+    let out = s.publish();
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0]["params"]["diagnostics"], json!([]));
+    // Both changes are compiled once
+    change(&mut s, 4, 0, 0, "Add(");
+    change(&mut s, 4, 0, 0, " ");
+    let out = s.publish();
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0]["params"]["diagnostics"][0]["code"], "E0001");
+    assert_eq!(s.publish(), Vec::<Value>::new());
+    assert_eq!(request(&mut s, "textDocument/definition", at(5, 9))["uri"], alu_uri);
+
+    // An error in the imported file is shown in the import, main.txt is
+    // compiled again
+    let out = open(&mut s, alu_uri, "component Add(a, b) -> (s, c) {\n");
+    assert_eq!(out.len(), 2);
+    assert_eq!(out[0]["params"]["diagnostics"][0]["code"], "E0001");
+    change(&mut s, 4, 0, 5, "");
+    let out = s.publish();
+    let d = &out[0]["params"]["diagnostics"][0];
+    assert_eq!(d["range"]["start"], json!({ "line": 0, "character": 0 }));
+    assert!(d["message"].as_str().unwrap().contains("alu.txt"));
+
+    assert_eq!(request(&mut s, "shutdown", Value::Null), Value::Null);
+    let out = s.handle(&json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/hover", "params": at(0, 0) }));
+    assert_eq!(out[0]["error"]["code"], rpc::INVALID_REQUEST);
+}
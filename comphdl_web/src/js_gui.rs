@@ -1,4 +1,4 @@
-use comphdl::{emit_json, parser};
+use comphdl::{emit_json, parser, suggest};
 use comphdl::component::{ComponentIndex, Structural};
 use comphdl::bit::Bit;
 use comphdl::wave_json::{WaveJson};
@@ -187,10 +187,20 @@ pub fn run_js_gui(definition: &str) -> String {
             if top == "" {
                 return format!("You must specify a top component name");
             }
-            return format!("Top component `{}` not found", top);
-            // TODO: did you mean ...? (find components with similar names)
+            return match cf.suggest_component(&top) {
+                Some(similar) => format!("Top component `{}` not found, {}", top, suggest::help(&similar)),
+                None => format!("Top component `{}` not found", top),
+            };
         }
     };
+    // A table is simulated as its Nand netlist, the netlist and waveform
+    // views need a structural component
+    if c.as_structural().is_none() {
+        c = match c.clone_as_structural() {
+            Some(s) => Box::new(s),
+            None => return format!("Top component `{}` cannot be simulated", top),
+        };
+    }
 
     debug!("Generating netlist and wavejson...");
     // Borrow the component as a structural to generate the netlist